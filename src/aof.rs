@@ -0,0 +1,339 @@
+// Append-only file: every write command is logged with the request framing
+// of the ferdis protocol (see `client::serialize_request`) and the log is
+// replayed at startup. Relative expire times are logged as absolute
+// deadlines, so replaying later does not extend them.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use crate::client::serialize_request;
+use crate::keyspace::{Entry, Value, now_ms};
+use crate::server::parse_request;
+use crate::snapshot;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    // fsync after every write command, before the reply goes out
+    Always,
+    // fsync at most once per second from the event loop
+    EverySec,
+    // leave flushing to the operating system
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!("unknown fsync policy {}", s)),
+        }
+    }
+}
+
+pub struct Aof {
+    path: String,
+    file: File,
+    policy: FsyncPolicy,
+    // commands logged while a rewrite runs, they go after the rewritten log
+    rewrite_buf: Option<Vec<u8>>,
+    // written but not yet fsynced
+    dirty: bool,
+    last_fsync: u64,
+}
+
+// Splits a log into commands. Returns them with the length of the complete
+// part; anything after it is a write, or a transaction, that was cut short.
+// The multi and exec around a transaction are left out.
+pub fn read_commands(buf: &[u8]) -> io::Result<(Vec<Vec<Vec<u8>>>, usize)> {
+    let mut commands = Vec::new();
+    let mut pos = 0;
+    // where the transaction without an exec yet starts, in `buf` and in
+    // `commands`
+    let mut open: Option<(usize, usize)> = None;
+    while buf.len() - pos >= 4 {
+        let len = usize::try_from(u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())).unwrap();
+        if buf.len() - pos - 4 < len {
+            break;
+        }
+        match parse_request(&buf[pos + 4..pos + 4 + len]) {
+            Ok(command) => {
+                if command == [b"multi"] {
+                    open = Some((pos, commands.len()));
+                } else if command == [b"exec"] {
+                    open = None;
+                } else {
+                    commands.push(command);
+                }
+            },
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("corrupt command at offset {}", pos)));
+            }
+        }
+        pos += 4 + len;
+    }
+    if let Some((start, count)) = open {
+        commands.truncate(count);
+        pos = start;
+    }
+    return Ok((commands, pos));
+}
+
+impl Aof {
+    // Opens the log at `path`, creating it when missing. Returns the logged
+    // commands; an incomplete command or transaction at the end is cut off
+    // the file.
+    pub fn open(path: &str, policy: FsyncPolicy) -> io::Result<(Aof, Vec<Vec<Vec<u8>>>)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let (commands, valid) = read_commands(&buf)?;
+        if valid < buf.len() {
+            println!("AOF {}: cutting off {} bytes of a truncated command or transaction", path, buf.len() - valid);
+            file.set_len(u64::try_from(valid).unwrap())?;
+            file.sync_all()?;
+        }
+        let aof = Aof {
+            path: path.to_string(),
+            file: file,
+            policy: policy,
+            rewrite_buf: None,
+            dirty: false,
+            last_fsync: now_ms(),
+        };
+        return Ok((aof, commands));
+    }
+
+    pub fn append(&mut self, command: &[Vec<u8>]) -> io::Result<()> {
+        let data = serialize_request(command);
+        if let Some(buf) = self.rewrite_buf.as_mut() {
+            buf.extend_from_slice(&data);
+        }
+        self.file.write_all(&data)?;
+        if self.policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        } else {
+            self.dirty = true;
+        }
+        return Ok(());
+    }
+
+    // Where a rewrite writes the new log before it replaces the current one
+    pub fn rewrite_path(&self) -> String {
+        return format!("{}.rewrite", self.path);
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        return self.rewrite_buf.is_some();
+    }
+
+    // From now on logged commands are also kept for the rewritten log
+    pub fn start_rewrite(&mut self) {
+        self.rewrite_buf = Some(Vec::new());
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buf = None;
+        let _ = fs::remove_file(self.rewrite_path());
+    }
+
+    // Appends the commands logged during the rewrite to the new log and
+    // renames it over the current one, then continues logging to it
+    pub fn finish_rewrite(&mut self) -> io::Result<()> {
+        let buf = self.rewrite_buf.take().unwrap_or_default();
+        let tmp = self.rewrite_path();
+        let res = OpenOptions::new().append(true).open(&tmp).and_then(|mut file| {
+            file.write_all(&buf)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(file)
+        });
+        match res {
+            Ok(file) => {
+                self.file = file;
+                self.dirty = false;
+                return Ok(());
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        }
+    }
+
+    // When the event loop has to wake up for the next everysec fsync
+    pub fn next_fsync(&self) -> Option<u64> {
+        if self.policy == FsyncPolicy::EverySec && self.dirty {
+            return Some(self.last_fsync + 1000);
+        }
+        return None;
+    }
+
+    // Called from the event loop
+    pub fn tick(&mut self, now: u64) -> io::Result<()> {
+        if self.next_fsync().is_some_and(|at| at <= now) {
+            self.file.sync_data()?;
+            self.dirty = false;
+            self.last_fsync = now;
+        }
+        return Ok(());
+    }
+}
+
+// The shortest log that rebuilds `entries`: one command per string and one
+// per sorted set member, list item, hash field or set member, deadlines as
+// pexpireat. Streams carry groups and pending entries no plain command
+// rebuilds, so they come back whole through restore.
+pub fn rewrite_commands<'a, I: Iterator<Item = (&'a [u8], &'a Entry)>>(entries: I) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, entry) in entries {
+        match &entry.value {
+            Value::Str(data) => {
+                out.extend_from_slice(&serialize_request(&[b"set", key, data]));
+            },
+            Value::ZSet(zset) => {
+                for node in zset.range(0, -1) {
+                    let score = node.score.to_string();
+                    out.extend_from_slice(&serialize_request(&[b"zadd", key, score.as_bytes(), &node.name]));
+                }
+            },
+            Value::List(list) => {
+                for item in list.iter() {
+                    out.extend_from_slice(&serialize_request(&[b"rpush", key, item]));
+                }
+            },
+            Value::Hash(hash) => {
+                for (field, value) in hash.iter() {
+                    out.extend_from_slice(&serialize_request(&[b"hset", key, field, value]));
+                }
+            },
+            Value::Set(set) => {
+                for member in set.iter() {
+                    out.extend_from_slice(&serialize_request(&[b"sadd", key, member]));
+                }
+            },
+            Value::Stream(_) => {
+                let payload = snapshot::dump_value(&entry.value);
+                out.extend_from_slice(&serialize_request(&[b"restore", key, b"0", &payload]));
+            },
+        }
+        if let Some(at) = entry.expire_at {
+            out.extend_from_slice(&serialize_request(&[b"pexpireat", key, at.to_string().as_bytes()]));
+        }
+    }
+    return out;
+}
+
+// Writes the rewritten log for `entries` to `path`, run off the event loop
+pub fn write_rewrite(path: &str, entries: Vec<(Vec<u8>, Entry)>) -> io::Result<()> {
+    let data = rewrite_commands(entries.iter().map(|(key, entry)| (key.as_slice(), entry)));
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ferdis-{}-{}.aof", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let path = temp_path("replay");
+        let (mut aof, commands) = Aof::open(&path, FsyncPolicy::Always).unwrap();
+        assert!(commands.is_empty());
+        aof.append(&[b"set".to_vec(), b"k".to_vec(), b"\x00v".to_vec()]).unwrap();
+        aof.append(&[b"del".to_vec(), b"k".to_vec()]).unwrap();
+        drop(aof);
+        let (_, commands) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(commands, vec![
+            vec![b"set".to_vec(), b"k".to_vec(), b"\x00v".to_vec()],
+            vec![b"del".to_vec(), b"k".to_vec()],
+        ]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_truncated_tail_is_cut_off() {
+        let path = temp_path("truncated");
+        let complete = serialize_request(&["set", "a", "1"]);
+        let mut buf = complete.clone();
+        buf.extend_from_slice(&serialize_request(&["set", "b", "2"])[..7]);
+        std::fs::write(&path, &buf).unwrap();
+        let (mut aof, commands) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete.len() as u64);
+        // new commands follow the last complete one
+        aof.append(&[b"del".to_vec(), b"a".to_vec()]).unwrap();
+        let (_, commands) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(commands.len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_open_transaction_is_cut_off() {
+        let path = temp_path("transaction");
+        let mut buf = Vec::new();
+        for command in [vec!["multi"], vec!["set", "a", "1"], vec!["set", "b", "2"], vec!["exec"]] {
+            buf.extend_from_slice(&serialize_request(&command));
+        }
+        let complete = buf.len();
+        for command in [vec!["multi"], vec!["set", "c", "3"]] {
+            buf.extend_from_slice(&serialize_request(&command));
+        }
+        std::fs::write(&path, &buf).unwrap();
+        let (_, commands) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(commands, vec![
+            vec![b"set".to_vec(), b"a".to_vec(), b"1".to_vec()],
+            vec![b"set".to_vec(), b"b".to_vec(), b"2".to_vec()],
+        ]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete as u64);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rewrite_keeps_concurrent_writes() {
+        let path = temp_path("rewrite");
+        let (mut aof, _) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        for i in 0..100 {
+            aof.append(&[b"set".to_vec(), b"k".to_vec(), i.to_string().into_bytes()]).unwrap();
+        }
+        aof.start_rewrite();
+        let entry = Entry { value: Value::Str(b"99".to_vec()), expire_at: Some(4000000000000) };
+        write_rewrite(&aof.rewrite_path(), vec![(b"k".to_vec(), entry)]).unwrap();
+        aof.append(&[b"del".to_vec(), b"k".to_vec()]).unwrap();
+        aof.finish_rewrite().unwrap();
+        aof.append(&[b"set".to_vec(), b"x".to_vec(), b"1".to_vec()]).unwrap();
+        let (_, commands) = Aof::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(commands, vec![
+            vec![b"set".to_vec(), b"k".to_vec(), b"99".to_vec()],
+            vec![b"pexpireat".to_vec(), b"k".to_vec(), b"4000000000000".to_vec()],
+            vec![b"del".to_vec(), b"k".to_vec()],
+            vec![b"set".to_vec(), b"x".to_vec(), b"1".to_vec()],
+        ]);
+        assert!(!std::path::Path::new(&aof.rewrite_path()).exists());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_everysec_schedule() {
+        let path = temp_path("everysec");
+        let (mut aof, _) = Aof::open(&path, FsyncPolicy::EverySec).unwrap();
+        assert_eq!(aof.next_fsync(), None);
+        aof.append(&[b"del".to_vec(), b"a".to_vec()]).unwrap();
+        let at = aof.next_fsync().unwrap();
+        aof.tick(at - 1).unwrap();
+        assert_eq!(aof.next_fsync(), Some(at));
+        aof.tick(at).unwrap();
+        assert_eq!(aof.next_fsync(), None);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,277 @@
+use std::cmp::Ordering;
+
+pub const NIL: usize = usize::MAX;
+
+#[derive(Clone, Debug)]
+struct Node<T> {
+    value: Option<T>,
+    left: usize,
+    right: usize,
+    height: u32,
+    // number of nodes in the subtree, used for rank queries
+    cnt: usize,
+}
+
+// Order-statistic AVL tree. Nodes live in an arena and are addressed by
+// index; an index stays valid until its value is removed, so callers can
+// keep it in an external map.
+#[derive(Clone, Debug)]
+pub struct AvlTree<T: Ord> {
+    nodes: Vec<Node<T>>,
+    free: Vec<usize>,
+    root: usize,
+}
+
+impl<T: Ord> Default for AvlTree<T> {
+    fn default() -> AvlTree<T> {
+        return AvlTree { nodes: Vec::new(), free: Vec::new(), root: NIL };
+    }
+}
+
+impl<T: Ord> AvlTree<T> {
+    pub fn new() -> AvlTree<T> {
+        return AvlTree::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.cnt(self.root);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.root == NIL;
+    }
+
+    pub fn get(&self, id: usize) -> &T {
+        return self.nodes[id].value.as_ref().unwrap();
+    }
+
+    fn height(&self, id: usize) -> u32 {
+        if id == NIL { 0 } else { self.nodes[id].height }
+    }
+
+    fn cnt(&self, id: usize) -> usize {
+        if id == NIL { 0 } else { self.nodes[id].cnt }
+    }
+
+    fn update(&mut self, id: usize) {
+        let (l, r) = (self.nodes[id].left, self.nodes[id].right);
+        self.nodes[id].height = 1 + self.height(l).max(self.height(r));
+        self.nodes[id].cnt = 1 + self.cnt(l) + self.cnt(r);
+    }
+
+    fn rotate_left(&mut self, id: usize) -> usize {
+        let new_root = self.nodes[id].right;
+        self.nodes[id].right = self.nodes[new_root].left;
+        self.nodes[new_root].left = id;
+        self.update(id);
+        self.update(new_root);
+        return new_root;
+    }
+
+    fn rotate_right(&mut self, id: usize) -> usize {
+        let new_root = self.nodes[id].left;
+        self.nodes[id].left = self.nodes[new_root].right;
+        self.nodes[new_root].right = id;
+        self.update(id);
+        self.update(new_root);
+        return new_root;
+    }
+
+    // Restores the AVL invariant at `id` and returns the new subtree root
+    fn balance(&mut self, id: usize) -> usize {
+        self.update(id);
+        let (l, r) = (self.nodes[id].left, self.nodes[id].right);
+        if self.height(l) > self.height(r) + 1 {
+            let (ll, lr) = (self.nodes[l].left, self.nodes[l].right);
+            if self.height(ll) < self.height(lr) {
+                self.nodes[id].left = self.rotate_left(l);
+            }
+            return self.rotate_right(id);
+        }
+        if self.height(r) > self.height(l) + 1 {
+            let (rl, rr) = (self.nodes[r].left, self.nodes[r].right);
+            if self.height(rr) < self.height(rl) {
+                self.nodes[id].right = self.rotate_right(r);
+            }
+            return self.rotate_left(id);
+        }
+        return id;
+    }
+
+    // Values must be unique; returns the index of the new node
+    pub fn insert(&mut self, value: T) -> usize {
+        let node = Node { value: Some(value), left: NIL, right: NIL, height: 1, cnt: 1 };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.root = self.insert_rec(self.root, id);
+        return id;
+    }
+
+    fn insert_rec(&mut self, node: usize, id: usize) -> usize {
+        if node == NIL {
+            return id;
+        }
+        if self.get(id) < self.get(node) {
+            let left = self.insert_rec(self.nodes[node].left, id);
+            self.nodes[node].left = left;
+        } else {
+            let right = self.insert_rec(self.nodes[node].right, id);
+            self.nodes[node].right = right;
+        }
+        return self.balance(node);
+    }
+
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let (root, removed) = self.remove_rec(self.root, value);
+        self.root = root;
+        if removed == NIL {
+            return None;
+        }
+        self.free.push(removed);
+        return self.nodes[removed].value.take();
+    }
+
+    // Returns the new subtree root and the detached node
+    fn remove_rec(&mut self, node: usize, value: &T) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        match value.cmp(self.get(node)) {
+            Ordering::Less => {
+                let (left, removed) = self.remove_rec(self.nodes[node].left, value);
+                self.nodes[node].left = left;
+                return (self.balance(node), removed);
+            },
+            Ordering::Greater => {
+                let (right, removed) = self.remove_rec(self.nodes[node].right, value);
+                self.nodes[node].right = right;
+                return (self.balance(node), removed);
+            },
+            Ordering::Equal => {
+                let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+                if left == NIL {
+                    return (right, node);
+                }
+                if right == NIL {
+                    return (left, node);
+                }
+                // the successor takes the place of the node, so indices of
+                // the remaining nodes never change
+                let (right, successor) = self.remove_min(right);
+                self.nodes[successor].left = left;
+                self.nodes[successor].right = right;
+                return (self.balance(successor), node);
+            }
+        }
+    }
+
+    fn remove_min(&mut self, node: usize) -> (usize, usize) {
+        let left = self.nodes[node].left;
+        if left == NIL {
+            return (self.nodes[node].right, node);
+        }
+        let (left, min) = self.remove_min(left);
+        self.nodes[node].left = left;
+        return (self.balance(node), min);
+    }
+
+    // Number of values strictly less than `value`
+    pub fn count_less(&self, value: &T) -> usize {
+        let mut node = self.root;
+        let mut count = 0;
+        while node != NIL {
+            if self.get(node) < value {
+                count += self.cnt(self.nodes[node].left) + 1;
+                node = self.nodes[node].right;
+            } else {
+                node = self.nodes[node].left;
+            }
+        }
+        return count;
+    }
+
+    // Index of the value at 0-based position `rank`
+    pub fn select(&self, rank: usize) -> Option<usize> {
+        let mut node = self.root;
+        let mut rank = rank;
+        while node != NIL {
+            let left_cnt = self.cnt(self.nodes[node].left);
+            match rank.cmp(&left_cnt) {
+                Ordering::Less => {
+                    node = self.nodes[node].left;
+                },
+                Ordering::Equal => {
+                    return Some(node);
+                },
+                Ordering::Greater => {
+                    rank -= left_cnt + 1;
+                    node = self.nodes[node].right;
+                }
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(tree: &AvlTree<u32>, node: usize) -> (u32, usize) {
+        if node == NIL {
+            return (0, 0);
+        }
+        let (lh, lc) = check(tree, tree.nodes[node].left);
+        let (rh, rc) = check(tree, tree.nodes[node].right);
+        assert!(lh.abs_diff(rh) <= 1);
+        assert_eq!(tree.nodes[node].height, 1 + lh.max(rh));
+        assert_eq!(tree.nodes[node].cnt, 1 + lc + rc);
+        return (tree.nodes[node].height, tree.nodes[node].cnt);
+    }
+
+    #[test]
+    fn test_insert_remove_keeps_balance() {
+        let mut tree: AvlTree<u32> = AvlTree::new();
+        let mut ids = Vec::new();
+        for i in 0..200 {
+            ids.push(tree.insert((i * 37) % 200));
+            check(&tree, tree.root);
+        }
+        for i in (0..200).step_by(3) {
+            assert_eq!(tree.remove(&i), Some(i));
+            check(&tree, tree.root);
+        }
+        assert_eq!(tree.remove(&0), None);
+        assert_eq!(tree.len(), 200 - 67);
+        // surviving indices still point at their values
+        for (i, id) in ids.iter().enumerate() {
+            let v = (i as u32 * 37) % 200;
+            if !v.is_multiple_of(3) {
+                assert_eq!(*tree.get(*id), v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rank_and_select() {
+        let mut tree: AvlTree<u32> = AvlTree::new();
+        for i in (0..100).rev() {
+            tree.insert(i * 2);
+        }
+        for rank in 0..100 {
+            let id = tree.select(rank).unwrap();
+            assert_eq!(*tree.get(id), rank as u32 * 2);
+            assert_eq!(tree.count_less(&(rank as u32 * 2)), rank);
+        }
+        assert_eq!(tree.count_less(&7), 4);
+        assert!(tree.select(100).is_none());
+    }
+}
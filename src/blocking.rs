@@ -0,0 +1,154 @@
+// Connections parked by blpop, brpop, xread and xreadgroup. Every key has a
+// queue of the connections waiting for it, longest waiting first, and the
+// event loop retries their commands in that order when the key is written.
+// This only keeps the books; retrying and answering happen in the event
+// loop.
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::os::fd::RawFd;
+
+pub struct Waiter {
+    pub keys: Vec<Vec<u8>>,
+    // run again when one of the keys changes, a NIL reply keeps waiting
+    pub command: Vec<Vec<u8>>,
+    // unix time in milliseconds, None waits forever
+    pub deadline: Option<u64>,
+    // ASKING came before the command, it is checked against the cluster
+    // again on every retry
+    pub asking: bool,
+}
+
+#[derive(Default)]
+pub struct Blocking {
+    queues: HashMap<Vec<u8>, VecDeque<RawFd>>,
+    waiters: HashMap<RawFd, Waiter>,
+    deadlines: BTreeSet<(u64, RawFd)>,
+    // keys with waiters that were written to since the last look
+    ready: Vec<Vec<u8>>,
+}
+
+impl Blocking {
+    pub fn new() -> Blocking {
+        return Blocking::default();
+    }
+
+    pub fn is_blocked(&self, fd: RawFd) -> bool {
+        return self.waiters.contains_key(&fd);
+    }
+
+    // Number of parked connections
+    pub fn blocked(&self) -> usize {
+        return self.waiters.len();
+    }
+
+    pub fn waiter(&self, fd: RawFd) -> Option<&Waiter> {
+        return self.waiters.get(&fd);
+    }
+
+    pub fn block(&mut self, fd: RawFd, waiter: Waiter) {
+        self.unblock(fd);
+        for key in waiter.keys.iter() {
+            let queue = self.queues.entry(key.clone()).or_default();
+            if !queue.contains(&fd) {
+                queue.push_back(fd);
+            }
+        }
+        if let Some(at) = waiter.deadline {
+            self.deadlines.insert((at, fd));
+        }
+        self.waiters.insert(fd, waiter);
+    }
+
+    pub fn unblock(&mut self, fd: RawFd) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&fd)?;
+        for key in waiter.keys.iter() {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|f| *f != fd);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        if let Some(at) = waiter.deadline {
+            self.deadlines.remove(&(at, fd));
+        }
+        return Some(waiter);
+    }
+
+    // Keys with connections waiting for them
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        return self.queues.keys().cloned().collect();
+    }
+
+    // Connections waiting for the key, longest waiting first
+    pub fn waiting(&self, key: &[u8]) -> Vec<RawFd> {
+        return self.queues.get(key).map(|queue| queue.iter().copied().collect()).unwrap_or_default();
+    }
+
+    // Remembers that the key may have something for its waiters now
+    pub fn touch(&mut self, key: &[u8]) {
+        if self.queues.contains_key(key) && !self.ready.iter().any(|k| k == key) {
+            self.ready.push(key.to_vec());
+        }
+    }
+
+    pub fn take_ready(&mut self) -> Vec<Vec<u8>> {
+        return std::mem::take(&mut self.ready);
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        return self.deadlines.first().map(|(at, _)| *at);
+    }
+
+    // Connections whose deadline is at or before `now`, earliest first
+    pub fn expired(&self, now: u64) -> Vec<RawFd> {
+        return self.deadlines.iter().take_while(|(at, _)| *at <= now).map(|(_, fd)| *fd).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(keys: &[&str], deadline: Option<u64>) -> Waiter {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|k| k.as_bytes().to_vec()).collect();
+        return Waiter { command: [vec![b"blpop".to_vec()], keys.clone(), vec![b"0".to_vec()]].concat(), keys: keys, deadline: deadline, asking: false };
+    }
+
+    #[test]
+    fn test_fifo() {
+        let mut blocking = Blocking::new();
+        blocking.block(5, waiter(&["a", "b"], None));
+        blocking.block(6, waiter(&["b"], None));
+        blocking.block(7, waiter(&["a"], None));
+        assert_eq!(blocking.waiting(b"a"), vec![5, 7]);
+        assert_eq!(blocking.waiting(b"b"), vec![5, 6]);
+        blocking.touch(b"a");
+        blocking.touch(b"a");
+        blocking.touch(b"c");
+        assert_eq!(blocking.take_ready(), vec![b"a".to_vec()]);
+        assert!(blocking.take_ready().is_empty());
+        assert_eq!(blocking.blocked(), 3);
+        assert!(blocking.unblock(5).is_some());
+        assert!(!blocking.is_blocked(5));
+        assert_eq!(blocking.waiting(b"a"), vec![7]);
+        assert_eq!(blocking.waiting(b"b"), vec![6]);
+        blocking.unblock(6);
+        assert!(blocking.waiting(b"b").is_empty());
+        assert!(blocking.unblock(6).is_none());
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let mut blocking = Blocking::new();
+        blocking.block(5, waiter(&["a"], Some(200)));
+        blocking.block(6, waiter(&["a"], Some(100)));
+        blocking.block(7, waiter(&["a"], None));
+        assert_eq!(blocking.next_deadline(), Some(100));
+        assert_eq!(blocking.expired(99), Vec::<RawFd>::new());
+        assert_eq!(blocking.expired(200), vec![6, 5]);
+        blocking.unblock(6);
+        assert_eq!(blocking.next_deadline(), Some(200));
+        blocking.unblock(5);
+        assert_eq!(blocking.next_deadline(), None);
+    }
+}
@@ -2,174 +2,740 @@ use nix::sys::socket::*;
 use nix::errno::Errno;
 use std::os::fd::RawFd;
 use nix::unistd::{close, read, write};
-use std::str::FromStr;
-use crate::server::ResType;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::fmt;
+use std::time::Duration;
+use nix::sys::time::{TimeVal, TimeValLike};
+use crate::server::{ResType, K_MAX_MSG};
 
-const K_MAX_MSG: usize = 4096;
+// One decoded reply, arrays hold further replies
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Nil,
+    Err { code: u32, msg: String },
+    Str(Vec<u8>),
+    Int(i64),
+    Dbl(f64),
+    Arr(Vec<Reply>),
+}
 
-#[derive(Debug)]
-pub struct FerdisResponse {
-    pub res_type: ResType,
-    pub res_code: u32,
-    pub message: Option<String>
+impl Reply {
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Reply::Nil => write!(f, "(nil)"),
+            Reply::Err { code, msg } => write!(f, "(error {}) {}", code, msg),
+            Reply::Str(data) => write!(f, "{:?}", String::from_utf8_lossy(data)),
+            Reply::Int(val) => write!(f, "(integer) {}", val),
+            Reply::Dbl(val) => write!(f, "(double) {}", val),
+            Reply::Arr(items) => {
+                if items.is_empty() {
+                    return write!(f, "(empty array)");
+                }
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n{:width$}", "", width = indent)?;
+                    }
+                    let prefix = format!("{}) ", i + 1);
+                    write!(f, "{}", prefix)?;
+                    item.fmt_indented(f, indent + prefix.len())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Printed the way redis-cli shows replies
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+// Address used by `send_message` and `send_pipeline`
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8081";
+
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    // the address could not be resolved to an IPv4 socket address
+    Address(String),
+    Io(Errno),
+    // the server closed the connection
+    Closed,
+    // the reply could not be decoded, the connection is dropped
+    Protocol,
+    // a request or reply over K_MAX_MSG
+    TooLarge,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Address(addr) => write!(f, "invalid address {}", addr),
+            ClientError::Io(e) => write!(f, "i/o error {}", e),
+            ClientError::Closed => write!(f, "connection closed by server"),
+            ClientError::Protocol => write!(f, "malformed reply"),
+            ClientError::TooLarge => write!(f, "message too long"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<Errno> for ClientError {
+    fn from(e: Errno) -> ClientError {
+        match e {
+            Errno::EPROTO => ClientError::Protocol,
+            _ => ClientError::Io(e),
+        }
+    }
 }
 
 // Guard against partial writes
-fn write_full(fd: RawFd, wbuf: &mut[u8]) -> Result<usize, Errno> {
+fn write_full(fd: RawFd, wbuf: &[u8]) -> Result<(), ClientError> {
     let mut buf_start = 0;
-    let mut n = wbuf.len();
-    while n > 0 {
-        match write(fd, &mut wbuf[buf_start..]) {
+    while buf_start < wbuf.len() {
+        match write(fd, &wbuf[buf_start..]) {
+            Ok(0) => {
+                return Err(ClientError::Closed);
+            },
             Ok(rv) => {
-                if rv <= 0 {
-                    println!("Zero bytes written");
-                    return Err(Errno::EIO);
-                }
-                assert!(rv <= n);
-                n -= rv;
                 buf_start += rv;
             },
+            Err(Errno::EINTR) => {
+                continue;
+            },
             Err(e) => {
-                println!("Error while writing {}", e);
-                return Err(e);
+                return Err(ClientError::Io(e));
             }
         }
     }
-    Ok(n)
+    Ok(())
 }
 
 // Guard against partial reads
-fn read_full(fd: RawFd, rbuf: &mut[u8]) -> Result<usize, Errno> {
+fn read_full(fd: RawFd, rbuf: &mut[u8]) -> Result<(), ClientError> {
     let mut buf_start = 0;
-    let mut n = rbuf.len();
-    while n > 0 {
+    while buf_start < rbuf.len() {
         match read(fd, &mut rbuf[buf_start..]) {
+            Ok(0) => {
+                return Err(ClientError::Closed);
+            },
             Ok(rv) => {
-                if rv <= 0 {
-                    println!("Zero bytes read");
-                    return Err(Errno::EIO);
-                }
-                assert!(rv <= n);
-                n -= rv;
                 buf_start += rv;
-            }, Err(e) => {
-                println!("Error while reading {}", e);
-                return Err(e);
+            },
+            Err(Errno::EINTR) => {
+                continue;
+            },
+            Err(e) => {
+                return Err(ClientError::Io(e));
             }
         }
     }
-    Ok(n)
+    Ok(())
 }
 
-fn send_request(fd: RawFd, text: &str) -> Result<usize, Errno> {
-    let reply: &[u8] = text.as_bytes();
-    let mut wbuf = [0; K_MAX_MSG];
-    let length = u32::try_from(reply.len()).unwrap();
-    wbuf[0..4].copy_from_slice(&length.to_le_bytes());
-    wbuf[4..4 + reply.len()].copy_from_slice(reply);
-    write_full(fd, &mut wbuf[0..4 + reply.len()])
+// | len (u32) | nstr (u32) | len (u32) | bytes | len (u32) | bytes | ...
+pub fn serialize_request<T: AsRef<[u8]>>(args: &[T]) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend_from_slice(&u32::try_from(args.len()).unwrap().to_le_bytes());
+    for arg in args {
+        let arg = arg.as_ref();
+        body.extend_from_slice(&u32::try_from(arg.len()).unwrap().to_le_bytes());
+        body.extend_from_slice(arg);
+    }
+    let mut wbuf: Vec<u8> = Vec::with_capacity(4 + body.len());
+    wbuf.extend_from_slice(&u32::try_from(body.len()).unwrap().to_le_bytes());
+    wbuf.extend_from_slice(&body);
+    return wbuf;
 }
 
-fn read_response(fd: RawFd) -> Result<FerdisResponse, Errno> {
+fn read_response(fd: RawFd) -> Result<Reply, ClientError> {
     let mut len_buf: [u8; 4] = [0; 4];
-    let mut length;
-    let mut rbuf: [u8; K_MAX_MSG] = [0; K_MAX_MSG];
-    match read_full(fd, &mut len_buf) {
-        Ok(_) => {
-            length = u32::from_le_bytes(len_buf);
-        },
-        Err(e) => {
-            println!("read() error {}", e);
-            return Err(e);
-        }
+    read_full(fd, &mut len_buf)?;
+    let length = usize::try_from(u32::from_le_bytes(len_buf)).unwrap();
+    if length > K_MAX_MSG {
+        return Err(ClientError::TooLarge);
     }
-    let response;
-    match read_full(fd, &mut rbuf[..length.try_into().unwrap()]) {
-        Ok(_) => {
-            response = deserialize_response(&mut rbuf[..length.try_into().unwrap()]);
-            return Ok(response);
-        }
-        Err(e) => {
-            println!("read() error {}", e);
-            return Err(e);
-        }
+    let mut rbuf: Vec<u8> = vec![0; length];
+    read_full(fd, &mut rbuf)?;
+    return Ok(deserialize_response(&rbuf)?);
+}
+
+// Decodes a whole response body, EPROTO when it is malformed
+pub fn deserialize_response(rbuf: &[u8]) -> Result<Reply, Errno> {
+    let (reply, used) = deserialize_value(rbuf)?;
+    if used != rbuf.len() {
+        // trailing garbage
+        return Err(Errno::EPROTO);
     }
+    return Ok(reply);
 }
 
-pub fn deserialize_response(rbuf: &mut[u8]) -> FerdisResponse {
-    let res_type_u32 = deserialize_u32(&mut rbuf[0..4]);
-    let res_type = ResType::from_u32(res_type_u32);
-    match res_type {
+// Decodes one value from the front of the buffer, returns it together with
+// the number of bytes it used up so array elements can be walked
+fn deserialize_value(rbuf: &[u8]) -> Result<(Reply, usize), Errno> {
+    let res_type = deserialize_u32(rbuf, 0)?;
+    if res_type > ResType::DBL as u32 {
+        return Err(Errno::EPROTO);
+    }
+    match ResType::from_u32(res_type) {
         ResType::NIL => {
-            return FerdisResponse{res_type: res_type, res_code: 0, message: None};
+            return Ok((Reply::Nil, 4));
         },
         ResType::ERR => {
-            let err_code = deserialize_u32(&mut rbuf[4..8]);
-            let message_length = deserialize_u32(&mut rbuf[8..12]);
-            let message = deserialize_string(&mut rbuf[12..], usize::try_from(message_length).unwrap());
-            return FerdisResponse{res_type: res_type, res_code: err_code, message: Some(message) };
+            let code = deserialize_u32(rbuf, 4)?;
+            let length = usize::try_from(deserialize_u32(rbuf, 8)?).unwrap();
+            let msg = rbuf.get(12..12 + length).ok_or(Errno::EPROTO)?;
+            return Ok((Reply::Err { code: code, msg: String::from_utf8_lossy(msg).into_owned() }, 12 + length));
         },
         ResType::STR => {
-            let message_length = deserialize_u32(&mut rbuf[4..8]);
-            let message = deserialize_string(&mut rbuf[8..], usize::try_from(message_length).unwrap());
-            return FerdisResponse{res_type: res_type, res_code: 0, message: Some(message) };
+            let length = usize::try_from(deserialize_u32(rbuf, 4)?).unwrap();
+            let data = rbuf.get(8..8 + length).ok_or(Errno::EPROTO)?;
+            return Ok((Reply::Str(data.to_vec()), 8 + length));
         },
         ResType::ARR => {
-            let mut strings: Vec<String> = Vec::new();
-            let arr_len = deserialize_u32(&mut rbuf[4..8]);
-            let mut start = 8;
-            for _ in 0..arr_len {
-                let resp = deserialize_response(&mut rbuf[start..]);
-                let message = resp.message.unwrap();
-                start += 8;
-                start += message.len();
-                strings.push(message);
+            let n = deserialize_u32(rbuf, 4)?;
+            let mut items = Vec::new();
+            let mut pos = 8;
+            for _ in 0..n {
+                let (item, used) = deserialize_value(&rbuf[pos..])?;
+                items.push(item);
+                pos += used;
             }
-            let mut out = String::new();
-            out.push_str("[");
-            out.push_str(&strings.join(", "));
-            out.push_str("]");
-            return FerdisResponse{res_type: res_type, res_code: 0, message: Some(out) };
+            return Ok((Reply::Arr(items), pos));
+        },
+        ResType::INT => {
+            let bytes = rbuf.get(4..12).ok_or(Errno::EPROTO)?;
+            return Ok((Reply::Int(i64::from_le_bytes(bytes.try_into().unwrap())), 12));
+        },
+        ResType::DBL => {
+            let bytes = rbuf.get(4..12).ok_or(Errno::EPROTO)?;
+            return Ok((Reply::Dbl(f64::from_le_bytes(bytes.try_into().unwrap())), 12));
         },
     }
 }
 
-pub fn deserialize_u32(rbuf: &mut[u8]) -> u32 {
-    let mut buf: [u8; 4] = [0; 4];
-    buf.copy_from_slice(&rbuf[0..4]);
-    let val = u32::from_le_bytes(buf);
-    return val;
+fn deserialize_u32(rbuf: &[u8], pos: usize) -> Result<u32, Errno> {
+    let bytes = rbuf.get(pos..pos + 4).ok_or(Errno::EPROTO)?;
+    return Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
 }
 
-pub fn deserialize_string(rbuf: &mut[u8], length: usize) -> String {
-    String::from_utf8(rbuf[..length.try_into().unwrap()].to_vec()).unwrap()
+// A connection to one server that is kept open between commands. After an
+// I/O or protocol error the socket is dropped and the next command connects
+// again.
+pub struct FerdisClient {
+    addr: SockaddrIn,
+    fd: Option<RawFd>,
+    timeout: Option<Duration>,
 }
 
-pub fn send_message(req: String) -> Result<FerdisResponse, Errno> {
-    let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None);
-    match fd {
-        Ok(fd) => {
-            let localhost = SockaddrIn::from_str("127.0.0.1:8081").unwrap();
-            match connect(fd, &localhost) {
-                Ok(()) => {
-                    if let Err(e) = send_request(fd, &req) {
-                        println!("Error {} sending request {}", e, req);
-                        let _ = close(fd);
-                        return Err(e);
-                    }
-                },
-                Err(e) => {
-                    println!("Error connecting to server {}", e);
-                    return Err(e);
-                }
-            }
-            let res = read_response(fd);
+// Zero turns the socket timeouts off
+fn apply_timeout(fd: RawFd, timeout: Option<Duration>) -> Result<(), Errno> {
+    let ms = timeout.map_or(0, |t| i64::try_from(t.as_millis()).unwrap_or(i64::MAX).max(1));
+    setsockopt(fd, sockopt::ReceiveTimeout, &TimeVal::milliseconds(ms))?;
+    setsockopt(fd, sockopt::SendTimeout, &TimeVal::milliseconds(ms))?;
+    return Ok(());
+}
+
+impl FerdisClient {
+    // Resolves `addr` ("host:port") and connects right away
+    pub fn connect(addr: &str) -> Result<FerdisClient, ClientError> {
+        let sockaddr = addr.to_socket_addrs().ok()
+            .and_then(|mut addrs| addrs.find_map(|a| match a {
+                SocketAddr::V4(v4) => Some(SockaddrIn::from(v4)),
+                SocketAddr::V6(_) => None,
+            }))
+            .ok_or_else(|| ClientError::Address(addr.to_string()))?;
+        let mut client = FerdisClient { addr: sockaddr, fd: None, timeout: None };
+        client.reconnect()?;
+        return Ok(client);
+    }
+
+    fn reconnect(&mut self) -> Result<RawFd, ClientError> {
+        self.disconnect();
+        let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None)?;
+        if let Err(e) = connect(fd, &self.addr).and_then(|_| apply_timeout(fd, self.timeout)) {
             let _ = close(fd);
-            return res;
-        },
-        Err(e) => {
-            println!("Error opening socket {}", e);
-            return Err(e);
+            return Err(ClientError::Io(e));
+        }
+        self.fd = Some(fd);
+        return Ok(fd);
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            let _ = close(fd);
+        }
+    }
+
+    // Fails reads and writes that take longer than `timeout` with EAGAIN,
+    // None waits forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), ClientError> {
+        self.timeout = timeout;
+        if let Some(fd) = self.fd {
+            apply_timeout(fd, timeout)?;
+        }
+        return Ok(());
+    }
+
+    // Sends one command, each argument is sent as a binary string
+    pub fn command<T: AsRef<[u8]>>(&mut self, args: &[T]) -> Result<Reply, ClientError> {
+        let mut replies = self.pipeline(&[args])?;
+        return Ok(replies.remove(0));
+    }
+
+    // Sends all commands in one write and then reads one reply per command,
+    // replies come back in the order of the commands
+    pub fn pipeline<T: AsRef<[u8]>, C: AsRef<[T]>>(&mut self, commands: &[C]) -> Result<Vec<Reply>, ClientError> {
+        let mut wbuf: Vec<u8> = Vec::new();
+        for command in commands {
+            let req = serialize_request(command.as_ref());
+            if req.len() > 4 + K_MAX_MSG {
+                return Err(ClientError::TooLarge);
+            }
+            wbuf.extend_from_slice(&req);
+        }
+        return self.exchange(&wbuf, commands.len());
+    }
+
+    // Writes `wbuf` and reads `replies` replies
+    fn exchange(&mut self, wbuf: &[u8], replies: usize) -> Result<Vec<Reply>, ClientError> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => self.reconnect()?,
+        };
+        let res = write_full(fd, wbuf).and_then(|_| {
+            let mut out = Vec::with_capacity(replies);
+            for _ in 0..replies {
+                out.push(read_response(fd)?);
+            }
+            Ok(out)
+        });
+        if res.is_err() {
+            // the stream may be out of step with the replies
+            self.disconnect();
+        }
+        return res;
+    }
+
+    // Subscribes to channels and returns one confirmation per channel. The
+    // connection is in push mode afterwards, messages arrive through
+    // `next_message`.
+    pub fn subscribe<C: AsRef<[u8]>>(&mut self, channels: &[C]) -> Result<Vec<Reply>, ClientError> {
+        return self.subscription("subscribe", channels);
+    }
+
+    pub fn psubscribe<C: AsRef<[u8]>>(&mut self, patterns: &[C]) -> Result<Vec<Reply>, ClientError> {
+        return self.subscription("psubscribe", patterns);
+    }
+
+    pub fn unsubscribe<C: AsRef<[u8]>>(&mut self, channels: &[C]) -> Result<Vec<Reply>, ClientError> {
+        return self.subscription("unsubscribe", channels);
+    }
+
+    pub fn punsubscribe<C: AsRef<[u8]>>(&mut self, patterns: &[C]) -> Result<Vec<Reply>, ClientError> {
+        return self.subscription("punsubscribe", patterns);
+    }
+
+    fn subscription<C: AsRef<[u8]>>(&mut self, kind: &str, names: &[C]) -> Result<Vec<Reply>, ClientError> {
+        let mut args: Vec<&[u8]> = vec![kind.as_bytes()];
+        args.extend(names.iter().map(|name| name.as_ref()));
+        let mut wbuf = serialize_request(&args);
+        if !names.is_empty() {
+            return self.exchange(&wbuf, names.len());
+        }
+        // without names the number of confirmations is up to the server, a
+        // ping behind the command marks the end
+        wbuf.extend_from_slice(&serialize_request(&["ping"]));
+        let mut replies = self.exchange(&wbuf, 1)?;
+        while replies.last() != Some(&Reply::Str(b"PONG".to_vec())) {
+            replies.extend(self.exchange(&[], 1)?);
+        }
+        replies.pop();
+        return Ok(replies);
+    }
+
+    // Waits for the next pushed message, ["message", channel, payload] or
+    // ["pmessage", pattern, channel, payload]. Like any error, running into
+    // the timeout drops the connection and with it the subscriptions.
+    pub fn next_message(&mut self) -> Result<Reply, ClientError> {
+        let mut replies = self.exchange(&[], 1)?;
+        return Ok(replies.remove(0));
+    }
+
+    pub fn publish<C: AsRef<[u8]>, M: AsRef<[u8]>>(&mut self, channel: C, message: M) -> Result<Reply, ClientError> {
+        return self.command(&[b"publish".as_slice(), channel.as_ref(), message.as_ref()]);
+    }
+
+    // Commands sent after multi answer "QUEUED" and run with exec
+    pub fn multi(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["multi"]);
+    }
+
+    // Replies of the queued commands in an array, NIL when a watched key
+    // changed and nothing ran
+    pub fn exec(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["exec"]);
+    }
+
+    pub fn discard(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["discard"]);
+    }
+
+    pub fn watch<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"watch"];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn unwatch(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["unwatch"]);
+    }
+
+    pub fn ping(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["ping"]);
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"get".as_slice(), key.as_ref()]);
+    }
+
+    pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<Reply, ClientError> {
+        return self.command(&[b"set".as_slice(), key.as_ref(), value.as_ref()]);
+    }
+
+    // set with a time to live in milliseconds
+    pub fn set_px<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V, ttl_ms: u64) -> Result<Reply, ClientError> {
+        let ttl = ttl_ms.to_string();
+        return self.command(&[b"set".as_slice(), key.as_ref(), value.as_ref(), b"px", ttl.as_bytes()]);
+    }
+
+    pub fn del<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"del".as_slice(), key.as_ref()]);
+    }
+
+    pub fn incr<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"incr".as_slice(), key.as_ref()]);
+    }
+
+    pub fn decr<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"decr".as_slice(), key.as_ref()]);
+    }
+
+    pub fn incr_by<K: AsRef<[u8]>>(&mut self, key: K, delta: i64) -> Result<Reply, ClientError> {
+        let delta = delta.to_string();
+        return self.command(&[b"incrby".as_slice(), key.as_ref(), delta.as_bytes()]);
+    }
+
+    pub fn decr_by<K: AsRef<[u8]>>(&mut self, key: K, delta: i64) -> Result<Reply, ClientError> {
+        let delta = delta.to_string();
+        return self.command(&[b"decrby".as_slice(), key.as_ref(), delta.as_bytes()]);
+    }
+
+    pub fn incr_by_float<K: AsRef<[u8]>>(&mut self, key: K, delta: f64) -> Result<Reply, ClientError> {
+        let delta = delta.to_string();
+        return self.command(&[b"incrbyfloat".as_slice(), key.as_ref(), delta.as_bytes()]);
+    }
+
+    pub fn lpush<K: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, key: K, items: &[I]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"lpush", key.as_ref()];
+        args.extend(items.iter().map(|i| i.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn rpush<K: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, key: K, items: &[I]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"rpush", key.as_ref()];
+        args.extend(items.iter().map(|i| i.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn lpop<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"lpop".as_slice(), key.as_ref()]);
+    }
+
+    pub fn rpop<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"rpop".as_slice(), key.as_ref()]);
+    }
+
+    // Waits up to `timeout` seconds, 0 forever, for an item in any of the
+    // lists and returns [key, item] or NIL
+    pub fn blpop<K: AsRef<[u8]>>(&mut self, keys: &[K], timeout: f64) -> Result<Reply, ClientError> {
+        return self.blocking_pop(b"blpop", keys, timeout);
+    }
+
+    pub fn brpop<K: AsRef<[u8]>>(&mut self, keys: &[K], timeout: f64) -> Result<Reply, ClientError> {
+        return self.blocking_pop(b"brpop", keys, timeout);
+    }
+
+    fn blocking_pop<K: AsRef<[u8]>>(&mut self, name: &[u8], keys: &[K], timeout: f64) -> Result<Reply, ClientError> {
+        let timeout = timeout.to_string();
+        let mut args: Vec<&[u8]> = vec![name];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        args.push(timeout.as_bytes());
+        return self.command(&args);
+    }
+
+    pub fn llen<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"llen".as_slice(), key.as_ref()]);
+    }
+
+    pub fn lrange<K: AsRef<[u8]>>(&mut self, key: K, start: i64, stop: i64) -> Result<Reply, ClientError> {
+        let (start, stop) = (start.to_string(), stop.to_string());
+        return self.command(&[b"lrange".as_slice(), key.as_ref(), start.as_bytes(), stop.as_bytes()]);
+    }
+
+    pub fn lindex<K: AsRef<[u8]>>(&mut self, key: K, index: i64) -> Result<Reply, ClientError> {
+        let index = index.to_string();
+        return self.command(&[b"lindex".as_slice(), key.as_ref(), index.as_bytes()]);
+    }
+
+    pub fn ltrim<K: AsRef<[u8]>>(&mut self, key: K, start: i64, stop: i64) -> Result<Reply, ClientError> {
+        let (start, stop) = (start.to_string(), stop.to_string());
+        return self.command(&[b"ltrim".as_slice(), key.as_ref(), start.as_bytes(), stop.as_bytes()]);
+    }
+
+    pub fn hset<K: AsRef<[u8]>, F: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, pairs: &[(F, V)]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"hset", key.as_ref()];
+        for (field, value) in pairs {
+            args.push(field.as_ref());
+            args.push(value.as_ref());
         }
-    };
+        return self.command(&args);
+    }
+
+    pub fn hget<K: AsRef<[u8]>, F: AsRef<[u8]>>(&mut self, key: K, field: F) -> Result<Reply, ClientError> {
+        return self.command(&[b"hget".as_slice(), key.as_ref(), field.as_ref()]);
+    }
+
+    pub fn hdel<K: AsRef<[u8]>, F: AsRef<[u8]>>(&mut self, key: K, fields: &[F]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"hdel", key.as_ref()];
+        args.extend(fields.iter().map(|f| f.as_ref()));
+        return self.command(&args);
+    }
+
+    // Fields and values alternating in one array
+    pub fn hgetall<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"hgetall".as_slice(), key.as_ref()]);
+    }
+
+    pub fn hincrby<K: AsRef<[u8]>, F: AsRef<[u8]>>(&mut self, key: K, field: F, delta: i64) -> Result<Reply, ClientError> {
+        let delta = delta.to_string();
+        return self.command(&[b"hincrby".as_slice(), key.as_ref(), field.as_ref(), delta.as_bytes()]);
+    }
+
+    pub fn hlen<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"hlen".as_slice(), key.as_ref()]);
+    }
+
+    pub fn sadd<K: AsRef<[u8]>, M: AsRef<[u8]>>(&mut self, key: K, members: &[M]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"sadd", key.as_ref()];
+        args.extend(members.iter().map(|m| m.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn srem<K: AsRef<[u8]>, M: AsRef<[u8]>>(&mut self, key: K, members: &[M]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"srem", key.as_ref()];
+        args.extend(members.iter().map(|m| m.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn sismember<K: AsRef<[u8]>, M: AsRef<[u8]>>(&mut self, key: K, member: M) -> Result<Reply, ClientError> {
+        return self.command(&[b"sismember".as_slice(), key.as_ref(), member.as_ref()]);
+    }
+
+    pub fn smembers<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"smembers".as_slice(), key.as_ref()]);
+    }
+
+    pub fn scard<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"scard".as_slice(), key.as_ref()]);
+    }
+
+    pub fn sinter<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Reply, ClientError> {
+        return self.set_algebra(b"sinter", keys);
+    }
+
+    pub fn sunion<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Reply, ClientError> {
+        return self.set_algebra(b"sunion", keys);
+    }
+
+    // Members of the first set that are in none of the others
+    pub fn sdiff<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Reply, ClientError> {
+        return self.set_algebra(b"sdiff", keys);
+    }
+
+    fn set_algebra<K: AsRef<[u8]>>(&mut self, name: &[u8], keys: &[K]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![name];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn srandmember<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"srandmember".as_slice(), key.as_ref()]);
+    }
+
+    pub fn spop<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"spop".as_slice(), key.as_ref()]);
+    }
+
+    // `id` is "*" for one picked by the server, the reply is the id used
+    pub fn xadd<K: AsRef<[u8]>, I: AsRef<[u8]>, F: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, id: I, fields: &[(F, V)]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"xadd", key.as_ref(), id.as_ref()];
+        for (field, value) in fields {
+            args.push(field.as_ref());
+            args.push(value.as_ref());
+        }
+        return self.command(&args);
+    }
+
+    pub fn xlen<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"xlen".as_slice(), key.as_ref()]);
+    }
+
+    // Entries as [id, [field, value...]], "-" and "+" are the ends of the stream
+    pub fn xrange<K: AsRef<[u8]>, S: AsRef<[u8]>, E: AsRef<[u8]>>(&mut self, key: K, start: S, end: E) -> Result<Reply, ClientError> {
+        return self.command(&[b"xrange".as_slice(), key.as_ref(), start.as_ref(), end.as_ref()]);
+    }
+
+    pub fn xtrim<K: AsRef<[u8]>>(&mut self, key: K, maxlen: usize) -> Result<Reply, ClientError> {
+        let maxlen = maxlen.to_string();
+        return self.command(&[b"xtrim".as_slice(), key.as_ref(), b"maxlen", maxlen.as_bytes()]);
+    }
+
+    // The entries after each id without waiting, NIL when there are none
+    pub fn xread<K: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, keys: &[K], ids: &[I]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"xread", b"streams"];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        args.extend(ids.iter().map(|i| i.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn xgroup_create<K: AsRef<[u8]>, G: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, key: K, group: G, id: I) -> Result<Reply, ClientError> {
+        return self.command(&[b"xgroup".as_slice(), b"create", key.as_ref(), group.as_ref(), id.as_ref()]);
+    }
+
+    // ">" as id reads entries no consumer of the group got yet
+    pub fn xreadgroup<G: AsRef<[u8]>, C: AsRef<[u8]>, K: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, group: G, consumer: C, keys: &[K], ids: &[I]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"xreadgroup", b"group", group.as_ref(), consumer.as_ref(), b"streams"];
+        args.extend(keys.iter().map(|k| k.as_ref()));
+        args.extend(ids.iter().map(|i| i.as_ref()));
+        return self.command(&args);
+    }
+
+    pub fn xack<K: AsRef<[u8]>, G: AsRef<[u8]>, I: AsRef<[u8]>>(&mut self, key: K, group: G, ids: &[I]) -> Result<Reply, ClientError> {
+        let mut args: Vec<&[u8]> = vec![b"xack", key.as_ref(), group.as_ref()];
+        args.extend(ids.iter().map(|i| i.as_ref()));
+        return self.command(&args);
+    }
+
+    // Pending entries of the group as [id, consumer, idle ms, deliveries]
+    pub fn xpending<K: AsRef<[u8]>, G: AsRef<[u8]>>(&mut self, key: K, group: G) -> Result<Reply, ClientError> {
+        return self.command(&[b"xpending".as_slice(), key.as_ref(), group.as_ref()]);
+    }
+
+    pub fn keys(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["keys"]);
+    }
+
+    pub fn dbsize(&mut self) -> Result<Reply, ClientError> {
+        return self.command(&["dbsize"]);
+    }
+
+    pub fn expire<K: AsRef<[u8]>>(&mut self, key: K, seconds: i64) -> Result<Reply, ClientError> {
+        let seconds = seconds.to_string();
+        return self.command(&[b"expire".as_slice(), key.as_ref(), seconds.as_bytes()]);
+    }
+
+    pub fn pexpire<K: AsRef<[u8]>>(&mut self, key: K, ms: i64) -> Result<Reply, ClientError> {
+        let ms = ms.to_string();
+        return self.command(&[b"pexpire".as_slice(), key.as_ref(), ms.as_bytes()]);
+    }
+
+    pub fn ttl<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"ttl".as_slice(), key.as_ref()]);
+    }
+
+    pub fn pttl<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"pttl".as_slice(), key.as_ref()]);
+    }
+
+    pub fn persist<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"persist".as_slice(), key.as_ref()]);
+    }
+
+    // `type` is a keyword
+    pub fn key_type<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Reply, ClientError> {
+        return self.command(&[b"type".as_slice(), key.as_ref()]);
+    }
+
+    pub fn zadd<K: AsRef<[u8]>, N: AsRef<[u8]>>(&mut self, key: K, score: f64, name: N) -> Result<Reply, ClientError> {
+        let score = score.to_string();
+        return self.command(&[b"zadd".as_slice(), key.as_ref(), score.as_bytes(), name.as_ref()]);
+    }
+
+    pub fn zrem<K: AsRef<[u8]>, N: AsRef<[u8]>>(&mut self, key: K, name: N) -> Result<Reply, ClientError> {
+        return self.command(&[b"zrem".as_slice(), key.as_ref(), name.as_ref()]);
+    }
+
+    pub fn zscore<K: AsRef<[u8]>, N: AsRef<[u8]>>(&mut self, key: K, name: N) -> Result<Reply, ClientError> {
+        return self.command(&[b"zscore".as_slice(), key.as_ref(), name.as_ref()]);
+    }
+
+    pub fn zrank<K: AsRef<[u8]>, N: AsRef<[u8]>>(&mut self, key: K, name: N) -> Result<Reply, ClientError> {
+        return self.command(&[b"zrank".as_slice(), key.as_ref(), name.as_ref()]);
+    }
+
+    pub fn zrange<K: AsRef<[u8]>>(&mut self, key: K, start: i64, stop: i64) -> Result<Reply, ClientError> {
+        let (start, stop) = (start.to_string(), stop.to_string());
+        return self.command(&[b"zrange".as_slice(), key.as_ref(), start.as_bytes(), stop.as_bytes()]);
+    }
+}
+
+impl Drop for FerdisClient {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+// Sends one command over a new connection to DEFAULT_ADDR
+pub fn send_message<T: AsRef<[u8]>>(args: &[T]) -> Result<Reply, ClientError> {
+    return FerdisClient::connect(DEFAULT_ADDR)?.command(args);
+}
+
+// Sends all commands over a new connection to DEFAULT_ADDR
+pub fn send_pipeline<T: AsRef<[u8]>, C: AsRef<[T]>>(commands: &[C]) -> Result<Vec<Reply>, ClientError> {
+    return FerdisClient::connect(DEFAULT_ADDR)?.pipeline(commands);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_nested() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(ResType::ARR as u32).to_le_bytes());
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&(ResType::NIL as u32).to_le_bytes());
+        buf.extend_from_slice(&(ResType::ARR as u32).to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&(ResType::INT as u32).to_le_bytes());
+        buf.extend_from_slice(&7i64.to_le_bytes());
+        buf.extend_from_slice(&(ResType::STR as u32).to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(b"\xff\x00");
+        buf.extend_from_slice(&(ResType::STR as u32).to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        let expected = Reply::Arr(vec![
+            Reply::Nil,
+            Reply::Arr(vec![Reply::Int(7), Reply::Str(vec![0xff, 0])]),
+            Reply::Str(Vec::new()),
+        ]);
+        assert_eq!(deserialize_response(&buf), Ok(expected));
+        // a cut off body is a protocol error, not a panic
+        for i in 0..buf.len() {
+            assert_eq!(deserialize_response(&buf[..i]), Err(Errno::EPROTO));
+        }
+    }
 }
@@ -0,0 +1,230 @@
+// Cluster mode: the key space is cut into 16384 hash slots and every slot is
+// served by one node. Keys are mapped to slots like in Redis Cluster, so
+// "{user1}.name" and "{user1}.mail" share the slot of "user1". Slots are
+// assigned by hand with the `cluster` command on every node; a node answers
+// MOVED for keys in slots it knows to be served elsewhere.
+//
+// A slot moves while it stays online: the target marks it IMPORTING and the
+// source MIGRATING, then `cluster migrate` on the source sends its keys over
+// in batches. Meanwhile the source serves the keys it still has and answers
+// ASK for the others, and the target serves the slot to clients that send
+// ASKING first. Once the last key is over both nodes hand the slot to the
+// target.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use crate::client::FerdisClient;
+
+pub const K_SLOTS: usize = 16384;
+
+// CRC-16/XMODEM, table built at compile time
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in data {
+        crc = (crc << 8) ^ CRC16_TABLE[usize::from(((crc >> 8) as u8) ^ *b)];
+    }
+    return crc;
+}
+
+// Only the part between the first '{' and the next '}' is hashed when it is
+// not empty
+pub fn key_slot(key: &[u8]) -> u16 {
+    let mut hashed = key;
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                hashed = &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    return crc16(hashed) & (K_SLOTS as u16 - 1);
+}
+
+pub struct Cluster {
+    // known nodes, this one first
+    nodes: Vec<SocketAddr>,
+    // index into `nodes` of the owner of every slot
+    slots: Vec<Option<u16>>,
+    // slots being moved away from this node and where to
+    migrating: HashMap<u16, SocketAddr>,
+    // slots being moved to this node and where from
+    importing: HashMap<u16, SocketAddr>,
+}
+
+// A running `cluster migrate`, the event loop moves one batch at a time
+pub struct Migration {
+    pub slot: u16,
+    pub target: SocketAddr,
+    // keys of the slot not looked at yet
+    pub keys: Vec<Vec<u8>>,
+    pub batch: usize,
+    pub client: FerdisClient,
+    // unix time in milliseconds before which no batch is sent, set after
+    // the target failed
+    pub next_at: u64,
+}
+
+impl Cluster {
+    // `myself` is the address other nodes and clients reach this one at
+    pub fn new(myself: SocketAddr) -> Cluster {
+        return Cluster {
+            nodes: vec![myself],
+            slots: vec![None; K_SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        };
+    }
+
+    pub fn myself(&self) -> SocketAddr {
+        return self.nodes[0];
+    }
+
+    pub fn owner(&self, slot: u16) -> Option<SocketAddr> {
+        return self.slots[usize::from(slot)].map(|node| self.nodes[usize::from(node)]);
+    }
+
+    pub fn is_mine(&self, slot: u16) -> bool {
+        return self.slots[usize::from(slot)] == Some(0);
+    }
+
+    // Also ends a migration of the slot
+    pub fn assign(&mut self, slot: u16, node: SocketAddr) {
+        self.stable(slot);
+        let index = match self.nodes.iter().position(|n| *n == node) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.slots[usize::from(slot)] = Some(u16::try_from(index).unwrap());
+    }
+
+    pub fn unassign(&mut self, slot: u16) {
+        self.stable(slot);
+        self.slots[usize::from(slot)] = None;
+    }
+
+    pub fn migrating(&self, slot: u16) -> Option<SocketAddr> {
+        return self.migrating.get(&slot).copied();
+    }
+
+    pub fn importing(&self, slot: u16) -> Option<SocketAddr> {
+        return self.importing.get(&slot).copied();
+    }
+
+    pub fn set_migrating(&mut self, slot: u16, target: SocketAddr) {
+        self.importing.remove(&slot);
+        self.migrating.insert(slot, target);
+    }
+
+    pub fn set_importing(&mut self, slot: u16, source: SocketAddr) {
+        self.migrating.remove(&slot);
+        self.importing.insert(slot, source);
+    }
+
+    // Forgets a migration of the slot in either direction
+    pub fn stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    // Slots on the move as (slot, other node, migrating), in slot order
+    pub fn moving(&self) -> Vec<(u16, SocketAddr, bool)> {
+        let mut out: Vec<(u16, SocketAddr, bool)> = self.migrating.iter().map(|(s, n)| (*s, *n, true))
+            .chain(self.importing.iter().map(|(s, n)| (*s, *n, false)))
+            .collect();
+        out.sort_by_key(|m| m.0);
+        return out;
+    }
+
+    pub fn assigned(&self) -> usize {
+        return self.slots.iter().filter(|s| s.is_some()).count();
+    }
+
+    pub fn known_nodes(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    // Runs of consecutive slots with the same owner: (first, last, owner)
+    pub fn ranges(&self) -> Vec<(u16, u16, SocketAddr)> {
+        let mut out: Vec<(u16, u16, SocketAddr)> = Vec::new();
+        for slot in 0..K_SLOTS as u16 {
+            let owner = match self.owner(slot) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            match out.last_mut() {
+                Some(last) if last.1 + 1 == slot && last.2 == owner => {
+                    last.1 = slot;
+                },
+                _ => {
+                    out.push((slot, slot, owner));
+                }
+            }
+        }
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"123456789"), 12739);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        // an empty tag hashes the whole key
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & 16383);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let me: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let mut cluster = Cluster::new(me);
+        for slot in 0..10 {
+            cluster.assign(slot, me);
+        }
+        cluster.assign(10, other);
+        cluster.assign(11, me);
+        cluster.unassign(5);
+        assert!(cluster.is_mine(4));
+        assert!(!cluster.is_mine(10));
+        assert_eq!(cluster.owner(5), None);
+        assert_eq!(cluster.assigned(), 11);
+        assert_eq!(cluster.known_nodes(), 2);
+        assert_eq!(cluster.ranges(), vec![(0, 4, me), (6, 9, me), (10, 10, other), (11, 11, me)]);
+
+        cluster.set_migrating(4, other);
+        cluster.set_importing(10, other);
+        assert_eq!(cluster.migrating(4), Some(other));
+        assert_eq!(cluster.moving(), vec![(4, other, true), (10, other, false)]);
+        // handing the slot over ends the migration
+        cluster.assign(4, other);
+        cluster.stable(10);
+        assert_eq!(cluster.migrating(4), None);
+        assert_eq!(cluster.importing(10), None);
+        assert_eq!(cluster.owner(4), Some(other));
+    }
+}
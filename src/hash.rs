@@ -0,0 +1,145 @@
+use crate::oa_map::OAMap;
+
+// A hash stays a plain vector of pairs while it has at most this many
+// fields and none of its fields or values is longer than K_SMALL_LEN.
+// Scanning a few pairs beats hashing and costs no table.
+const K_SMALL_FIELDS: usize = 64;
+const K_SMALL_LEN: usize = 64;
+
+#[derive(Clone, Debug)]
+enum Encoding {
+    Small(Vec<(Vec<u8>, Vec<u8>)>),
+    Map(OAMap<Vec<u8>, Vec<u8>>),
+}
+
+// Field/value map stored under one key. Small hashes are kept compact and
+// move to an OAMap once they grow; they never move back.
+#[derive(Clone, Debug)]
+pub struct Hash {
+    encoding: Encoding,
+}
+
+impl Default for Hash {
+    fn default() -> Hash {
+        return Hash { encoding: Encoding::Small(Vec::new()) };
+    }
+}
+
+impl Hash {
+    pub fn new() -> Hash {
+        return Hash::default();
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Small(pairs) => pairs.len(),
+            Encoding::Map(map) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        match &self.encoding {
+            Encoding::Small(pairs) => pairs.iter().find(|(f, _)| f == field).map(|(_, v)| v.as_slice()),
+            Encoding::Map(map) => map.get_ref(&field.to_vec()).map(|v| v.as_slice()),
+        }
+    }
+
+    // Returns true when the field was not there before
+    pub fn insert(&mut self, field: &[u8], value: &[u8]) -> bool {
+        if let Encoding::Small(pairs) = &mut self.encoding {
+            if let Some(pair) = pairs.iter_mut().find(|(f, _)| f == field) {
+                if value.len() <= K_SMALL_LEN {
+                    pair.1 = value.to_vec();
+                    return false;
+                }
+            } else if pairs.len() < K_SMALL_FIELDS && field.len() <= K_SMALL_LEN && value.len() <= K_SMALL_LEN {
+                pairs.push((field.to_vec(), value.to_vec()));
+                return true;
+            }
+            self.grow();
+        }
+        match &mut self.encoding {
+            Encoding::Map(map) => {
+                let new = map.get_ref(&field.to_vec()).is_none();
+                map.put(field.to_vec(), value.to_vec());
+                return new;
+            },
+            Encoding::Small(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        match &mut self.encoding {
+            Encoding::Small(pairs) => match pairs.iter().position(|(f, _)| f == field) {
+                Some(i) => {
+                    pairs.swap_remove(i);
+                    return true;
+                },
+                None => {
+                    return false;
+                }
+            },
+            Encoding::Map(map) => {
+                return map.remove(&field.to_vec()).is_some();
+            }
+        }
+    }
+
+    // Field/value pairs in no particular order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &[u8])> + '_> {
+        match &self.encoding {
+            Encoding::Small(pairs) => Box::new(pairs.iter().map(|(f, v)| (f.as_slice(), v.as_slice()))),
+            Encoding::Map(map) => Box::new(map.iter().map(|(f, v)| (f.as_slice(), v.as_slice()))),
+        }
+    }
+
+    fn grow(&mut self) {
+        if let Encoding::Small(pairs) = &mut self.encoding {
+            let mut map = OAMap::new_with_capacity(pairs.len() * 2);
+            for (field, value) in pairs.drain(..) {
+                map.put(field, value);
+            }
+            self.encoding = Encoding::Map(map);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_and_map() {
+        let mut hash = Hash::new();
+        assert!(hash.insert(b"name", b"ann"));
+        assert!(!hash.insert(b"name", b"bob"));
+        assert_eq!(hash.get(b"name"), Some(b"bob".as_slice()));
+        assert_eq!(hash.get(b"mail"), None);
+        assert!(matches!(hash.encoding, Encoding::Small(_)));
+
+        // a long value moves the hash to a map and keeps what it had
+        let long = vec![b'x'; K_SMALL_LEN + 1];
+        assert!(!hash.insert(b"name", &long));
+        assert!(matches!(hash.encoding, Encoding::Map(_)));
+        assert_eq!(hash.get(b"name"), Some(long.as_slice()));
+        assert!(hash.remove(b"name"));
+        assert!(!hash.remove(b"name"));
+        assert!(hash.is_empty());
+
+        // and so do many fields
+        let mut hash = Hash::new();
+        for i in 0..K_SMALL_FIELDS + 10 {
+            assert!(hash.insert(i.to_string().as_bytes(), b"v"));
+        }
+        assert!(matches!(hash.encoding, Encoding::Map(_)));
+        assert_eq!(hash.len(), K_SMALL_FIELDS + 10);
+        assert_eq!(hash.iter().count(), K_SMALL_FIELDS + 10);
+        assert_eq!(hash.get(b"3"), Some(b"v".as_slice()));
+        assert!(hash.remove(b"3"));
+        assert_eq!(hash.len(), K_SMALL_FIELDS + 9);
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::oa_map::OAMap;
+use crate::zset::ZSet;
+use crate::list::List;
+use crate::hash::Hash;
+use crate::set::Set;
+use crate::stream::Stream;
+
+// Upper bound of keys removed by one active expiration pass, so a burst of
+// deadlines does not stall the event loop
+const K_MAX_EXPIRE_WORK: usize = 2000;
+
+// Every key holds exactly one kind of value. Commands check the kind they
+// expect and answer with a WRONGTYPE error on a mismatch.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Str(Vec<u8>),
+    ZSet(ZSet),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    Stream(Stream),
+}
+
+impl Value {
+    // Name reported by the `type` command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::ZSet(_) => "zset",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub value: Value,
+    pub expire_at: Option<u64>,
+}
+
+// All keys with their deadlines. Deadlines are unix time in milliseconds and
+// are mirrored in a min-heap so the nearest one is found without a scan.
+// The heap is never updated in place: stale items (the key was deleted or
+// got a new deadline) are dropped when they reach the top.
+//
+// Keys watched by a transaction also get a version that goes up on every
+// change, including deletion and expiry, so `exec` can tell whether they
+// were touched since `watch`. Versions are only kept while someone watches.
+#[derive(Debug)]
+pub struct Keyspace {
+    map: OAMap<Vec<u8>, Entry>,
+    expiry: BinaryHeap<Reverse<(u64, Vec<u8>)>>,
+    // key -> (version, number of watchers)
+    versions: HashMap<Vec<u8>, (u64, usize)>,
+}
+
+pub fn now_ms() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    return u64::try_from(since_epoch.as_millis()).unwrap();
+}
+
+impl Default for Keyspace {
+    fn default() -> Keyspace {
+        return Keyspace { map: OAMap::new(), expiry: BinaryHeap::new(), versions: HashMap::new() };
+    }
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        return Keyspace::default();
+    }
+
+    // Lazy expiration: an expired key is removed when it is looked up
+    fn expire_if_needed(&mut self, key: &Vec<u8>, now: u64) {
+        let expired = match self.map.get_ref(key) {
+            Some(entry) => entry.expire_at.is_some_and(|at| at <= now),
+            None => false,
+        };
+        if expired {
+            self.map.remove(key);
+            self.touch(key);
+        }
+    }
+
+    // Counts a change of the key for its watchers
+    fn touch(&mut self, key: &[u8]) {
+        if let Some((version, _)) = self.versions.get_mut(key) {
+            *version += 1;
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8], now: u64) -> Option<&Entry> {
+        let key = key.to_vec();
+        self.expire_if_needed(&key, now);
+        return self.map.get_ref(&key);
+    }
+
+    // For changing the entry in place. Counts as a change for watchers even
+    // when the caller ends up leaving the entry alone.
+    pub fn get_mut(&mut self, key: &[u8], now: u64) -> Option<&mut Entry> {
+        let key = key.to_vec();
+        self.expire_if_needed(&key, now);
+        if self.map.get_ref(&key).is_some() {
+            self.touch(&key);
+        }
+        return self.map.get_mut(&key);
+    }
+
+    // Returns the entry of `key`, inserting one made by `make` if the key
+    // does not exist
+    pub fn get_or_insert(&mut self, key: &[u8], now: u64, make: fn() -> Value) -> &mut Entry {
+        if self.contains_key(key, now) {
+            self.touch(key);
+        } else {
+            self.set(key, make());
+        }
+        return self.map.get_mut(&key.to_vec()).unwrap();
+    }
+
+    pub fn contains_key(&mut self, key: &[u8], now: u64) -> bool {
+        return self.get(key, now).is_some();
+    }
+
+    // Replaces the value and drops any deadline
+    pub fn set(&mut self, key: &[u8], value: Value) {
+        self.map.put(key.to_vec(), Entry { value, expire_at: None });
+        self.touch(key);
+    }
+
+    pub fn set_with_deadline(&mut self, key: &[u8], value: Value, deadline: u64) {
+        self.map.put(key.to_vec(), Entry { value, expire_at: Some(deadline) });
+        self.expiry.push(Reverse((deadline, key.to_vec())));
+        self.touch(key);
+    }
+
+    pub fn delete(&mut self, key: &[u8], now: u64) -> Option<Entry> {
+        let key = key.to_vec();
+        self.expire_if_needed(&key, now);
+        let removed = self.map.remove(&key);
+        if removed.is_some() {
+            self.touch(&key);
+        }
+        return removed;
+    }
+
+    // Drops every key, watchers see all of their keys change
+    pub fn clear(&mut self) {
+        self.map = OAMap::new();
+        self.expiry.clear();
+        for (version, _) in self.versions.values_mut() {
+            *version += 1;
+        }
+    }
+
+    pub fn keys(&self, now: u64) -> Vec<Vec<u8>> {
+        return self.map.iter()
+            .filter(|(_, e)| e.expire_at.is_none_or(|at| at > now))
+            .map(|(k, _)| k.clone())
+            .collect();
+    }
+
+    // Keys that have not expired at `now` with their entries
+    pub fn iter(&self, now: u64) -> impl Iterator<Item = (&[u8], &Entry)> {
+        return self.map.iter()
+            .filter(move |(_, e)| e.expire_at.is_none_or(|at| at > now))
+            .map(|(k, e)| (k.as_slice(), e));
+    }
+
+    // Number of keys that have not expired at `now`
+    pub fn len(&self, now: u64) -> usize {
+        return self.map.iter()
+            .filter(|(_, e)| e.expire_at.is_none_or(|at| at > now))
+            .count();
+    }
+
+    // Returns false when the key does not exist
+    pub fn set_deadline(&mut self, key: &[u8], deadline: u64, now: u64) -> bool {
+        let key = key.to_vec();
+        self.expire_if_needed(&key, now);
+        match self.map.get_mut(&key) {
+            Some(entry) => {
+                entry.expire_at = Some(deadline);
+                self.touch(&key);
+                self.expiry.push(Reverse((deadline, key)));
+                return true;
+            },
+            None => {
+                return false;
+            }
+        }
+    }
+
+    // Returns true when a deadline was removed
+    pub fn persist(&mut self, key: &[u8], now: u64) -> bool {
+        let key = key.to_vec();
+        self.expire_if_needed(&key, now);
+        match self.map.get_mut(&key) {
+            Some(entry) => {
+                let removed = entry.expire_at.take().is_some();
+                if removed {
+                    self.touch(&key);
+                }
+                return removed;
+            },
+            None => {
+                return false;
+            }
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        return self.expiry.peek().map(|Reverse((at, _))| *at);
+    }
+
+    // Active expiration, called from the event loop. Returns the number of
+    // keys removed.
+    pub fn remove_expired(&mut self, now: u64) -> usize {
+        let mut removed = 0;
+        while let Some(Reverse((at, _))) = self.expiry.peek() {
+            if *at > now || removed >= K_MAX_EXPIRE_WORK {
+                break;
+            }
+            let Reverse((at, key)) = self.expiry.pop().unwrap();
+            let current = self.map.get_ref(&key).and_then(|e| e.expire_at);
+            if current == Some(at) {
+                self.map.remove(&key);
+                self.touch(&key);
+                removed += 1;
+            }
+        }
+        return removed;
+    }
+
+    // Starts keeping a version for the key, returns the current one. Every
+    // call needs a matching `unwatch`.
+    pub fn watch(&mut self, key: &[u8], now: u64) -> u64 {
+        self.expire_if_needed(&key.to_vec(), now);
+        let (version, watchers) = self.versions.entry(key.to_vec()).or_insert((0, 0));
+        *watchers += 1;
+        return *version;
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some((_, watchers)) = self.versions.get_mut(key) {
+            *watchers -= 1;
+            if *watchers == 0 {
+                self.versions.remove(key);
+            }
+        }
+    }
+
+    // Version of a watched key as of `now`, an expired key counts as
+    // deleted even before it is removed
+    pub fn version(&mut self, key: &[u8], now: u64) -> Option<u64> {
+        self.expire_if_needed(&key.to_vec(), now);
+        return self.versions.get(key).map(|(version, _)| *version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lazy_expiry() {
+        let mut ks = Keyspace::new();
+        ks.set_with_deadline(b"k", Value::Str(b"v".to_vec()), 100);
+        assert!(ks.get(b"k", 99).is_some());
+        assert!(ks.get(b"k", 100).is_none());
+        assert!(ks.get(b"k", 99).is_none());
+    }
+
+    #[test]
+    fn test_active_expiry_skips_stale_deadlines() {
+        let mut ks = Keyspace::new();
+        ks.set_with_deadline(b"a", Value::Str(b"1".to_vec()), 10);
+        ks.set_with_deadline(b"b", Value::Str(b"2".to_vec()), 20);
+        assert!(ks.set_deadline(b"a", 50, 0));
+        assert_eq!(ks.next_deadline(), Some(10));
+        assert_eq!(ks.remove_expired(30), 1);
+        assert!(ks.get(b"a", 30).is_some());
+        assert!(ks.get(b"b", 0).is_none());
+        assert_eq!(ks.next_deadline(), Some(50));
+    }
+
+    #[test]
+    fn test_get_or_insert_keeps_existing_value() {
+        let mut ks = Keyspace::new();
+        ks.set(b"k", Value::Str(b"v".to_vec()));
+        assert_eq!(ks.get_or_insert(b"k", 0, || Value::ZSet(ZSet::new())).value.type_name(), "string");
+        assert_eq!(ks.get_or_insert(b"z", 0, || Value::ZSet(ZSet::new())).value.type_name(), "zset");
+    }
+
+    #[test]
+    fn test_watched_versions() {
+        let mut ks = Keyspace::new();
+        ks.set(b"a", Value::Str(b"1".to_vec()));
+        let seen = ks.watch(b"a", 0);
+        assert_eq!(ks.watch(b"b", 0), 0);
+        ks.get(b"a", 0);
+        assert!(!ks.persist(b"a", 0));
+        assert_eq!(ks.version(b"a", 0), Some(seen));
+        ks.set_with_deadline(b"a", Value::Str(b"2".to_vec()), 10);
+        assert_eq!(ks.version(b"a", 0), Some(seen + 1));
+        // expiry is a change too, noticed without looking the key up
+        assert_eq!(ks.version(b"a", 10), Some(seen + 2));
+        assert_eq!(ks.delete(b"a", 10).map(|_| ()), None);
+        assert_eq!(ks.version(b"a", 10), Some(seen + 2));
+        ks.get_or_insert(b"b", 0, || Value::ZSet(ZSet::new()));
+        assert_eq!(ks.version(b"b", 0), Some(1));
+        ks.clear();
+        assert_eq!(ks.version(b"b", 0), Some(2));
+        ks.unwatch(b"a");
+        ks.unwatch(b"b");
+        assert_eq!(ks.version(b"a", 0), None);
+    }
+
+    #[test]
+    fn test_set_clears_deadline() {
+        let mut ks = Keyspace::new();
+        ks.set_with_deadline(b"k", Value::Str(b"v".to_vec()), 10);
+        ks.set(b"k", Value::Str(b"w".to_vec()));
+        assert_eq!(ks.remove_expired(20), 0);
+        assert!(matches!(&ks.get(b"k", 20).unwrap().value, Value::Str(v) if v == b"w"));
+        assert!(!ks.persist(b"k", 20));
+    }
+}
@@ -1,3 +1,21 @@
+// The codebase spells out `return` and field names on purpose
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::upper_case_acronyms,
+    clippy::needless_late_init)]
+
 pub mod oa_map;
+pub mod keyspace;
+pub mod avl;
+pub mod zset;
+pub mod list;
+pub mod hash;
+pub mod set;
+pub mod stream;
 pub mod server;
 pub mod client;
+pub mod resp;
+pub mod aof;
+pub mod snapshot;
+pub mod replication;
+pub mod cluster;
+pub mod pubsub;
+pub mod blocking;
@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+
+// Limits of one chunk. An item larger than K_CHUNK_BYTES gets a chunk of its
+// own.
+const K_CHUNK_BYTES: usize = 4096;
+const K_CHUNK_ITEMS: usize = 128;
+
+// A run of items packed back to back in one buffer, so small items do not
+// cost an allocation each. Changes at either end move at most one chunk's
+// worth of bytes.
+#[derive(Clone, Debug, Default)]
+struct Chunk {
+    data: Vec<u8>,
+    // end offset of every item in `data`
+    ends: Vec<u32>,
+}
+
+impl Chunk {
+    fn len(&self) -> usize {
+        return self.ends.len();
+    }
+
+    fn start(&self, i: usize) -> usize {
+        if i == 0 {
+            return 0;
+        }
+        return self.ends[i - 1] as usize;
+    }
+
+    fn get(&self, i: usize) -> &[u8] {
+        return &self.data[self.start(i)..self.ends[i] as usize];
+    }
+
+    fn fits(&self, item: &[u8]) -> bool {
+        return self.ends.is_empty() || (self.ends.len() < K_CHUNK_ITEMS && self.data.len() + item.len() <= K_CHUNK_BYTES);
+    }
+
+    fn push_back(&mut self, item: &[u8]) {
+        self.data.extend_from_slice(item);
+        self.ends.push(u32::try_from(self.data.len()).unwrap());
+    }
+
+    fn push_front(&mut self, item: &[u8]) {
+        self.data.splice(0..0, item.iter().copied());
+        let n = u32::try_from(item.len()).unwrap();
+        for end in self.ends.iter_mut() {
+            *end += n;
+        }
+        self.ends.insert(0, n);
+    }
+
+    fn pop_front(&mut self) -> Vec<u8> {
+        let end = self.ends.remove(0);
+        let item = self.data.drain(..end as usize).collect();
+        for e in self.ends.iter_mut() {
+            *e -= end;
+        }
+        return item;
+    }
+
+    fn pop_back(&mut self) -> Vec<u8> {
+        self.ends.pop();
+        return self.data.split_off(self.start(self.ends.len()));
+    }
+
+    // Keeps the items in [from, to)
+    fn keep(&mut self, from: usize, to: usize) {
+        let (start, end) = (self.start(from), self.start(to));
+        self.data.truncate(end);
+        self.data.drain(..start);
+        self.ends.truncate(to);
+        self.ends.drain(..from);
+        let shift = u32::try_from(start).unwrap();
+        for e in self.ends.iter_mut() {
+            *e -= shift;
+        }
+    }
+}
+
+// List of binary strings with O(1) push and pop at both ends, kept as a
+// deque of chunks. Positions are found by skipping whole chunks.
+#[derive(Clone, Debug, Default)]
+pub struct List {
+    chunks: VecDeque<Chunk>,
+    len: usize,
+}
+
+impl List {
+    pub fn new() -> List {
+        return List::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn push_front(&mut self, item: &[u8]) {
+        if !self.chunks.front().is_some_and(|chunk| chunk.fits(item)) {
+            self.chunks.push_front(Chunk::default());
+        }
+        self.chunks.front_mut().unwrap().push_front(item);
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, item: &[u8]) {
+        if !self.chunks.back().is_some_and(|chunk| chunk.fits(item)) {
+            self.chunks.push_back(Chunk::default());
+        }
+        self.chunks.back_mut().unwrap().push_back(item);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.chunks.front_mut()?;
+        let item = chunk.pop_front();
+        if chunk.len() == 0 {
+            self.chunks.pop_front();
+        }
+        self.len -= 1;
+        return Some(item);
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.chunks.back_mut()?;
+        let item = chunk.pop_back();
+        if chunk.len() == 0 {
+            self.chunks.pop_back();
+        }
+        self.len -= 1;
+        return Some(item);
+    }
+
+    // Chunk holding the item at `index` and the item's position in it
+    fn locate(&self, mut index: usize) -> Option<(usize, usize)> {
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if index < chunk.len() {
+                return Some((i, index));
+            }
+            index -= chunk.len();
+        }
+        return None;
+    }
+
+    // Negative indexes count from the end
+    pub fn get(&self, index: i64) -> Option<&[u8]> {
+        let len = i64::try_from(self.len).unwrap();
+        let index = if index < 0 { len + index } else { index };
+        if index < 0 {
+            return None;
+        }
+        let (chunk, pos) = self.locate(usize::try_from(index).unwrap())?;
+        return Some(self.chunks[chunk].get(pos));
+    }
+
+    // Clamps an inclusive range with negative positions counting from the
+    // end into [first, last), empty when first >= last
+    fn bounds(&self, start: i64, stop: i64) -> (usize, usize) {
+        let len = i64::try_from(self.len).unwrap();
+        let start = if start < 0 { (len + start).max(0) } else { start.min(len) };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if stop < start {
+            return (0, 0);
+        }
+        return (usize::try_from(start).unwrap(), usize::try_from(stop + 1).unwrap());
+    }
+
+    // Items with positions in [start, stop]; negative positions count from
+    // the end
+    pub fn range(&self, start: i64, stop: i64) -> Vec<&[u8]> {
+        let (first, last) = self.bounds(start, stop);
+        let mut out = Vec::with_capacity(last - first);
+        if first == last {
+            return out;
+        }
+        let (mut chunk, mut pos) = self.locate(first).unwrap();
+        while out.len() < last - first {
+            if pos == self.chunks[chunk].len() {
+                chunk += 1;
+                pos = 0;
+                continue;
+            }
+            out.push(self.chunks[chunk].get(pos));
+            pos += 1;
+        }
+        return out;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        return self.chunks.iter().flat_map(|chunk| (0..chunk.len()).map(move |i| chunk.get(i)));
+    }
+
+    // Keeps only the items with positions in [start, stop]
+    pub fn trim(&mut self, start: i64, stop: i64) {
+        let (mut first, mut last) = self.bounds(start, stop);
+        if first == last {
+            self.chunks.clear();
+            self.len = 0;
+            return;
+        }
+        let kept = last - first;
+        // whole chunks outside the range go first
+        while self.chunks.front().is_some_and(|chunk| chunk.len() <= first) {
+            let chunk = self.chunks.pop_front().unwrap();
+            first -= chunk.len();
+            last -= chunk.len();
+        }
+        let mut end = 0;
+        let mut n = 0;
+        while end < last {
+            end += self.chunks[n].len();
+            n += 1;
+        }
+        self.chunks.truncate(n);
+        let back = self.chunks.back_mut().unwrap();
+        let back_len = back.len();
+        back.keep(0, back_len - (end - last));
+        let front = self.chunks.front_mut().unwrap();
+        let front_len = front.len();
+        front.keep(first, front_len);
+        self.len = kept;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(list: &List) -> Vec<String> {
+        return list.iter().map(|i| String::from_utf8(i.to_vec()).unwrap()).collect();
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut list = List::new();
+        list.push_back(b"b");
+        list.push_front(b"a");
+        list.push_back(b"c");
+        assert_eq!(items(&list), vec!["a", "b", "c"]);
+        assert_eq!(list.pop_front(), Some(b"a".to_vec()));
+        assert_eq!(list.pop_back(), Some(b"c".to_vec()));
+        assert_eq!(list.pop_back(), Some(b"b".to_vec()));
+        assert_eq!(list.pop_back(), None);
+        assert!(list.is_empty());
+
+        // across many chunks, with items larger than a chunk
+        let big = vec![7u8; K_CHUNK_BYTES * 2];
+        for i in 0..1000 {
+            list.push_back(i.to_string().as_bytes());
+            list.push_front(format!("-{}", i).as_bytes());
+        }
+        list.push_back(&big);
+        assert_eq!(list.len(), 2001);
+        assert!(list.chunks.len() > 2000 / K_CHUNK_ITEMS);
+        assert_eq!(list.get(0), Some(b"-999".as_slice()));
+        assert_eq!(list.get(1999), Some(b"999".as_slice()));
+        assert_eq!(list.get(-1), Some(big.as_slice()));
+        assert_eq!(list.get(2001), None);
+        assert_eq!(list.get(-2002), None);
+        assert_eq!(list.pop_back(), Some(big));
+        for i in 0..1000 {
+            assert_eq!(list.pop_front(), Some(format!("-{}", 999 - i).into_bytes()));
+        }
+        for i in 0..1000 {
+            assert_eq!(list.pop_front(), Some(i.to_string().into_bytes()));
+        }
+        assert_eq!(list.len(), 0);
+        assert!(list.chunks.is_empty());
+    }
+
+    #[test]
+    fn test_range_and_trim() {
+        let mut list = List::new();
+        for i in 0..500 {
+            list.push_back(i.to_string().as_bytes());
+        }
+        let range = |list: &List, start, stop| list.range(start, stop).iter().map(|i| String::from_utf8(i.to_vec()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(range(&list, 0, 2), vec!["0", "1", "2"]);
+        assert_eq!(range(&list, 126, 129), vec!["126", "127", "128", "129"]);
+        assert_eq!(range(&list, -2, -1), vec!["498", "499"]);
+        assert_eq!(range(&list, 498, 1000), vec!["498", "499"]);
+        assert!(list.range(5, 4).is_empty());
+        assert!(list.range(600, 700).is_empty());
+        assert_eq!(list.range(0, -1).len(), 500);
+
+        list.trim(130, -101);
+        assert_eq!(list.len(), 270);
+        assert_eq!(list.get(0), Some(b"130".as_slice()));
+        assert_eq!(list.get(-1), Some(b"399".as_slice()));
+        assert_eq!(list.range(0, -1).len(), 270);
+        assert_eq!(items(&list), (130..400).map(|i| i.to_string()).collect::<Vec<_>>());
+        list.trim(1, 1);
+        assert_eq!(items(&list), vec!["131"]);
+        list.push_front(b"x");
+        assert_eq!(items(&list), vec!["x", "131"]);
+        list.trim(3, 10);
+        assert!(list.is_empty());
+        assert!(list.chunks.is_empty());
+    }
+}
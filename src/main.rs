@@ -1,19 +1,64 @@
-use ferdis::server::run_server;
-use ferdis::client::send_message;
+use ferdis::server::{run_server_with, ServerConfig};
+use ferdis::client::{FerdisClient, DEFAULT_ADDR};
 use std::env;
 
 fn main() {
     let mut args: Vec<String> = env::args().collect();
     args.remove(0);
-    if args.len() == 0 {
-        run_server();
+    if args.is_empty() || args[0] == "server" {
+        let mut config = ServerConfig::default();
+        let mut i = 1;
+        while i < args.len() {
+            match (args[i].as_str(), args.get(i + 1)) {
+                ("--bind", Some(addr)) => {
+                    config.addr = addr.clone();
+                },
+                ("--resp-bind", Some(addr)) => {
+                    config.resp_addr = Some(addr.clone());
+                },
+                ("--max-msg", Some(size)) => {
+                    config.max_msg = size.parse().expect("--max-msg takes a size in bytes");
+                },
+                ("--appendonly", Some(path)) => {
+                    config.aof_path = Some(path.clone());
+                },
+                ("--dbfilename", Some(path)) => {
+                    config.snapshot_path = Some(path.clone());
+                },
+                ("--replicaof", Some(addr)) => {
+                    config.replicaof = Some(addr.clone());
+                },
+                ("--cluster-enabled", Some(flag)) => {
+                    config.cluster_enabled = flag == "yes";
+                },
+                ("--appendfsync", Some(policy)) => {
+                    config.fsync = policy.parse().expect("--appendfsync takes always, everysec or no");
+                },
+                _ => {
+                    panic!("Wrong arguments");
+                }
+            }
+            i += 2;
+        }
+        run_server_with(config);
         return;
     }
     if args[0] == "client" {
         args.remove(0);
-        let req = args.join(" ");
-        let response = send_message(req);
-        println!("{:?}", response);
+        let mut addr = String::from(DEFAULT_ADDR);
+        if args.len() >= 2 && args[0] == "--addr" {
+            addr = args[1].clone();
+            args.drain(..2);
+        }
+        let response = FerdisClient::connect(&addr).and_then(|mut client| client.command(&args));
+        match response {
+            Ok(reply) => {
+                println!("{}", reply);
+            },
+            Err(e) => {
+                println!("Error {}", e);
+            }
+        }
     } else {
         panic!("Wrong arguments");
     }
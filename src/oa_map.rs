@@ -1,5 +1,3 @@
-use std::cell::RefCell;
-
 pub trait Hashable {
     fn hash(&self) -> u32;
 }
@@ -9,60 +7,64 @@ pub struct Entry<K: Hashable, V> {
     key: Option<K>,
     value: Option<V>,
     empty: bool,
+    // a removed entry, probing goes on past it
+    deleted: bool,
     int_key: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct OAMap<K: Hashable, V> {
-    arr: Vec<RefCell<Entry<K, V>>>,
+    arr: Vec<Entry<K, V>>,
     entry_count: usize,
+    tombstones: usize,
     capacity: usize,
     cap_ratio: usize
 }
 
+// FNV-1a, a plain byte sum puts anagrams and similar keys in one cluster
+fn hash_bytes(bytes: &[u8]) -> u32 {
+    let mut h: u32 = 0x811c9dc5;
+    for b in bytes {
+        h ^= u32::from(*b);
+        h = h.wrapping_mul(0x01000193);
+    }
+    return h;
+}
+
 impl Hashable for String {
     fn hash(&self) -> u32 {
-        let mut h: u32 = 0;
-        for b in self.bytes() {
-            h += u32::from(b);
-        }
-        return h;
+        return hash_bytes(self.as_bytes());
+    }
+}
+
+impl Hashable for Vec<u8> {
+    fn hash(&self) -> u32 {
+        return hash_bytes(self);
     }
 }
 
 impl<K: Hashable, V> Entry<K, V> {
 
     fn new() -> Entry<K, V> {
-        Entry {key: None, value: None, int_key: 0, empty: true}
+        Entry {key: None, value: None, int_key: 0, empty: true, deleted: false}
     }
 
     fn populate(&mut self, key: K, value: V) {
-        if let None = self.key {
-            self.key = Some(key);
-        }
+        self.int_key = key.hash();
+        self.key = Some(key);
         self.value = Some(value);
-        if let Some(k) = &self.key {
-            self.int_key = k.hash();
-        }
-
         self.empty = false;
+        self.deleted = false;
     }
 }
 
-impl<K: Hashable + Clone, V: Clone> OAMap<K, V> where K: PartialEq{
-    pub fn new() -> OAMap<K, V> {
-        let e = std::iter::repeat_with(|| RefCell::new(Entry::new()))
-            .take(1000)
-            .collect::<Vec<_>>();
-        return OAMap { arr: e, entry_count: 0, capacity: 1000, cap_ratio: 0 };
-    }
-
-
+impl<K: Hashable + PartialEq, V> OAMap<K, V> {
     pub fn new_with_capacity(capacity: usize) -> OAMap<K, V> {
-        let e = std::iter::repeat_with(|| RefCell::new(Entry::new()))
+        let capacity = capacity.max(1);
+        let e = std::iter::repeat_with(Entry::new)
             .take(capacity)
             .collect::<Vec<_>>();
-        return OAMap { arr: e, entry_count: 0, capacity: capacity, cap_ratio: 0 };
+        return OAMap { arr: e, entry_count: 0, tombstones: 0, capacity, cap_ratio: 0 };
     }
 
     pub fn find_address(&self, key: &K, arr_len: usize) -> usize {
@@ -71,111 +73,161 @@ impl<K: Hashable + Clone, V: Clone> OAMap<K, V> where K: PartialEq{
         return address.try_into().unwrap();
     }
 
-    pub fn get(&self, key: K) -> Option<V> {
-        let mut address = self.find_address(&key, self.arr.len());
-        let mut entry = self.arr.get(address).unwrap().borrow();
-        while address < self.arr.len() {
-            if let Some(k) = &entry.key {
-                if *k == key && !entry.empty {
-                    break;
+    // Probe from the home address, wrapping around. A slot that was never
+    // used ends the chain; deleted slots are tombstones that do not.
+    fn find_slot(&self, key: &K) -> Option<usize> {
+        let len = self.arr.len();
+        let mut address = self.find_address(key, len);
+        for _ in 0..len {
+            let entry = &self.arr[address];
+            match &entry.key {
+                Some(k) => {
+                    if !entry.empty && k == key {
+                        return Some(address);
+                    }
+                },
+                None => {
+                    if !entry.deleted {
+                        return None;
+                    }
                 }
             }
-            address += 1;
-            if let Some(e) = self.arr.get(address) {
-                entry = e.borrow();
-            }
-        }
-        if let Some(k) = &entry.key {
-            if *k == key && !entry.empty {
-                return Some(entry.value.clone()?);
-            }
+            address = (address + 1) % len;
         }
         return None;
     }
 
+    pub fn get_ref(&self, key: &K) -> Option<&V> {
+        let address = self.find_slot(key)?;
+        return self.arr[address].value.as_ref();
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let address = self.find_slot(key)?;
+        return self.arr[address].value.as_mut();
+    }
+
     pub fn contains_key(&self, key: K) -> bool {
-        return self.get(key).is_some();
+        return self.find_slot(&key).is_some();
     }
 
     pub fn put(&mut self, key: K, value: V) {
+        if let Some(address) = self.find_slot(&key) {
+            self.arr[address].value = Some(value);
+            return;
+        }
         if self.cap_ratio > 50 {
-            self.resize();
+            self.resize(self.capacity * 2);
+        } else if 100 * (self.entry_count + self.tombstones) / self.arr.len() > 50 {
+            // mostly tombstones, which make every miss probe a long way
+            self.resize(self.capacity);
         }
-        let mut address = self.find_address(&key, self.arr.len());
-        let mut entry = self.arr.get(address).unwrap().borrow_mut();
-        while address < self.arr.len() {
-            if entry.empty {
-                entry.populate(key, value);
+        let len = self.arr.len();
+        let mut address = self.find_address(&key, len);
+        for _ in 0..len {
+            if self.arr[address].empty {
+                if self.arr[address].deleted {
+                    self.tombstones -= 1;
+                }
+                self.arr[address].populate(key, value);
                 self.entry_count += 1;
-                self.cap_ratio = 100 * self.entry_count / self.arr.len();
+                self.cap_ratio = 100 * self.entry_count / len;
                 return;
             }
-            if let Some(k) = &entry.key {
-                if *k == key {
-                    entry.populate(key, value);
-                    return;
-                }
-            }
-            address += 1;
-            entry = self.arr.get(address).unwrap().borrow_mut();
+            address = (address + 1) % len;
         }
-        // TODO: There should be some resize logic here as well
+        // only reachable when every slot is taken, which the ratio check prevents
+        unreachable!("OAMap is full");
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let address = self.find_slot(key)?;
+        self.arr[address].key = None;
+        self.arr[address].empty = true;
+        self.arr[address].deleted = true;
+        self.entry_count -= 1;
+        self.tombstones += 1;
+        self.cap_ratio = 100 * self.entry_count / self.arr.len();
+        return self.arr[address].value.take();
     }
 
     pub fn delete(&mut self, key: K) {
-        let mut address = self.find_address(&key, self.arr.len());
-        let mut entry = self.arr.get(address).unwrap().borrow_mut();
-        let okey = Some(key);
-        while entry.key != okey && address < self.arr.len() {
-            address += 1;
-            entry = self.arr.get(address).unwrap().borrow_mut();
+        self.remove(&key);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entry_count;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entry_count == 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.arr.iter().filter(|e| !e.empty).filter_map(|e| {
+            match (&e.key, &e.value) {
+                (Some(k), Some(v)) => Some((k, v)),
+                _ => None,
+            }
+        })
+    }
+
+    // Some key, found by scanning from slot `seed` on. Keys after long runs
+    // of free slots come up more often, which is good enough for sampling.
+    pub fn random_key(&self, seed: u64) -> Option<&K> {
+        if self.entry_count == 0 {
+            return None;
         }
-        if entry.key == okey {
-            entry.empty = true;
-            self.entry_count -= 1;
-            self.cap_ratio = 100 * self.entry_count / self.arr.len();
+        let len = self.arr.len();
+        let start = usize::try_from(seed % u64::try_from(len).unwrap()).unwrap();
+        for i in 0..len {
+            let entry = &self.arr[(start + i) % len];
+            if !entry.empty {
+                return entry.key.as_ref();
+            }
         }
+        return None;
     }
 
-    pub fn keys(&self) -> Vec<K> {
-        let mut out: Vec<K> = Vec::new();
-        for entry in self.arr.iter() {
-            if !entry.borrow().empty {
-                match &entry.borrow().key {
-                    Some(k) => {
-                        out.push(k.clone());
-                    },
-                    None => {
-                        // shouldn't happen
-                    }
-                }
+    // Rebuilds the table with `capacity` slots, leaving the tombstones behind
+    fn resize(&mut self, capacity: usize) {
+        let mut map: OAMap<K, V> = OAMap::new_with_capacity(capacity);
+        for entry in self.arr.drain(..) {
+            if entry.empty {
+                continue;
             }
-        }
-        return out;
-    }
-
-    fn resize(&mut self) {
-        let mut map: OAMap<K, V> = OAMap::new_with_capacity(self.capacity * 2);
-        for entry in self.arr.iter() {
-            if !entry.borrow().empty {
-                match &entry.borrow().key {
-                    Some(k) => {
-                        let val = self.get(k.clone());
-                        map.put(k.clone(), val.unwrap());
-                    },
-                    None => {
-                        // shouldn't happen
-                    }
-                }
+            if let (Some(k), Some(v)) = (entry.key, entry.value) {
+                map.put(k, v);
             }
         }
         self.arr = map.arr;
         self.entry_count = map.entry_count;
+        self.tombstones = 0;
         self.capacity = map.capacity;
         self.cap_ratio = map.cap_ratio;
     }
 }
 
+impl<K: Hashable + PartialEq, V> Default for OAMap<K, V> {
+    fn default() -> OAMap<K, V> {
+        return OAMap::new_with_capacity(1000);
+    }
+}
+
+impl<K: Hashable + Clone + PartialEq, V: Clone> OAMap<K, V> {
+    pub fn new() -> OAMap<K, V> {
+        return OAMap::default();
+    }
+
+    pub fn get(&self, key: K) -> Option<V> {
+        return self.get_ref(&key).cloned();
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        return self.iter().map(|(k, _)| k.clone()).collect();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -230,4 +282,43 @@ mod tests {
             assert_eq!(out.unwrap(), e.1);
         }
     }
+
+    #[test]
+    fn test_reuse_deleted_slot() {
+        let mut map: OAMap<String, String> = OAMap::new_with_capacity(4);
+        map.put("a".to_string(), "1".to_string());
+        map.delete("a".to_string());
+        map.put("b".to_string(), "2".to_string());
+        map.put("a".to_string(), "3".to_string());
+        assert_eq!(map.get("a".to_string()).unwrap(), "3");
+        assert_eq!(map.get("b".to_string()).unwrap(), "2");
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_churn_drops_tombstones() {
+        let mut map: OAMap<String, String> = OAMap::new_with_capacity(16);
+        map.put("kept".to_string(), "0".to_string());
+        for i in 0..1000 {
+            map.put(format!("key{}", i), i.to_string());
+            map.delete(format!("key{}", i));
+        }
+        assert_eq!(map.capacity, 16);
+        assert!(map.tombstones <= 8);
+        assert_eq!(map.arr.iter().filter(|e| e.key.is_some()).count(), 1);
+        assert_eq!(map.get("kept".to_string()).unwrap(), "0");
+        assert!(map.get("key999".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_probe_wraps_around() {
+        let mut map: OAMap<String, String> = OAMap::new_with_capacity(16);
+        for i in 0..8 {
+            map.put(format!("key{}", i), i.to_string());
+        }
+        for i in 0..8 {
+            assert_eq!(map.get(format!("key{}", i)).unwrap(), i.to_string());
+        }
+        assert_eq!(map.keys().len(), 8);
+    }
 }
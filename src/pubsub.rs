@@ -0,0 +1,228 @@
+// Channel and pattern subscriptions of connections. Messages themselves go
+// through the event loop, this only knows who listens to what.
+use std::collections::{BTreeSet, HashMap};
+use std::os::fd::RawFd;
+
+// Glob-style matching like Redis: `*` any run of bytes, `?` one byte,
+// `[abc]`, `[^a-z]` classes and `\` to escape the next byte
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*` when the rest does not match
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            },
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == text[t] { Some(p + 2) } else { None }
+            },
+            Some(c) => {
+                if *c == text[t] { Some(p + 1) } else { None }
+            },
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            },
+            (None, Some((after_star, from))) => {
+                // let the star take one more byte
+                p = after_star;
+                t = from + 1;
+                star = Some((after_star, from + 1));
+            },
+            (None, None) => {
+                return false;
+            }
+        }
+    }
+    return pattern[p..].iter().all(|c| *c == b'*');
+}
+
+// Matches `c` against the class starting at pattern[open] == '[', returns
+// the position after the class on a match
+fn match_class(pattern: &[u8], open: usize, c: u8) -> Option<usize> {
+    let mut i = open + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    // an unterminated class ends the pattern
+    let next = (i + 1).min(pattern.len());
+    if matched != negate {
+        return Some(next);
+    }
+    return None;
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<Vec<u8>, Vec<RawFd>>,
+    patterns: HashMap<Vec<u8>, Vec<RawFd>>,
+    by_conn: HashMap<RawFd, Subscriptions>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        return PubSub::default();
+    }
+
+    // Channels plus patterns of a connection, it is in push mode while this
+    // is not zero
+    pub fn count(&self, fd: RawFd) -> usize {
+        return self.by_conn.get(&fd).map_or(0, |subs| subs.channels.len() + subs.patterns.len());
+    }
+
+    pub fn subscribe(&mut self, fd: RawFd, channel: &[u8]) {
+        if self.by_conn.entry(fd).or_default().channels.insert(channel.to_vec()) {
+            self.channels.entry(channel.to_vec()).or_default().push(fd);
+        }
+    }
+
+    pub fn psubscribe(&mut self, fd: RawFd, pattern: &[u8]) {
+        if self.by_conn.entry(fd).or_default().patterns.insert(pattern.to_vec()) {
+            self.patterns.entry(pattern.to_vec()).or_default().push(fd);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, fd: RawFd, channel: &[u8]) {
+        if let Some(subs) = self.by_conn.get_mut(&fd) {
+            if subs.channels.remove(channel) {
+                remove_fd(&mut self.channels, channel, fd);
+            }
+        }
+        self.forget_idle(fd);
+    }
+
+    pub fn punsubscribe(&mut self, fd: RawFd, pattern: &[u8]) {
+        if let Some(subs) = self.by_conn.get_mut(&fd) {
+            if subs.patterns.remove(pattern) {
+                remove_fd(&mut self.patterns, pattern, fd);
+            }
+        }
+        self.forget_idle(fd);
+    }
+
+    pub fn channels_of(&self, fd: RawFd) -> Vec<Vec<u8>> {
+        return self.by_conn.get(&fd).map_or(Vec::new(), |subs| subs.channels.iter().cloned().collect());
+    }
+
+    pub fn patterns_of(&self, fd: RawFd) -> Vec<Vec<u8>> {
+        return self.by_conn.get(&fd).map_or(Vec::new(), |subs| subs.patterns.iter().cloned().collect());
+    }
+
+    // Drops every subscription of a closed connection
+    pub fn remove_conn(&mut self, fd: RawFd) {
+        for channel in self.channels_of(fd) {
+            self.unsubscribe(fd, &channel);
+        }
+        for pattern in self.patterns_of(fd) {
+            self.punsubscribe(fd, &pattern);
+        }
+    }
+
+    // Connections subscribed to the channel
+    pub fn subscribers(&self, channel: &[u8]) -> Vec<RawFd> {
+        return self.channels.get(channel).cloned().unwrap_or_default();
+    }
+
+    // (pattern, connection) for every pattern subscription matching the channel
+    pub fn pattern_subscribers(&self, channel: &[u8]) -> Vec<(Vec<u8>, RawFd)> {
+        let mut out = Vec::new();
+        for (pattern, fds) in self.patterns.iter() {
+            if glob_match(pattern, channel) {
+                out.extend(fds.iter().map(|fd| (pattern.clone(), *fd)));
+            }
+        }
+        return out;
+    }
+
+    // Channels with at least one subscriber
+    pub fn active_channels(&self) -> Vec<Vec<u8>> {
+        return self.channels.keys().cloned().collect();
+    }
+
+    fn forget_idle(&mut self, fd: RawFd) {
+        if self.count(fd) == 0 {
+            self.by_conn.remove(&fd);
+        }
+    }
+}
+
+fn remove_fd(map: &mut HashMap<Vec<u8>, Vec<RawFd>>, name: &[u8], fd: RawFd) {
+    if let Some(fds) = map.get_mut(name) {
+        fds.retain(|f| *f != fd);
+        if fds.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"news.*", b"news.tech"));
+        assert!(glob_match(b"news.*", b"news."));
+        assert!(!glob_match(b"news.*", b"new"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        assert!(glob_match(b"\\*x", b"*x"));
+        assert!(!glob_match(b"\\*x", b"ax"));
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let mut pubsub = PubSub::new();
+        pubsub.subscribe(5, b"a");
+        pubsub.subscribe(5, b"a");
+        pubsub.subscribe(6, b"a");
+        pubsub.psubscribe(5, b"a*");
+        assert_eq!(pubsub.count(5), 2);
+        assert_eq!(pubsub.subscribers(b"a"), vec![5, 6]);
+        assert_eq!(pubsub.pattern_subscribers(b"ab"), vec![(b"a*".to_vec(), 5)]);
+        pubsub.unsubscribe(6, b"a");
+        assert_eq!(pubsub.count(6), 0);
+        pubsub.remove_conn(5);
+        assert_eq!(pubsub.count(5), 0);
+        assert!(pubsub.subscribers(b"a").is_empty());
+        assert!(pubsub.pattern_subscribers(b"ab").is_empty());
+        assert!(pubsub.active_channels().is_empty());
+    }
+}
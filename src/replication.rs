@@ -0,0 +1,178 @@
+// Leader/follower replication. A follower connects to the leader's ferdis
+// port like any client and sends `psync <replid> <offset>`. The leader
+// answers with one of
+//
+//   "FULLRESYNC <replid> <offset>" followed by a snapshot (see `snapshot`)
+//   "CONTINUE <replid>"            followed by the part of the backlog the
+//                                  follower has not seen yet
+//
+// and from then on sends every write command it executes, framed like a
+// request. The replication offset counts the bytes of that stream, so two
+// servers with the same replid and offset hold the same data.
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use crate::keyspace::now_ms;
+
+// Stream bytes kept for followers that reconnect
+pub const K_BACKLOG_SIZE: usize = 1 << 20;
+// Wait between attempts to reach the leader
+pub const K_RECONNECT_MS: u64 = 1000;
+
+// The last bytes of the replication stream, addressed by stream offset
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    // offset of the byte after the last one written
+    end: u64,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize, end: u64) -> Backlog {
+        return Backlog { buf: VecDeque::new(), capacity: capacity, end: end };
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        self.buf.extend(data.iter());
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.end += u64::try_from(data.len()).unwrap();
+    }
+
+    // Replication offset, the number of stream bytes produced so far
+    pub fn offset(&self) -> u64 {
+        return self.end;
+    }
+
+    // Everything after `offset`, None when part of it is no longer kept
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let start = self.end - u64::try_from(self.buf.len()).unwrap();
+        if offset < start || offset > self.end {
+            return None;
+        }
+        let skip = usize::try_from(offset - start).unwrap();
+        return Some(self.buf.iter().skip(skip).copied().collect());
+    }
+
+    // Drops the kept bytes and continues the stream at `end`
+    pub fn reset(&mut self, end: u64) {
+        self.buf.clear();
+        self.end = end;
+    }
+}
+
+// 40 random hex digits naming a history of the dataset
+pub fn new_replid() -> String {
+    let mut id = String::new();
+    let state = RandomState::new();
+    let mut i: u64 = 0;
+    while id.len() < 40 {
+        let mut hasher = state.build_hasher();
+        hasher.write_u64(now_ms());
+        hasher.write_u64(i);
+        id.push_str(&format!("{:016x}", hasher.finish()));
+        i += 1;
+    }
+    id.truncate(40);
+    return id;
+}
+
+// Progress of a follower's link to its leader
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkState {
+    // no connection, the next attempt is due at `retry_at`
+    Connect,
+    // psync sent, waiting for the answer
+    Handshake,
+    // full resync accepted, waiting for the snapshot
+    Snapshot,
+    // receiving the stream
+    Connected,
+}
+
+pub(crate) struct Replication {
+    pub(crate) replid: String,
+    pub(crate) backlog: Backlog,
+    // set on followers
+    pub(crate) leader: Option<SocketAddr>,
+    // connection to the leader
+    pub(crate) link: Option<RawFd>,
+    pub(crate) link_state: LinkState,
+    pub(crate) retry_at: u64,
+    // replid and offset announced by FULLRESYNC, applied with the snapshot
+    pub(crate) pending: Option<(String, u64)>,
+    // writes of a transaction from the leader, applied when its exec arrives
+    pub(crate) queued: Option<Vec<Vec<Vec<u8>>>>,
+    // connections of followers that get the stream
+    pub(crate) replicas: Vec<RawFd>,
+    pub(crate) full_syncs: u64,
+    pub(crate) partial_syncs: u64,
+}
+
+impl Replication {
+    pub(crate) fn new() -> Replication {
+        return Replication {
+            replid: new_replid(),
+            backlog: Backlog::new(K_BACKLOG_SIZE, 0),
+            leader: None,
+            link: None,
+            link_state: LinkState::Connect,
+            retry_at: 0,
+            pending: None,
+            queued: None,
+            replicas: Vec::new(),
+            full_syncs: 0,
+            partial_syncs: 0,
+        };
+    }
+
+    // When the event loop has to wake up to reconnect to the leader
+    pub(crate) fn next_connect(&self) -> Option<u64> {
+        if self.leader.is_some() && self.link.is_none() {
+            return Some(self.retry_at);
+        }
+        return None;
+    }
+
+    // The link went down, try again after a pause
+    pub(crate) fn link_lost(&mut self, now: u64) {
+        self.link = None;
+        self.link_state = LinkState::Connect;
+        self.pending = None;
+        self.queued = None;
+        self.retry_at = now + K_RECONNECT_MS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.since(100), Some(Vec::new()));
+        assert_eq!(backlog.since(99), None);
+        backlog.append(b"abcdef");
+        assert_eq!(backlog.offset(), 106);
+        assert_eq!(backlog.since(102), Some(b"cdef".to_vec()));
+        backlog.append(b"ghijk");
+        // only the last 8 bytes are kept
+        assert_eq!(backlog.since(102), None);
+        assert_eq!(backlog.since(103), Some(b"defghijk".to_vec()));
+        assert_eq!(backlog.since(112), None);
+        backlog.reset(500);
+        assert_eq!(backlog.offset(), 500);
+        assert_eq!(backlog.since(103), None);
+    }
+
+    #[test]
+    fn test_replid() {
+        let id = new_replid();
+        assert_eq!(id.len(), 40);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(id, new_replid());
+    }
+}
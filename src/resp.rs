@@ -0,0 +1,283 @@
+// RESP front-end: requests from redis-cli and Redis client libraries are
+// turned into the same argument lists as the length-prefixed protocol, and
+// replies are transcoded from the ferdis serialization (see `server::out_*`).
+use crate::server::ResType;
+
+// Longest inline command line
+const K_MAX_INLINE: usize = 64 << 10;
+
+#[derive(Debug, PartialEq)]
+pub enum RespError {
+    Protocol(&'static str),
+}
+
+// Arguments of a parsed request and the number of bytes it used up
+pub type Parsed = (Vec<Vec<u8>>, usize);
+
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while i + 1 < buf.len() {
+        if buf[i] == b'\r' && buf[i + 1] == b'\n' {
+            return Some(i);
+        }
+        i += 1;
+    }
+    return None;
+}
+
+fn parse_len(line: &[u8]) -> Option<i64> {
+    return std::str::from_utf8(line).ok()?.parse::<i64>().ok();
+}
+
+// Parses one request from the front of `buf`. Returns the arguments and the
+// number of bytes consumed, or None when the request is not complete yet.
+pub fn parse_request(buf: &[u8], max_msg: usize) -> Result<Option<Parsed>, RespError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        return parse_inline(buf);
+    }
+    let end = match find_crlf(buf, 1) {
+        Some(end) => end,
+        None => {
+            return Ok(None);
+        }
+    };
+    let nstr = match parse_len(&buf[1..end]) {
+        Some(n) if n >= 0 && usize::try_from(n).unwrap() <= max_msg => usize::try_from(n).unwrap(),
+        _ => {
+            return Err(RespError::Protocol("invalid multibulk length"));
+        }
+    };
+    let mut pos = end + 2;
+    let mut args: Vec<Vec<u8>> = Vec::with_capacity(nstr.min(1024));
+    for _ in 0..nstr {
+        if pos >= buf.len() {
+            return Ok(None);
+        }
+        if buf[pos] != b'$' {
+            return Err(RespError::Protocol("expected '$'"));
+        }
+        let end = match find_crlf(buf, pos + 1) {
+            Some(end) => end,
+            None => {
+                return Ok(None);
+            }
+        };
+        let len = match parse_len(&buf[pos + 1..end]) {
+            Some(n) if n >= 0 && usize::try_from(n).unwrap() <= max_msg => usize::try_from(n).unwrap(),
+            _ => {
+                return Err(RespError::Protocol("invalid bulk length"));
+            }
+        };
+        let start = end + 2;
+        if buf.len() < start + len + 2 {
+            return Ok(None);
+        }
+        if &buf[start + len..start + len + 2] != b"\r\n" {
+            return Err(RespError::Protocol("expected CRLF"));
+        }
+        args.push(buf[start..start + len].to_vec());
+        pos = start + len + 2;
+    }
+    return Ok(Some((args, pos)));
+}
+
+// Inline commands as typed into telnet: arguments separated by spaces
+fn parse_inline(buf: &[u8]) -> Result<Option<Parsed>, RespError> {
+    let end = match buf.iter().position(|b| *b == b'\n') {
+        Some(end) => end,
+        None => {
+            if buf.len() > K_MAX_INLINE {
+                return Err(RespError::Protocol("too big inline request"));
+            }
+            return Ok(None);
+        }
+    };
+    let line = if end > 0 && buf[end - 1] == b'\r' { &buf[..end - 1] } else { &buf[..end] };
+    let args = line.split(|b| *b == b' ' || *b == b'\t')
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_vec())
+        .collect();
+    return Ok(Some((args, end + 1)));
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    return u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+}
+
+// Errors keep an upper case code such as WRONGTYPE, other messages get the
+// generic ERR prefix
+fn error_line(message: &[u8]) -> Vec<u8> {
+    let first = message.split(|b| *b == b' ').next().unwrap_or(b"");
+    let has_code = !first.is_empty() && first.iter().all(|b| b.is_ascii_uppercase());
+    let mut out = Vec::new();
+    out.push(b'-');
+    if !has_code {
+        out.extend_from_slice(b"ERR ");
+    }
+    // a line break would end the error early
+    out.extend(message.iter().map(|b| if *b == b'\r' || *b == b'\n' { b' ' } else { *b }));
+    out.extend_from_slice(b"\r\n");
+    return out;
+}
+
+// Transcodes one serialized reply, returns the RESP bytes and the number of
+// input bytes consumed
+fn encode_value(reply: &[u8], resp3: bool, out: &mut Vec<u8>) -> usize {
+    let res_type = ResType::from_u32(read_u32(reply, 0));
+    match res_type {
+        ResType::NIL => {
+            out.extend_from_slice(if resp3 { b"_\r\n" } else { b"$-1\r\n" });
+            return 4;
+        },
+        ResType::ERR => {
+            let len = usize::try_from(read_u32(reply, 8)).unwrap();
+            out.extend_from_slice(&error_line(&reply[12..12 + len]));
+            return 12 + len;
+        },
+        ResType::STR => {
+            let len = usize::try_from(read_u32(reply, 4)).unwrap();
+            out.extend_from_slice(format!("${}\r\n", len).as_bytes());
+            out.extend_from_slice(&reply[8..8 + len]);
+            out.extend_from_slice(b"\r\n");
+            return 8 + len;
+        },
+        ResType::ARR => {
+            let n = read_u32(reply, 4);
+            out.extend_from_slice(format!("*{}\r\n", n).as_bytes());
+            let mut pos = 8;
+            for _ in 0..n {
+                pos += encode_value(&reply[pos..], resp3, out);
+            }
+            return pos;
+        },
+        ResType::INT => {
+            let val = i64::from_le_bytes(reply[4..12].try_into().unwrap());
+            out.extend_from_slice(format!(":{}\r\n", val).as_bytes());
+            return 12;
+        },
+        ResType::DBL => {
+            // RESP2 has no double type, clients get the number as a bulk string
+            let val = format_double(f64::from_le_bytes(reply[4..12].try_into().unwrap()));
+            if resp3 {
+                out.extend_from_slice(format!(",{}\r\n", val).as_bytes());
+            } else {
+                out.extend_from_slice(format!("${}\r\n{}\r\n", val.len(), val).as_bytes());
+            }
+            return 12;
+        },
+    }
+}
+
+// Shortest text that reads back as the same double, "inf" and "-inf" for
+// the infinities like Redis
+fn format_double(val: f64) -> String {
+    return val.to_string();
+}
+
+pub fn encode_reply(reply: &[u8], resp3: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(reply.len() + 16);
+    encode_value(reply, resp3, &mut out);
+    return out;
+}
+
+// A value sent without a request, a RESP3 push. Pushes are always arrays.
+pub fn encode_push(reply: &[u8]) -> Vec<u8> {
+    let mut out = encode_reply(reply, true);
+    if out.first() == Some(&b'*') {
+        out[0] = b'>';
+    }
+    return out;
+}
+
+pub fn encode_error(message: &str) -> Vec<u8> {
+    return error_line(message.as_bytes());
+}
+
+// Reply to HELLO, a map in RESP3 and a flat array in RESP2
+pub fn hello_reply(resp3: bool) -> Vec<u8> {
+    let fields: [(&str, &str); 3] = [
+        ("server", "ferdis"),
+        ("version", env!("CARGO_PKG_VERSION")),
+        ("proto", if resp3 { "3" } else { "2" }),
+    ];
+    let mut out = Vec::new();
+    if resp3 {
+        out.extend_from_slice(format!("%{}\r\n", fields.len()).as_bytes());
+    } else {
+        out.extend_from_slice(format!("*{}\r\n", fields.len() * 2).as_bytes());
+    }
+    for (name, value) in fields {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", name.len(), name).as_bytes());
+        if name == "proto" {
+            out.extend_from_slice(format!(":{}\r\n", value).as_bytes());
+        } else {
+            out.extend_from_slice(format!("${}\r\n{}\r\n", value.len(), value).as_bytes());
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multibulk() {
+        let buf = b"*3\r\n$3\r\nset\r\n$3\r\nk y\r\n$0\r\n\r\n*1\r\n$4\r\nping\r\n";
+        let (args, used) = parse_request(buf, 1024).unwrap().unwrap();
+        assert_eq!(args, vec![b"set".to_vec(), b"k y".to_vec(), b"".to_vec()]);
+        let (args, rest) = parse_request(&buf[used..], 1024).unwrap().unwrap();
+        assert_eq!(args, vec![b"ping".to_vec()]);
+        assert_eq!(used + rest, buf.len());
+        // every prefix is incomplete
+        for i in 0..used {
+            assert_eq!(parse_request(&buf[..i], 1024), Ok(None));
+        }
+        assert!(parse_request(b"*1\r\n$9999\r\n", 1024).is_err());
+        assert!(parse_request(b"*1\r\n+ok\r\n", 1024).is_err());
+    }
+
+    #[test]
+    fn test_parse_inline() {
+        let (args, used) = parse_request(b"SET  key value\r\nGET", 1024).unwrap().unwrap();
+        assert_eq!(args, vec![b"SET".to_vec(), b"key".to_vec(), b"value".to_vec()]);
+        assert_eq!(used, 16);
+        assert_eq!(parse_request(b"GET key", 1024), Ok(None));
+    }
+
+    #[test]
+    fn test_encode_reply() {
+        let mut arr = Vec::new();
+        arr.extend_from_slice(&(ResType::ARR as u32).to_le_bytes());
+        arr.extend_from_slice(&2u32.to_le_bytes());
+        arr.extend_from_slice(&(ResType::STR as u32).to_le_bytes());
+        arr.extend_from_slice(&2u32.to_le_bytes());
+        arr.extend_from_slice(b"hi");
+        arr.extend_from_slice(&(ResType::NIL as u32).to_le_bytes());
+        assert_eq!(encode_reply(&arr, false), b"*2\r\n$2\r\nhi\r\n$-1\r\n".to_vec());
+        assert_eq!(encode_reply(&arr, true), b"*2\r\n$2\r\nhi\r\n_\r\n".to_vec());
+        assert_eq!(encode_push(&arr), b">2\r\n$2\r\nhi\r\n_\r\n".to_vec());
+
+        let mut err = Vec::new();
+        err.extend_from_slice(&(ResType::ERR as u32).to_le_bytes());
+        err.extend_from_slice(&1u32.to_le_bytes());
+        err.extend_from_slice(&15u32.to_le_bytes());
+        err.extend_from_slice(b"Unknown command");
+        assert_eq!(encode_reply(&err, false), b"-ERR Unknown command\r\n".to_vec());
+        assert_eq!(encode_error("WRONGTYPE bad"), b"-WRONGTYPE bad\r\n".to_vec());
+
+        let mut int = Vec::new();
+        int.extend_from_slice(&(ResType::INT as u32).to_le_bytes());
+        int.extend_from_slice(&(-2i64).to_le_bytes());
+        assert_eq!(encode_reply(&int, false), b":-2\r\n".to_vec());
+
+        let mut dbl = Vec::new();
+        dbl.extend_from_slice(&(ResType::DBL as u32).to_le_bytes());
+        dbl.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(encode_reply(&dbl, false), b"$3\r\n1.5\r\n".to_vec());
+        assert_eq!(encode_reply(&dbl, true), b",1.5\r\n".to_vec());
+    }
+}
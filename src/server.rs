@@ -9,20 +9,51 @@ use nix::poll::poll;
 use nix::fcntl::{fcntl, OFlag, FcntlArg};
 use std::os::fd::AsRawFd;
 use std::os::fd::RawFd;
-use std::str::FromStr;
 use std::result::Result;
 use std::collections::HashMap;
-use std::sync::Mutex;
-use crate::oa_map::OAMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::keyspace::{Entry, Keyspace, Value, now_ms};
+use crate::zset::ZSet;
+use crate::list::List;
+use crate::hash::Hash;
+use crate::set::Set;
+use crate::stream::{Stream, StreamId};
+use crate::resp;
+use crate::aof::{self, Aof, FsyncPolicy};
+use crate::snapshot;
+use crate::client::{deserialize_response, serialize_request, ClientError, FerdisClient, Reply};
+use crate::replication::{self, LinkState, Replication};
+use crate::cluster::{self, Cluster, Migration, K_SLOTS};
+use crate::pubsub::{self, PubSub};
+use crate::blocking::{Blocking, Waiter};
 
-const K_MAX_MSG: usize = 4096;
-use once_cell::sync::Lazy;
-
-static STORAGE: Lazy<Mutex<OAMap<String, String>>> = Lazy::new(|| {
-    let m = OAMap::new();
-    Mutex::new(m)
-});
+// Default limit for one request or reply, buffers grow on demand up to it
+pub const K_MAX_MSG: usize = 512 << 20;
+// Bytes read from a socket in one go
+const K_READ_CHUNK: usize = 64 << 10;
+// How often the event loop looks for finished background jobs
+const K_BG_CHECK_MS: u64 = 100;
+// Keys moved per round of `cluster migrate` unless told otherwise
+const K_MIGRATE_BATCH: usize = 100;
+// Payload bytes after which a round of `cluster migrate` ends early, the
+// event loop waits for the whole round
+const K_MIGRATE_BATCH_BYTES: usize = 1 << 20;
+// How long a migration waits for the target
+const K_MIGRATE_TIMEOUT_MS: u64 = 5000;
+// Pause before a migration tries a target that failed again
+const K_MIGRATE_RETRY_MS: u64 = 1000;
+// Unsent pushed bytes after which a follower or subscriber that does not
+// keep up is dropped
+const K_PUSH_BUF_MAX: usize = 256 << 20;
 
+// REQ: waiting for requests, RES: replies are queued in wbuf (requests are
+// still read and answered in order), END: the connection is being closed
 #[derive(PartialEq)]
 enum ConnState {
     REQ,
@@ -35,7 +66,9 @@ pub enum ResType {
    NIL = 0,
    ERR = 1,
    STR = 2,
-   ARR = 3
+   ARR = 3,
+   INT = 4,
+   DBL = 5
 }
 
 impl ResType {
@@ -54,6 +87,12 @@ impl ResType {
             ResType::ARR => {
                 String::from("ARR")
             },
+            ResType::INT => {
+                String::from("INT")
+            },
+            ResType::DBL => {
+                String::from("DBL")
+            },
         }
     }
 
@@ -71,6 +110,12 @@ impl ResType {
             3 => {
                 return ResType::ARR;
             },
+            4 => {
+                return ResType::INT;
+            },
+            5 => {
+                return ResType::DBL;
+            },
             _ => {
                 panic!("Unknown value {}", value);
             }
@@ -78,14 +123,118 @@ impl ResType {
     }
 }
 
+pub struct ServerConfig {
+    pub addr: String,
+    // optional second listener that speaks RESP instead of the ferdis protocol
+    pub resp_addr: Option<String>,
+    // largest request or reply body in bytes
+    pub max_msg: usize,
+    // append-only file, replayed on startup when it exists
+    pub aof_path: Option<String>,
+    pub fsync: FsyncPolicy,
+    // target of save and bgsave, loaded on startup when there is no AOF
+    pub snapshot_path: Option<String>,
+    // "host:port" of a leader to replicate from
+    pub replicaof: Option<String>,
+    // serve only the hash slots assigned to this node
+    pub cluster_enabled: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        return ServerConfig {
+            addr: String::from("0.0.0.0:8081"),
+            resp_addr: None,
+            max_msg: K_MAX_MSG,
+            aof_path: None,
+            fsync: FsyncPolicy::EverySec,
+            snapshot_path: None,
+            replicaof: None,
+            cluster_enabled: false,
+        };
+    }
+}
+
+impl ServerConfig {
+    // "host:port" of the ferdis protocol listener, port 0 picks a free port
+    pub fn bind(mut self, addr: &str) -> ServerConfig {
+        self.addr = addr.to_string();
+        return self;
+    }
+
+    pub fn resp_bind(mut self, addr: &str) -> ServerConfig {
+        self.resp_addr = Some(addr.to_string());
+        return self;
+    }
+
+    pub fn max_msg(mut self, max_msg: usize) -> ServerConfig {
+        self.max_msg = max_msg;
+        return self;
+    }
+
+    pub fn aof(mut self, path: &str, fsync: FsyncPolicy) -> ServerConfig {
+        self.aof_path = Some(path.to_string());
+        self.fsync = fsync;
+        return self;
+    }
+
+    pub fn snapshot(mut self, path: &str) -> ServerConfig {
+        self.snapshot_path = Some(path.to_string());
+        return self;
+    }
+
+    pub fn replicaof(mut self, addr: &str) -> ServerConfig {
+        self.replicaof = Some(addr.to_string());
+        return self;
+    }
+
+    pub fn cluster_enabled(mut self, enabled: bool) -> ServerConfig {
+        self.cluster_enabled = enabled;
+        return self;
+    }
+
+    // Opens the listeners, the server starts serving with `Server::run`
+    pub fn build(self) -> Result<Server, Errno> {
+        return Server::bind(self);
+    }
+}
+
+// Wire protocol of a connection, fixed by the listener it came from. RESP
+// connections start as RESP2 and may switch with HELLO.
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    Ferdis,
+    Resp2,
+    Resp3,
+}
+
+// What is on the other end of a connection
+#[derive(Clone, Copy, PartialEq)]
+enum ConnKind {
+    Client,
+    // a follower that gets the replication stream
+    Replica,
+    // our link to the leader
+    Leader,
+}
+
 struct Conn {
     fd: RawFd,
     state: ConnState,
-    rbuf_size: usize,
-    rbuf: [u8; 4 + K_MAX_MSG],
-    wbuf_size: usize,
+    proto: Protocol,
+    kind: ConnKind,
+    // the next command may use a slot this node is importing
+    asking: bool,
+    // commands queued since `multi`, None outside a transaction
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+    // keys watched for the next `exec` with their versions at `watch`
+    watched: Vec<(Vec<u8>, u64)>,
+    max_msg: usize,
+    rbuf: Vec<u8>,
+    // bytes at the front of rbuf that belong to requests already handled
+    rbuf_read: usize,
     wbuf_sent: usize,
-    wbuf: [u8; 4 + K_MAX_MSG],
+    wbuf: Vec<u8>,
 }
 
 struct Response {
@@ -94,21 +243,24 @@ struct Response {
 }
 
 impl Conn {
-    fn new(fd: RawFd) -> Conn {
-        Conn{fd: fd, state: ConnState::REQ, rbuf_size: 0, rbuf: [0; 4 + K_MAX_MSG], wbuf_size: 0, wbuf_sent: 0, wbuf: [0; 4 + K_MAX_MSG]}
+    fn new(fd: RawFd, proto: Protocol, max_msg: usize) -> Conn {
+        Conn{fd: fd, state: ConnState::REQ, proto: proto, kind: ConnKind::Client, asking: false, queued: None, watched: Vec::new(), max_msg: max_msg, rbuf: Vec::new(), rbuf_read: 0, wbuf_sent: 0, wbuf: Vec::new()}
     }
 }
 
 fn set_nb_mode(fd: RawFd) -> Result<usize, Errno> {
-    if let Err(e) = fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) { return Err(e);
-    }
+    fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
     Ok(0)
 }
 
-fn accept_new_conn(fd2conn: &mut HashMap<RawFd, Conn>,fd: RawFd) -> Result<usize, Errno> {
+fn accept_new_conn(fd2conn: &mut HashMap<RawFd, Conn>,fd: RawFd, proto: Protocol, max_msg: usize) -> Result<usize, Errno> {
     match accept(fd) {
         Ok(connfd) => {
-            fd2conn.insert(connfd, Conn::new(connfd));
+            if let Err(e) = set_nb_mode(connfd) {
+                let _ = close(connfd);
+                return Err(e);
+            }
+            fd2conn.insert(connfd, Conn::new(connfd, proto, max_msg));
         },
         Err(e) => {
             return Err(e);
@@ -117,31 +269,32 @@ fn accept_new_conn(fd2conn: &mut HashMap<RawFd, Conn>,fd: RawFd) -> Result<usize
     Ok(0)
 }
 
-fn connection_io(conn: &mut Conn) {
-    match conn.state {
-        ConnState::REQ => {
-            state_req(conn);
-        },
-        ConnState::RES => {
-            state_res(conn);
-        }
-        ConnState::END => {
-
-        }
+fn connection_io(conn: &mut Conn, revents: PollFlags, state: &mut State) {
+    if revents.intersects(PollFlags::POLLIN | PollFlags::POLLERR | PollFlags::POLLHUP) {
+        state_req(conn, state);
+    }
+    if conn.state == ConnState::RES && revents.contains(PollFlags::POLLOUT) {
+        state_res(conn);
     }
 }
 
-fn state_req(conn: &mut Conn) {
-    while try_fill_buffer(conn) {};
+fn state_req(conn: &mut Conn, state: &mut State) {
+    while try_fill_buffer(conn, state) {};
+    if conn.state == ConnState::RES {
+        state_res(conn);
+    }
 }
 
-fn try_fill_buffer(conn: &mut Conn) -> bool {
-    assert!(conn.rbuf_size < conn.rbuf.len());
+fn try_fill_buffer(conn: &mut Conn, state: &mut State) -> bool {
+    // grow the buffer by one chunk and shrink it back to the bytes read
+    let filled = conn.rbuf.len();
+    conn.rbuf.resize(filled + K_READ_CHUNK, 0);
     loop {
-        match read(conn.fd, &mut conn.rbuf[conn.rbuf_size..]) {
+        match read(conn.fd, &mut conn.rbuf[filled..]) {
             Ok(rv) => {
+                conn.rbuf.truncate(filled + rv);
                 if rv == 0 {
-                    if conn.rbuf_size > 0 {
+                    if filled > 0 {
                         println!("unexpected EOF");
                     } else {
                         println!("EOF");
@@ -149,8 +302,6 @@ fn try_fill_buffer(conn: &mut Conn) -> bool {
                     conn.state = ConnState::END;
                     return false;
                 }
-                conn.rbuf_size += rv;
-                assert!(conn.rbuf_size <= conn.rbuf.len());
                 break;
             },
             Err(e) => {
@@ -159,9 +310,11 @@ fn try_fill_buffer(conn: &mut Conn) -> bool {
                         continue;
                     },
                     Errno::EAGAIN => {
+                        conn.rbuf.truncate(filled);
                         return false;
                     },
                     _ => {
+                        conn.rbuf.truncate(filled);
                         println!("read() error while filling buffer");
                         conn.state = ConnState::END;
                         return false;
@@ -170,285 +323,4076 @@ fn try_fill_buffer(conn: &mut Conn) -> bool {
             }
         }
     }
-    while try_one_request(conn) {}
-    return conn.state == ConnState::REQ;
+    handle_requests(conn, state);
+    return conn.state != ConnState::END;
 }
 
-fn do_request(req_buf: &[u8]) -> Result<Response, Errno> {
-    match parse_request(req_buf) {
-        Ok(r) => {
-            let command: Vec<&str> = r.split(" ").collect();
-            match command[0] {
-                "get" => {
-                    // do get
-                    return do_get(command);
-                },
-                "set" => {
-                    // do set
-                    return do_set(command);
-                },
-                "del" => {
-                    // do del
-                    return do_del(command);
-                },
-                "keys" => {
-                    // do keys
-                    return do_keys(command);
-                },
-                _ => {
-                    let out = out_err(1, "Unknown command");
-                    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+// Answers every complete request in the buffer, replies are queued in order.
+// Stops early at a request that blocks the connection.
+fn handle_requests(conn: &mut Conn, state: &mut State) {
+    while try_one_request(conn, state) {}
+    conn.rbuf.drain(..conn.rbuf_read);
+    conn.rbuf_read = 0;
+}
+
+// Runs one command that arrived on `conn`. ASKING is kept on the connection
+// and only applies to the command after it.
+fn conn_command(conn: &mut Conn, command: Vec<Vec<u8>>, state: &mut State) -> Result<Response, Errno> {
+    if command.len() == 1 && command[0].eq_ignore_ascii_case(b"asking") {
+        conn.asking = true;
+        let out = out_nil();
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let subscribed = state.pubsub.count(conn.fd) > 0;
+    if subscribed && conn.proto != Protocol::Resp3 && !command.first().is_some_and(|name| name.eq_ignore_ascii_case(b"ping")) {
+        // replies could not be told apart from pushed messages
+        let name = String::from_utf8_lossy(command.first().map_or(b"".as_slice(), |n| n.as_slice())).to_lowercase();
+        let message = format!("Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context", name);
+        let out = out_err(13, &message);
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    match command.first().map(|name| name.to_ascii_lowercase()).as_deref() {
+        Some(b"multi") => {
+            return do_multi(conn, &command);
+        },
+        Some(b"exec") => {
+            return do_exec(conn, &command, state);
+        },
+        Some(b"discard") => {
+            return do_discard(conn, &command, state);
+        },
+        Some(b"watch") => {
+            return do_watch(conn, &command, state);
+        },
+        Some(b"unwatch") => {
+            unwatch_all(conn, &mut state.keyspace);
+            let out = out_nil();
+            return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        _ => {}
+    }
+    if let Some(queued) = conn.queued.as_mut() {
+        let out = if is_subscription_command(&command) {
+            out_err(13, "Command not allowed inside a transaction")
+        } else {
+            queued.push(command);
+            out_str(b"QUEUED")
+        };
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let asking = std::mem::replace(&mut conn.asking, false);
+    let waiter = blocking_waiter(&command, &mut state.keyspace);
+    let res = do_command(command, asking, state)?;
+    if let Some(mut waiter) = waiter {
+        if read_u32(&res.message, 0) == Some(ResType::NIL as u32) {
+            // nothing to pop yet, the reply comes from `serve_blocked`
+            waiter.asking = asking;
+            state.blocking.block(conn.fd, waiter);
+        }
+    }
+    return Ok(res);
+}
+
+// What a blocking command waits for when it finds nothing, None for other
+// commands, for calls that do not block and for arguments the handler
+// rejects. The command kept for the retry has "$" ids of xread pinned to
+// the current last id, so entries added while waiting are not skipped.
+fn blocking_waiter(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Option<Waiter> {
+    let mut command = command.to_vec();
+    command[0] = command.first()?.to_ascii_lowercase();
+    match command[0].as_slice() {
+        b"blpop" | b"brpop" => {
+            if command.len() < 3 {
+                return None;
+            }
+            let timeout = parse_timeout(command.last().unwrap())?;
+            let keys = command[1..command.len() - 1].to_vec();
+            return Some(Waiter { keys: keys, command: command, deadline: deadline_after((timeout * 1000.0).ceil() as u64), asking: false });
+        },
+        b"xread" | b"xreadgroup" => {
+            let options = parse_read_options(&command).ok()?;
+            let block = options.block?;
+            let n = (command.len() - options.streams) / 2;
+            let keys = command[options.streams..options.streams + n].to_vec();
+            if options.group.is_some() && command[options.streams + n..].iter().any(|id| id != b">") {
+                // reading pending entries never waits
+                return None;
+            }
+            let now = now_ms();
+            for (i, key) in keys.iter().enumerate() {
+                if command[options.streams + n + i] == b"$" {
+                    let last = match keyspace.get(key, now).map(|entry| &entry.value) {
+                        Some(Value::Stream(stream)) => stream.last_id(),
+                        _ => StreamId::MIN,
+                    };
+                    command[options.streams + n + i] = last.to_string().into_bytes();
                 }
             }
+            return Some(Waiter { keys: keys, command: command, deadline: deadline_after(block), asking: false });
         },
-        Err(e) => {
-            return Err(e);
+        _ => {
+            return None;
         }
     }
 }
-fn do_keys(command: Vec<&str>) -> Result<Response,Errno> {
+
+// The deadline `ms` milliseconds from now, None for 0 which waits forever.
+// now_ms rounds down, the extra millisecond keeps the wait from ending
+// early.
+fn deadline_after(ms: u64) -> Option<u64> {
+    if ms == 0 {
+        return None;
+    }
+    return Some(now_ms().saturating_add(ms).saturating_add(1));
+}
+
+// multi, commands after it are queued until exec or discard
+fn do_multi(conn: &mut Conn, command: &[Vec<u8>]) -> Result<Response,Errno> {
+    let out = if command.len() > 1 {
+        out_err(3, "Too many arguments")
+    } else if conn.queued.is_some() {
+        out_err(13, "MULTI calls can not be nested")
+    } else {
+        conn.queued = Some(Vec::new());
+        out_nil()
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// exec, runs the queued commands one after the other and returns their
+// replies, or NIL without running them when a watched key changed. Nothing
+// else runs in between since the event loop handles one command at a time.
+// ASKING before multi applies to the whole transaction, and a redirect for
+// any command sends all of it elsewhere before anything runs.
+fn do_exec(conn: &mut Conn, command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
     if command.len() > 1 {
         let out = out_err(3, "Too many arguments");
         return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
     }
+    let queued = match conn.queued.take() {
+        Some(queued) => queued,
+        None => {
+            let out = out_err(13, "EXEC without MULTI");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let changed = conn.watched.iter().any(|(key, seen)| state.keyspace.version(key, now) != Some(*seen));
+    unwatch_all(conn, &mut state.keyspace);
+    if changed {
+        let out = out_nil();
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let asking = std::mem::replace(&mut conn.asking, false);
+    let queued: Vec<Vec<Vec<u8>>> = queued.into_iter()
+        .map(|command| if command.is_empty() { command } else { absolute_form(command, now) })
+        .collect();
+    for command in queued.iter().filter(|command| !command.is_empty()) {
+        if let Some(out) = cluster_redirect(command, asking, state) {
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    }
+    // the log and followers get several writes as one transaction too
+    let writes = queued.iter().filter(|command| !command.is_empty() && is_write(&command[0])).count();
+    let wrap = writes > 1 && state.repl.leader.is_none();
+    if wrap {
+        propagate(&[b"multi".to_vec()], state);
+    }
+    let mut replies = Vec::new();
+    let mut failed = None;
+    for command in queued {
+        if command.is_empty() {
+            replies.push(out_err(2, "Insufficient arguments"));
+            continue;
+        }
+        match run_command(&command, state) {
+            Ok(res) => {
+                replies.push(res.message);
+            },
+            Err(e) => {
+                failed = Some(e);
+                break;
+            }
+        }
+    }
+    if wrap {
+        propagate(&[b"exec".to_vec()], state);
+    }
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    let out = out_arr_raw(replies);
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
 
-    let storage = STORAGE.lock().unwrap();
-    let keys = storage.keys();
-    let out;
-    if keys.is_empty() {
-        out = out_nil();
+// discard, drops the queued commands and the watched keys
+fn do_discard(conn: &mut Conn, command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    let out = if command.len() > 1 {
+        out_err(3, "Too many arguments")
+    } else if conn.queued.take().is_none() {
+        out_err(13, "DISCARD without MULTI")
     } else {
-        out = out_arr(storage.keys());
-    }
-    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out });
+        unwatch_all(conn, &mut state.keyspace);
+        out_nil()
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
 }
 
-fn do_get(command: Vec<&str>) -> Result<Response,Errno> {
+// watch key..., the next exec fails if any of the keys changes before it
+fn do_watch(conn: &mut Conn, command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
     if command.len() < 2 {
         let out = out_err(2, "Insufficient arguments");
         return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
     }
-
-    let storage = STORAGE.lock().unwrap();
-    if !storage.contains_key(command[1].to_string()) {
-        let out = out_nil();
+    if conn.queued.is_some() {
+        let out = out_err(13, "WATCH inside MULTI is not allowed");
         return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
     }
-    let value = storage.get(command[1].to_string()).unwrap();
-    let out = out_str(&value);
+    let mut lower = command.to_vec();
+    lower[0] = b"watch".to_vec();
+    let asking = std::mem::replace(&mut conn.asking, false);
+    if let Some(out) = cluster_redirect(&lower, asking, state) {
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    for key in command[1..].iter() {
+        if !conn.watched.iter().any(|(watched, _)| watched == key) {
+            let version = state.keyspace.watch(key, now);
+            conn.watched.push((key.clone(), version));
+        }
+    }
+    let out = out_nil();
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
 
-    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out });
+fn unwatch_all(conn: &mut Conn, keyspace: &mut Keyspace) {
+    for (key, _) in conn.watched.drain(..) {
+        keyspace.unwatch(&key);
+    }
+}
+
+fn is_subscription_command(command: &[Vec<u8>]) -> bool {
+    match command.first().map(|name| name.to_ascii_lowercase()).as_deref() {
+        Some(b"subscribe") | Some(b"psubscribe") | Some(b"unsubscribe") | Some(b"punsubscribe") => {
+            return true;
+        },
+        _ => {
+            return false;
+        }
+    }
+}
+
+// subscribe channel... / psubscribe pattern... / unsubscribe [channel...] /
+// punsubscribe [pattern...]. Each channel or pattern is confirmed with its
+// own push [kind, name, subscriptions left], so these answer on the
+// connection instead of returning one reply. Without names the unsubscribe
+// commands drop every channel or pattern.
+fn do_subscriptions(conn: &mut Conn, command: &[Vec<u8>], state: &mut State) {
+    let kind = command[0].to_ascii_lowercase();
+    let fd = conn.fd;
+    let mut names: Vec<Vec<u8>> = command[1..].to_vec();
+    if names.is_empty() {
+        match kind.as_slice() {
+            b"unsubscribe" => {
+                names = state.pubsub.channels_of(fd);
+            },
+            b"punsubscribe" => {
+                names = state.pubsub.patterns_of(fd);
+            },
+            _ => {
+                push_value(conn, &out_err(2, "Insufficient arguments"));
+                return;
+            }
+        }
+        if names.is_empty() {
+            let count = i64::try_from(state.pubsub.count(fd)).unwrap();
+            push_value(conn, &out_arr_raw(vec![out_str(&kind), out_nil(), out_int(count)]));
+            return;
+        }
+    }
+    for name in names {
+        match kind.as_slice() {
+            b"subscribe" => state.pubsub.subscribe(fd, &name),
+            b"psubscribe" => state.pubsub.psubscribe(fd, &name),
+            b"unsubscribe" => state.pubsub.unsubscribe(fd, &name),
+            _ => state.pubsub.punsubscribe(fd, &name),
+        }
+        let count = i64::try_from(state.pubsub.count(fd)).unwrap();
+        push_value(conn, &out_arr_raw(vec![out_str(&kind), out_str(&name), out_int(count)]));
+    }
 }
 
-fn do_set(command: Vec<&str>) -> Result<Response,Errno> {
+// publish channel message, pushes the message to every subscriber of the
+// channel and every matching pattern, returns how many got it
+fn do_publish(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
     if command.len() < 3 {
         let out = out_err(2, "Insufficient arguments");
         return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
     }
-    let mut storage = STORAGE.lock().unwrap();
-    storage.put(command[1].to_string(), command[2].to_string());
-    let out = out_nil();
-    Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out})
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let (channel, message) = (&command[1], &command[2]);
+    let mut receivers = 0;
+    for fd in state.pubsub.subscribers(channel) {
+        let push = out_arr_raw(vec![out_str(b"message"), out_str(channel), out_str(message)]);
+        state.outbox.push((fd, Outgoing::Push(push)));
+        receivers += 1;
+    }
+    for (pattern, fd) in state.pubsub.pattern_subscribers(channel) {
+        let push = out_arr_raw(vec![out_str(b"pmessage"), out_str(&pattern), out_str(channel), out_str(message)]);
+        state.outbox.push((fd, Outgoing::Push(push)));
+        receivers += 1;
+    }
+    let out = out_int(receivers);
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
 }
 
-fn do_del(command: Vec<&str>) -> Result<Response,Errno> {
+// pubsub channels [pattern] / pubsub numsub [channel...]
+fn do_pubsub(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
     if command.len() < 2 {
         let out = out_err(2, "Insufficient arguments");
         return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
     }
-
-    let mut storage = STORAGE.lock().unwrap();
-    if storage.contains_key(command[1].to_string()) {
-        let val = storage.get(command[1].to_string());
-        let out = out_str(&val.unwrap());
-        storage.delete(command[1].to_string());
-        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
-    }
-    let out = out_nil();
+    let out = match command[1].to_ascii_lowercase().as_slice() {
+        b"channels" if command.len() <= 3 => {
+            let mut channels: Vec<Vec<u8>> = state.pubsub.active_channels().into_iter()
+                .filter(|channel| command.get(2).is_none_or(|pattern| pubsub::glob_match(pattern, channel)))
+                .collect();
+            channels.sort();
+            out_arr(channels)
+        },
+        b"numsub" => {
+            let mut items = Vec::new();
+            for channel in command[2..].iter() {
+                items.push(out_str(channel));
+                items.push(out_int(i64::try_from(state.pubsub.subscribers(channel).len()).unwrap()));
+            }
+            out_arr_raw(items)
+        },
+        b"channels" => out_err(3, "Too many arguments"),
+        _ => out_err(1, "Unknown pubsub subcommand"),
+    };
     return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
 }
 
-fn out_nil() -> Vec<u8> {
-    let mut out = Vec::new();
-    (ResType::NIL as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    return out;
+// Runs one command, independent of the protocol it arrived with
+fn do_command(command: Vec<Vec<u8>>, asking: bool, state: &mut State) -> Result<Response, Errno> {
+    if command.is_empty() {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let command = absolute_form(command, now_ms());
+    if let Some(out) = cluster_redirect(&command, asking, state) {
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    return run_command(&command, state);
 }
 
-fn out_str(val: &str) -> Vec<u8> {
-    let mut out = Vec::new();
-    (ResType::STR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    (val.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    val.bytes().for_each(|b| out.push(b));
-    return out;
+// Runs a command in absolute form that this node serves
+fn run_command(command: &[Vec<u8>], state: &mut State) -> Result<Response, Errno> {
+    if state.repl.leader.is_some() && is_write(&command[0]) {
+        // followers only change through the stream from their leader
+        let out = out_err(8, "READONLY You can't write against a read only replica");
+        return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let res = execute(command, state)?;
+    replicate(command, &res.message, state);
+    return Ok(res);
 }
 
-fn out_err(code: u32, message: &str) -> Vec<u8> {
-    let mut out = Vec::new();
-    (ResType::ERR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    code.to_le_bytes().iter().for_each(|b| out.push(*b));
-    (message.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    message.bytes().for_each(|b| out.push(b));
-    return out;
+// Hands an executed write on, in a form that replays to the same result,
+// and lets connections blocked on its keys try again
+fn replicate(command: &[Vec<u8>], reply: &[u8], state: &mut State) {
+    if !is_write(&command[0]) || is_error(reply) {
+        return;
+    }
+    match command[0].as_slice() {
+        b"blpop" | b"brpop" | b"spop" | b"xadd" | b"xreadgroup" => {
+            if let Some(effect) = replay_form(command, reply) {
+                propagate(&effect, state);
+            }
+        },
+        _ => {
+            propagate(command, state);
+        }
+    }
+    for key in command_keys(command) {
+        state.blocking.touch(key);
+    }
 }
 
-fn out_arr(values: Vec<String>) -> Vec<u8> {
-    let mut out = Vec::new();
-    (ResType::ARR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    (values.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
-    for val in values {
-        out_str(&val).iter().for_each(|b| out.push(*b));
+// What a blocking, random or clock driven write did, as a command that does
+// the same when replayed: followers get the pop that happened, not the
+// wait, the members that were drawn, not the draw, and the id xadd picked.
+// None when nothing changed.
+fn replay_form(command: &[Vec<u8>], reply: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let reply = deserialize_response(reply).ok()?;
+    if command[0] == b"xadd" {
+        let pos = match command[2].to_ascii_lowercase().as_slice() {
+            b"maxlen" | b"minid" => parse_trim(command, 2).ok()?.1,
+            _ => 2,
+        };
+        let mut effect = command.to_vec();
+        if let Reply::Str(id) = reply {
+            effect[pos] = id;
+        }
+        return Some(effect);
+    }
+    if command[0] == b"xreadgroup" {
+        return if reply == Reply::Nil { None } else { Some(command.to_vec()) };
+    }
+    if command[0] == b"spop" {
+        let mut effect = vec![b"srem".to_vec(), command[1].clone()];
+        match reply {
+            Reply::Str(member) => effect.push(member),
+            Reply::Arr(members) => {
+                for member in members {
+                    if let Reply::Str(member) = member {
+                        effect.push(member);
+                    }
+                }
+            },
+            _ => {}
+        }
+        return if effect.len() > 2 { Some(effect) } else { None };
+    }
+    match reply {
+        Reply::Arr(reply) => match reply.first() {
+            Some(Reply::Str(key)) => {
+                let pop: &[u8] = if command[0] == b"blpop" { b"lpop" } else { b"rpop" };
+                return Some(vec![pop.to_vec(), key.clone()]);
+            },
+            _ => {
+                return None;
+            }
+        },
+        _ => {
+            return None;
+        }
     }
-    return out;
 }
 
-fn parse_request(req_buf: &[u8]) -> Result<String, Errno> {
-    Ok(String::from_utf8(req_buf.to_vec()).unwrap())
+// Hands an executed write on to the AOF and to the followers
+fn propagate(command: &[Vec<u8>], state: &mut State) {
+    if let Some(aof) = state.aof.as_mut() {
+        if let Err(e) = aof.append(command) {
+            println!("Error {} while writing the AOF", e);
+        }
+    }
+    let data = serialize_request(command);
+    state.repl.backlog.append(&data);
+    for fd in state.repl.replicas.iter() {
+        state.outbox.push((*fd, Outgoing::Raw(data.clone())));
+    }
 }
 
-fn try_one_request(conn: &mut Conn) -> bool {
-    if conn.rbuf_size < 4 {
-        // not enough data in the buffer, retry
-        return false;
+// Keys a command works on, the ones that decide where it runs in cluster mode
+fn command_keys(command: &[Vec<u8>]) -> &[Vec<u8>] {
+    match command[0].as_slice() {
+        b"get" | b"set" | b"del" | b"pexpireat" | b"expireat" | b"expire" | b"pexpire" | b"ttl" | b"pttl"
+        | b"persist" | b"type" | b"incr" | b"incrby" | b"decr" | b"decrby" | b"incrbyfloat" | b"lpush" | b"rpush"
+        | b"lpop" | b"rpop" | b"llen" | b"lrange" | b"lindex" | b"ltrim" | b"hset" | b"hget" | b"hdel" | b"hgetall"
+        | b"hincrby" | b"hlen" | b"sadd" | b"srem" | b"sismember" | b"smembers" | b"scard" | b"srandmember" | b"spop"
+        | b"xadd" | b"xlen" | b"xrange" | b"xtrim" | b"xack" | b"xpending" | b"zadd" | b"zrem" | b"zscore" | b"zrank" | b"zrange" | b"zquery" | b"dump"
+        | b"restore" => {
+            return &command[1..command.len().min(2)];
+        },
+        b"watch" | b"sinter" | b"sunion" | b"sdiff" | b"sinterstore" | b"sunionstore" | b"sdiffstore" => {
+            return &command[1..];
+        },
+        b"blpop" | b"brpop" => {
+            // the last argument is the timeout
+            return &command[1..command.len().max(2) - 1];
+        },
+        b"xgroup" => {
+            return &command[command.len().min(2)..command.len().min(3)];
+        },
+        b"xread" | b"xreadgroup" => {
+            match parse_read_options(command) {
+                Ok(options) => {
+                    let n = (command.len() - options.streams) / 2;
+                    return &command[options.streams..options.streams + n];
+                },
+                Err(_) => {
+                    return &[];
+                }
+            }
+        },
+        _ => {
+            return &[];
+        }
     }
+}
 
-    let mut len_buf: [u8; 4] = [0;4];
-    len_buf.copy_from_slice(&conn.rbuf[0..4]);
-    let length = u32::from_le_bytes(len_buf);
-
-    if length > u32::try_from(K_MAX_MSG).unwrap() {
-        println!("Message too long");
-        conn.state = ConnState::END;
-        return false;
+// The error that sends a command elsewhere when its keys are not served by
+// this node, None when it can run here
+fn cluster_redirect(command: &[Vec<u8>], asking: bool, state: &mut State) -> Option<Vec<u8>> {
+    let cluster = state.cluster.as_ref()?;
+    let keys = command_keys(command);
+    let slot = cluster::key_slot(keys.first()?);
+    if keys.iter().any(|key| cluster::key_slot(key) != slot) {
+        return Some(out_err(10, "CROSSSLOT Keys in request don't hash to the same slot"));
     }
-
-    if 4 + length > u32::try_from(conn.rbuf_size).unwrap() {
-        // not enough data in the buffer, retry
-        return false;
+    if cluster.is_mine(slot) {
+        let target = cluster.migrating(slot)?;
+        // keys that moved already, or never existed, are the target's now
+        let now = now_ms();
+        let present = keys.iter().filter(|key| state.keyspace.contains_key(key, now)).count();
+        if present == 0 {
+            return Some(out_err(11, &format!("ASK {} {}", slot, target)));
+        }
+        if present < keys.len() {
+            return Some(out_err(10, "TRYAGAIN Multiple keys request during rehashing of slot"));
+        }
+        return None;
     }
-
-    println!("Client says {}", String::from_utf8(conn.rbuf[4..(4 + length).try_into().unwrap()].to_vec()).unwrap());
-    // get one request and generate a response
-    match do_request(&conn.rbuf[4..4 + usize::try_from(length).unwrap()]) {
-        Ok(res) => {
-            conn.wbuf[0..4].copy_from_slice(&res.length.to_le_bytes());
-            conn.wbuf[4..4 + usize::try_from(res.length).unwrap()].copy_from_slice(&res.message);
-            conn.wbuf_size += 4 + usize::try_from(res.length).unwrap();
+    if asking && cluster.importing(slot).is_some() {
+        return None;
+    }
+    match cluster.owner(slot) {
+        Some(owner) => {
+            return Some(out_err(9, &format!("MOVED {} {}", slot, owner)));
         },
-        Err(_) => {
-            println!("Could not do request");
-            conn.state = ConnState::END;
-            return false;
+        None => {
+            return Some(out_err(10, "CLUSTERDOWN Hash slot not served"));
         }
     }
+}
 
-
-    // remove the request from the buffer
-    let remain = conn.rbuf_size - 4 - usize::try_from(length).unwrap();
-    if remain > 0 {
-        conn.rbuf.copy_within(4 + usize::try_from(length).unwrap().., 0);
+// Commands that change the keyspace, these are logged to the AOF and sent to
+// followers
+fn is_write(name: &[u8]) -> bool {
+    match name.to_ascii_lowercase().as_slice() {
+        b"set" | b"del" | b"expire" | b"pexpire" | b"expireat" | b"pexpireat" | b"persist" | b"zadd" | b"zrem" | b"restore"
+        | b"incr" | b"incrby" | b"decr" | b"decrby" | b"incrbyfloat" | b"lpush" | b"rpush" | b"lpop" | b"rpop" | b"ltrim"
+        | b"blpop" | b"brpop" | b"hset" | b"hdel" | b"hincrby" | b"sadd" | b"srem" | b"spop" | b"sinterstore"
+        | b"sunionstore" | b"sdiffstore" | b"xadd" | b"xtrim" | b"xgroup" | b"xreadgroup" | b"xack" => {
+            return true;
+        },
+        _ => {
+            return false;
+        }
     }
-    conn.rbuf_size = remain;
-
-    // change state
-    conn.state = ConnState::RES;
-    state_res(conn);
-
-    return conn.state == ConnState::REQ;
 }
 
-fn state_res(conn: &mut Conn) {
-    while try_flush_buffer(conn) {}
+fn is_error(reply: &[u8]) -> bool {
+    return read_u32(reply, 0) == Some(ResType::ERR as u32);
 }
 
-fn try_flush_buffer(conn: &mut Conn) -> bool {
-    loop {
-        let remain = conn.wbuf_size - conn.wbuf_sent;
-        match write(conn.fd, &mut conn.wbuf[conn.wbuf_sent..conn.wbuf_sent + remain]) {
-            Ok(rv) => {
-                conn.wbuf_sent += rv;
-                assert!(conn.wbuf_sent <= conn.wbuf_size);
-                if conn.wbuf_sent == conn.wbuf_size {
-                    conn.state = ConnState::REQ;
-                    conn.wbuf_size = 0;
-                    conn.wbuf_sent = 0;
-                    return false;
-                }
-                break;
-            },
-            Err(e) => {
-                match e {
-                    Errno::EINTR => {
-                        continue;
-                    },
-                    Errno::EAGAIN => {
-                        return false;
-                    },
+// Rewrites relative expire times into absolute deadlines, so the command has
+// the same effect whenever it is replayed. Commands with arguments that do
+// not parse are left alone for the handler to reject. The name comes back
+// lower case.
+fn absolute_form(mut command: Vec<Vec<u8>>, now: u64) -> Vec<Vec<u8>> {
+    command[0] = command[0].to_ascii_lowercase();
+    let unit: i64 = match command[0].as_slice() {
+        b"expire" => 1000,
+        b"pexpire" => 1,
+        b"set" => {
+            let mut i = 3;
+            while i + 1 < command.len() {
+                let unit: i64 = match command[i].to_ascii_lowercase().as_slice() {
+                    b"ex" => 1000,
+                    b"px" => 1,
                     _ => {
-                        println!("read() error while flushing buffer");
-                        conn.state = ConnState::END;
-                        return false;
+                        i += 2;
+                        continue;
                     }
+                };
+                match parse_int(&command[i + 1]).and_then(|a| a.checked_mul(unit)) {
+                    Some(ms) if ms > 0 => {
+                        command[i] = b"pxat".to_vec();
+                        command[i + 1] = now.saturating_add(ms as u64).to_string().into_bytes();
+                    },
+                    _ => {}
                 }
+                i += 2;
             }
+            return command;
+        },
+        _ => {
+            return command;
+        }
+    };
+    if command.len() == 3 {
+        if let Some(ms) = parse_int(&command[2]).and_then(|a| a.checked_mul(unit)) {
+            command[0] = b"pexpireat".to_vec();
+            command[2] = (now as i64).saturating_add(ms).to_string().into_bytes();
         }
     }
-    return true;
+    return command;
 }
 
-pub fn run_server() {
-    let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None);
-    match fd {
-        Ok(fd) => {
-            let _ = setsockopt(fd, ReuseAddr, &true);
-            let localhost = SockaddrIn::from_str("0.0.0.0:8081").unwrap();
-            bind(fd.as_raw_fd(), &localhost).expect("bind");
-            match listen(fd.as_raw_fd(), 128) {
-                Ok(()) => {
-                    let mut fd2conn: HashMap<RawFd,Conn> = HashMap::new();
-                    let mut poll_args: Vec<PollFd> = Vec::new();
-                    let mut listening_id: PollFd;
-                    if let Err(e) = set_nb_mode(fd) {
-                        println!("Error {} while setting non-blocking mode on fd {}", e, fd);
-                        return;
-                    }
-                    loop {
-                        poll_args.clear();
-                        listening_id = PollFd::new(fd, PollFlags::POLLIN);
-                        for (fd, conn) in fd2conn.iter() {
-                            let mut pfd = PollFd::new(*fd, PollFlags::empty());
-                            if conn.state == ConnState::REQ {
-                                pfd.set_events(PollFlags::POLLERR | PollFlags::POLLIN);
-                            } else {
-                                pfd.set_events(PollFlags::POLLERR | PollFlags::POLLOUT);
-                            }
-                            poll_args.push(pfd);
+// Runs a command in absolute form against the keyspace
+fn execute(command: &[Vec<u8>], state: &mut State) -> Result<Response, Errno> {
+    if command.is_empty() {
+        return Err(Errno::EPROTO);
+    }
+    let keyspace = &mut state.keyspace;
+    match String::from_utf8_lossy(&command[0]).as_ref() {
+        "save" => {
+            return do_save(command, state);
+        },
+        "bgsave" => {
+            return do_bgsave(command, state);
+        },
+        "lastsave" => {
+            return do_lastsave(command, state);
+        },
+        "bgrewriteaof" => {
+            return do_bgrewriteaof(command, state);
+        },
+        "replicaof" => {
+            return do_replicaof(command, state);
+        },
+        "info" => {
+            return do_info(command, state);
+        },
+        "cluster" => {
+            return do_cluster(command, state);
+        },
+        "publish" => {
+            return do_publish(command, state);
+        },
+        "pubsub" => {
+            return do_pubsub(command, state);
+        },
+        "ping" => {
+            return do_ping(command);
+        },
+        "get" => {
+            // do get
+            return do_get(command, keyspace);
+        },
+        "set" => {
+            // do set
+            return do_set(command, keyspace);
+        },
+        "del" => {
+            // do del
+            return do_del(command, keyspace);
+        },
+        "incr" | "incrby" => {
+            return do_incrby(command, keyspace, 1);
+        },
+        "decr" | "decrby" => {
+            return do_incrby(command, keyspace, -1);
+        },
+        "incrbyfloat" => {
+            return do_incrbyfloat(command, keyspace);
+        },
+        "keys" => {
+            // do keys
+            return do_keys(command, keyspace);
+        },
+        "dbsize" => {
+            return do_dbsize(command, keyspace);
+        },
+        "expire" | "expireat" => {
+            return do_expireat(command, keyspace, 1000);
+        },
+        "pexpire" | "pexpireat" => {
+            return do_expireat(command, keyspace, 1);
+        },
+        "ttl" => {
+            return do_ttl(command, keyspace, 1000);
+        },
+        "pttl" => {
+            return do_ttl(command, keyspace, 1);
+        },
+        "persist" => {
+            return do_persist(command, keyspace);
+        },
+        "type" => {
+            return do_type(command, keyspace);
+        },
+        "zadd" => {
+            return do_zadd(command, keyspace);
+        },
+        "zrem" => {
+            return do_zrem(command, keyspace);
+        },
+        "zscore" => {
+            return do_zscore(command, keyspace);
+        },
+        "zrank" => {
+            return do_zrank(command, keyspace);
+        },
+        "zrange" => {
+            return do_zrange(command, keyspace);
+        },
+        "zquery" => {
+            return do_zquery(command, keyspace);
+        },
+        "lpush" | "rpush" => {
+            return do_push(command, keyspace);
+        },
+        "lpop" | "rpop" => {
+            return do_pop(command, keyspace);
+        },
+        "blpop" | "brpop" => {
+            return do_bpop(command, keyspace);
+        },
+        "llen" => {
+            return do_llen(command, keyspace);
+        },
+        "lrange" => {
+            return do_lrange(command, keyspace);
+        },
+        "lindex" => {
+            return do_lindex(command, keyspace);
+        },
+        "ltrim" => {
+            return do_ltrim(command, keyspace);
+        },
+        "hset" => {
+            return do_hset(command, keyspace);
+        },
+        "hget" => {
+            return do_hget(command, keyspace);
+        },
+        "hdel" => {
+            return do_hdel(command, keyspace);
+        },
+        "hgetall" => {
+            return do_hgetall(command, keyspace);
+        },
+        "hincrby" => {
+            return do_hincrby(command, keyspace);
+        },
+        "hlen" => {
+            return do_hlen(command, keyspace);
+        },
+        "sadd" => {
+            return do_sadd(command, keyspace);
+        },
+        "srem" => {
+            return do_srem(command, keyspace);
+        },
+        "sismember" => {
+            return do_sismember(command, keyspace);
+        },
+        "smembers" => {
+            return do_smembers(command, keyspace);
+        },
+        "scard" => {
+            return do_scard(command, keyspace);
+        },
+        "sinter" | "sunion" | "sdiff" => {
+            return do_salgebra(command, keyspace);
+        },
+        "sinterstore" | "sunionstore" | "sdiffstore" => {
+            return do_salgebra_store(command, keyspace);
+        },
+        "srandmember" => {
+            return do_srandmember(command, keyspace, state.max_msg);
+        },
+        "spop" => {
+            return do_spop(command, keyspace);
+        },
+        "xadd" => {
+            return do_xadd(command, keyspace);
+        },
+        "xlen" => {
+            return do_xlen(command, keyspace);
+        },
+        "xrange" => {
+            return do_xrange(command, keyspace);
+        },
+        "xtrim" => {
+            return do_xtrim(command, keyspace);
+        },
+        "xread" => {
+            return do_xread(command, keyspace);
+        },
+        "xgroup" => {
+            return do_xgroup(command, keyspace);
+        },
+        "xreadgroup" => {
+            return do_xreadgroup(command, keyspace);
+        },
+        "xack" => {
+            return do_xack(command, keyspace);
+        },
+        "xpending" => {
+            return do_xpending(command, keyspace);
+        },
+        "dump" => {
+            return do_dump(command, keyspace);
+        },
+        "restore" => {
+            return do_restore(command, keyspace);
+        },
+        _ => {
+            let out = out_err(1, "Unknown command");
+            return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    }
+}
+
+// ping [message]
+fn do_ping(command: &[Vec<u8>]) -> Result<Response,Errno> {
+    let out = match command.get(1) {
+        Some(message) => out_str(message),
+        None => out_str(b"PONG"),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn do_keys(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let keys = keyspace.keys(now_ms());
+    let out;
+    if keys.is_empty() {
+        out = out_nil();
+    } else {
+        out = out_arr(keys);
+    }
+    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out });
+}
+
+// dbsize, the number of keys that have not expired
+fn do_dbsize(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let count = keyspace.len(now_ms());
+    let out = out_int(i64::try_from(count).unwrap());
+    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out });
+}
+
+fn do_get(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Str(value) => out_str(value),
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+
+    return Ok(Response { length: u32::try_from(out.len()).unwrap(), message: out });
+}
+
+// set key value [EX seconds | PX milliseconds | EXAT unix-seconds | PXAT unix-milliseconds]
+fn do_set(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let mut deadline: Option<u64> = None;
+    let mut i = 3;
+    while i < command.len() {
+        // (unit, relative to now)
+        let (unit, relative) = match command[i].to_ascii_lowercase().as_slice() {
+            b"ex" => (1000, true),
+            b"px" => (1, true),
+            b"exat" => (1000, false),
+            b"pxat" => (1, false),
+            _ => {
+                let out = out_err(4, "Syntax error");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        };
+        let amount = match command.get(i + 1).and_then(|a| parse_int(a)) {
+            Some(a) if a > 0 => a,
+            _ => {
+                let out = out_err(4, "Invalid expire time");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        };
+        match amount.checked_mul(unit) {
+            Some(ms) if relative => {
+                deadline = Some(now_ms().saturating_add(ms as u64));
+            },
+            Some(ms) => {
+                deadline = Some(ms as u64);
+            },
+            None => {
+                let out = out_err(4, "Invalid expire time");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        }
+        i += 2;
+    }
+    match deadline {
+        Some(deadline) => {
+            keyspace.set_with_deadline(&command[1], Value::Str(command[2].clone()), deadline);
+        },
+        None => {
+            keyspace.set(&command[1], Value::Str(command[2].clone()));
+        }
+    }
+    let out = out_nil();
+    Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out})
+}
+
+fn do_del(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    // a deleted string is returned, other types only report the deletion
+    let out = match keyspace.delete(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Str(value) => out_str(value),
+            _ => out_int(1),
+        },
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// incr key / decr key / incrby key delta / decrby key delta, `sign` is -1
+// for the decrementing ones. The string is read as an i64, a missing key as
+// 0, and the new value is returned. The key keeps its deadline.
+fn do_incrby(command: &[Vec<u8>], keyspace: &mut Keyspace, sign: i64) -> Result<Response,Errno> {
+    let by = command[0].ends_with(b"by");
+    let arity = if by { 3 } else { 2 };
+    if command.len() < arity {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > arity {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let delta = if by { parse_int(&command[2]) } else { Some(1) };
+    let delta = match delta {
+        Some(delta) => delta,
+        None => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let delta = match delta.checked_mul(sign) {
+        Some(delta) => delta,
+        None => {
+            let out = out_err(14, "Increment or decrement would overflow");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get_mut(&command[1], now_ms()) {
+        Some(entry) => match &mut entry.value {
+            Value::Str(value) => match parse_int(value).map(|current| current.checked_add(delta)) {
+                Some(Some(next)) => {
+                    *value = next.to_string().into_bytes();
+                    out_int(next)
+                },
+                Some(None) => out_err(14, "Increment or decrement would overflow"),
+                None => out_err(4, "Value is not an integer or out of range"),
+            },
+            _ => out_wrongtype(),
+        },
+        None => {
+            keyspace.set(&command[1], Value::Str(delta.to_string().into_bytes()));
+            out_int(delta)
+        }
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// incrbyfloat key delta, like incrby for an f64. The result is stored in
+// the shortest form that reads back as the same number.
+fn do_incrbyfloat(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let delta = match parse_score(&command[2]) {
+        Some(delta) if delta.is_finite() => delta,
+        _ => {
+            let out = out_err(4, "Value is not a valid float");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let current = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Str(value) => parse_score(value).filter(|current| current.is_finite()),
+            _ => {
+                let out = out_wrongtype();
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        },
+        None => Some(0.0),
+    };
+    let out = match current.map(|current| current + delta) {
+        Some(next) if next.is_finite() => {
+            let stored = next.to_string().into_bytes();
+            match keyspace.get_mut(&command[1], now_ms()) {
+                Some(entry) => {
+                    entry.value = Value::Str(stored);
+                },
+                None => {
+                    keyspace.set(&command[1], Value::Str(stored));
+                }
+            }
+            out_dbl(next)
+        },
+        Some(_) => out_err(14, "Increment would produce NaN or Infinity"),
+        None => out_err(4, "Value is not a valid float"),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// expireat key unix-seconds / pexpireat key unix-milliseconds, `unit`
+// converts to milliseconds. expire and pexpire arrive here rewritten to
+// pexpireat by `absolute_form`.
+fn do_expireat(command: &[Vec<u8>], keyspace: &mut Keyspace, unit: i64) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        // absolute_form leaves these alone, the delay must not pass for a unix time
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let at = match parse_int(&command[2]).and_then(|a| a.checked_mul(unit)) {
+        Some(at) => at,
+        None => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let done;
+    if at <= 0 || at as u64 <= now {
+        // a deadline in the past deletes the key right away
+        done = keyspace.delete(&command[1], now).is_some();
+    } else {
+        done = keyspace.set_deadline(&command[1], at as u64, now);
+    }
+    let out = out_int(if done { 1 } else { 0 });
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// Remaining time to live, -2 when the key does not exist and -1 when it has no deadline
+fn do_ttl(command: &[Vec<u8>], keyspace: &mut Keyspace, unit: u64) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    let ttl = match keyspace.get(&command[1], now) {
+        Some(entry) => match entry.expire_at {
+            Some(at) => i64::try_from((at - now + unit / 2) / unit).unwrap_or(i64::MAX),
+            None => -1,
+        },
+        None => -2,
+    };
+    let out = out_int(ttl);
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn do_persist(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let done = keyspace.persist(&command[1], now_ms());
+    let out = out_int(if done { 1 } else { 0 });
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// type key, the kind of value stored at key or "none"
+fn do_type(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => out_str(entry.value.type_name().as_bytes()),
+        None => out_str(b"none"),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// dump key, the value in the format restore takes
+fn do_dump(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => out_str(&snapshot::dump_value(&entry.value)),
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// restore key unix-milliseconds payload [replace], 0 for a key without a
+// deadline. The deadline is absolute so the command replays as it is.
+fn do_restore(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let replace = match command.get(4) {
+        Some(opt) if opt.eq_ignore_ascii_case(b"replace") => true,
+        Some(_) => {
+            let out = out_err(4, "Syntax error");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => false,
+    };
+    let expire_at = match parse_int(&command[2]).and_then(|at| u64::try_from(at).ok()) {
+        Some(at) => at,
+        None => {
+            let out = out_err(4, "Invalid expire time");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let value = match snapshot::restore_value(&command[3]) {
+        Ok(value) => value,
+        Err(_) => {
+            let out = out_err(4, "DUMP payload version or checksum are wrong");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    if !replace && keyspace.contains_key(&command[1], now) {
+        let out = out_err(12, "BUSYKEY Target key name already exists.");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    match expire_at {
+        0 => {
+            keyspace.set(&command[1], value);
+        },
+        at if at <= now => {
+            // expired on the way, like a key that was never there
+            keyspace.delete(&command[1], now);
+        },
+        at => {
+            keyspace.set_with_deadline(&command[1], value, at);
+        }
+    }
+    let out = out_nil();
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// save, writes a snapshot before replying
+fn do_save(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let path = match &state.snapshot_path {
+        Some(path) => path.clone(),
+        None => {
+            let out = out_err(7, "No snapshot file configured");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let data = snapshot::encode(state.keyspace.iter(now));
+    let out = match snapshot::write_snapshot(&path, &data) {
+        Ok(()) => {
+            state.last_save = now;
+            out_nil()
+        },
+        Err(e) => out_err(7, &format!("Error saving snapshot: {}", e)),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// bgsave, copies the keyspace and writes the snapshot from another thread
+fn do_bgsave(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let path = match &state.snapshot_path {
+        Some(path) => path.clone(),
+        None => {
+            let out = out_err(7, "No snapshot file configured");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    if state.bgsave.is_some() {
+        let out = out_err(7, "Background save already in progress");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let entries: Vec<(Vec<u8>, Entry)> = state.keyspace.iter(now_ms())
+        .map(|(key, entry)| (key.to_vec(), entry.clone()))
+        .collect();
+    state.bgsave = Some(thread::spawn(move || {
+        let data = snapshot::encode(entries.iter().map(|(key, entry)| (key.as_slice(), entry)));
+        return snapshot::write_snapshot(&path, &data);
+    }));
+    let out = out_str(b"Background saving started");
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// bgrewriteaof, writes a minimal log of the current keyspace from another
+// thread. Commands logged in the meantime are appended to it once it is done.
+fn do_bgrewriteaof(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match start_aof_rewrite(state) {
+        Ok(()) => out_str(b"Background append only file rewriting started"),
+        Err(message) => out_err(7, message),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn start_aof_rewrite(state: &mut State) -> Result<(), &'static str> {
+    let aof = match state.aof.as_mut() {
+        Some(aof) => aof,
+        None => {
+            return Err("Append only file is not enabled");
+        }
+    };
+    if aof.rewrite_in_progress() {
+        return Err("Background append only file rewriting already in progress");
+    }
+    let entries: Vec<(Vec<u8>, Entry)> = state.keyspace.iter(now_ms())
+        .map(|(key, entry)| (key.to_vec(), entry.clone()))
+        .collect();
+    let path = aof.rewrite_path();
+    aof.start_rewrite();
+    state.aof_rewrite = Some(thread::spawn(move || {
+        return aof::write_rewrite(&path, entries);
+    }));
+    return Ok(());
+}
+
+// replicaof host port / replicaof no one. Naming the current leader again
+// reconnects and resumes from its backlog when possible.
+fn do_replicaof(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command[1].eq_ignore_ascii_case(b"no") && command[2].eq_ignore_ascii_case(b"one") {
+        if state.repl.leader.take().is_some() {
+            state.closing.extend(state.repl.link.take());
+            state.repl.link_state = LinkState::Connect;
+            state.repl.pending = None;
+            state.repl.queued = None;
+            // writes accepted from now on make a new history
+            state.repl.replid = replication::new_replid();
+            println!("Promoted to leader");
+        }
+        let out = out_nil();
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let port = match parse_int(&command[2]).and_then(|p| u16::try_from(p).ok()) {
+        Some(port) => port,
+        None => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let host = String::from_utf8_lossy(&command[1]).into_owned();
+    let addr = match resolve_v4(&format!("{}:{}", host, port)) {
+        Some(addr) => addr,
+        None => {
+            let out = out_err(4, "Invalid leader address");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    state.repl.leader = Some(addr);
+    state.closing.extend(state.repl.link.take());
+    state.repl.link_state = LinkState::Connect;
+    state.repl.pending = None;
+    state.repl.queued = None;
+    state.repl.retry_at = 0;
+    let out = out_nil();
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// info [clients | replication], "name:value" lines about connected clients
+// and the replication state
+fn do_info(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() > 2 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let section = command.get(1).map(|s| s.to_ascii_lowercase());
+    let wants = |name: &[u8]| section.as_deref().is_none_or(|s| s == name);
+    let mut info = String::new();
+    if wants(b"clients") {
+        info.push_str("# Clients\r\n");
+        info.push_str(&format!("blocked_clients:{}\r\n", state.blocking.blocked()));
+    }
+    if !wants(b"replication") {
+        let out = out_str(info.as_bytes());
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let repl = &state.repl;
+    info.push_str("# Replication\r\n");
+    match repl.leader {
+        Some(addr) => {
+            info.push_str("role:slave\r\n");
+            info.push_str(&format!("master_host:{}\r\nmaster_port:{}\r\n", addr.ip(), addr.port()));
+            let status = if repl.link_state == LinkState::Connected { "up" } else { "down" };
+            info.push_str(&format!("master_link_status:{}\r\n", status));
+        },
+        None => {
+            info.push_str("role:master\r\n");
+        }
+    }
+    info.push_str(&format!("connected_slaves:{}\r\n", repl.replicas.len()));
+    info.push_str(&format!("master_replid:{}\r\n", repl.replid));
+    info.push_str(&format!("master_repl_offset:{}\r\n", repl.backlog.offset()));
+    info.push_str(&format!("sync_full:{}\r\n", repl.full_syncs));
+    info.push_str(&format!("sync_partial_ok:{}\r\n", repl.partial_syncs));
+    let out = out_str(info.as_bytes());
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn parse_slot(arg: &[u8]) -> Option<u16> {
+    return parse_int(arg).and_then(|s| u16::try_from(s).ok()).filter(|s| usize::from(*s) < K_SLOTS);
+}
+
+// cluster info | keyslot key | slots | nodes | addslots slot... |
+// delslots slot... | setslot slot node host:port
+fn do_cluster(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let cluster = match state.cluster.as_mut() {
+        Some(cluster) => cluster,
+        None => {
+            let out = out_err(10, "This instance has cluster support disabled");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    // slots that changed hands
+    let mut changed = Vec::new();
+    let out = match command[1].to_ascii_lowercase().as_slice() {
+        b"info" => {
+            let status = if cluster.assigned() == K_SLOTS { "ok" } else { "fail" };
+            let mut info = String::new();
+            info.push_str("cluster_enabled:1\r\n");
+            info.push_str(&format!("cluster_state:{}\r\n", status));
+            info.push_str(&format!("cluster_slots_assigned:{}\r\n", cluster.assigned()));
+            info.push_str(&format!("cluster_known_nodes:{}\r\n", cluster.known_nodes()));
+            out_str(info.as_bytes())
+        },
+        b"keyslot" if command.len() == 3 => {
+            out_int(i64::from(cluster::key_slot(&command[2])))
+        },
+        b"slots" => {
+            let mut items = Vec::new();
+            for (first, last, owner) in cluster.ranges() {
+                let node = out_arr_raw(vec![out_str(owner.ip().to_string().as_bytes()), out_int(i64::from(owner.port()))]);
+                items.push(out_arr_raw(vec![out_int(i64::from(first)), out_int(i64::from(last)), node]));
+            }
+            out_arr_raw(items)
+        },
+        b"nodes" => {
+            // one line per node: address, flags and the slot ranges it serves
+            let ranges = cluster.ranges();
+            let mut nodes = vec![cluster.myself()];
+            for (_, _, owner) in ranges.iter() {
+                if !nodes.contains(owner) {
+                    nodes.push(*owner);
+                }
+            }
+            let mut text = String::new();
+            for node in nodes {
+                text.push_str(&node.to_string());
+                text.push_str(if node == cluster.myself() { " myself,master" } else { " master" });
+                for (first, last, _) in ranges.iter().filter(|r| r.2 == node) {
+                    if first == last {
+                        text.push_str(&format!(" {}", first));
+                    } else {
+                        text.push_str(&format!(" {}-{}", first, last));
+                    }
+                }
+                if node == cluster.myself() {
+                    // slots on the move, written like Redis does
+                    for (slot, other, migrating) in cluster.moving() {
+                        let arrow = if migrating { "->-" } else { "-<-" };
+                        text.push_str(&format!(" [{}{}{}]", slot, arrow, other));
+                    }
+                }
+                text.push('\n');
+            }
+            out_str(text.as_bytes())
+        },
+        b"addslots" | b"delslots" if command.len() > 2 => {
+            let add = command[1].eq_ignore_ascii_case(b"addslots");
+            let mut slots = Vec::new();
+            for arg in command[2..].iter() {
+                match parse_slot(arg) {
+                    Some(slot) if add && cluster.owner(slot).is_some() => {
+                        let out = out_err(4, &format!("Slot {} is already busy", slot));
+                        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+                    },
+                    Some(slot) => {
+                        slots.push(slot);
+                    },
+                    None => {
+                        let out = out_err(4, "Invalid or out of range slot");
+                        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+                    }
+                }
+            }
+            let myself = cluster.myself();
+            for slot in slots.iter() {
+                if add {
+                    cluster.assign(*slot, myself);
+                } else {
+                    cluster.unassign(*slot);
+                }
+            }
+            changed = slots;
+            out_nil()
+        },
+        b"setslot" | b"countkeysinslot" | b"getkeysinslot" | b"migrate" if command.len() > 2 => {
+            let slot = match parse_slot(&command[2]) {
+                Some(slot) => slot,
+                None => {
+                    let out = out_err(4, "Invalid or out of range slot");
+                    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+                }
+            };
+            match command[1].to_ascii_lowercase().as_slice() {
+                b"setslot" => {
+                    changed.push(slot);
+                    do_cluster_setslot(command, slot, cluster)
+                },
+                b"countkeysinslot" => {
+                    let now = now_ms();
+                    let count = state.keyspace.iter(now).filter(|(key, _)| cluster::key_slot(key) == slot).count();
+                    out_int(i64::try_from(count).unwrap())
+                },
+                b"getkeysinslot" => match command.get(3).and_then(|c| parse_int(c)).and_then(|c| usize::try_from(c).ok()) {
+                    Some(count) => {
+                        let keys = state.keyspace.iter(now_ms())
+                            .filter(|(key, _)| cluster::key_slot(key) == slot)
+                            .take(count)
+                            .map(|(key, _)| key.to_vec())
+                            .collect();
+                        out_arr(keys)
+                    },
+                    None => out_err(4, "Value is not an integer or out of range"),
+                },
+                _ => {
+                    let batch = match command.get(3).map(|b| parse_int(b).and_then(|b| usize::try_from(b).ok())) {
+                        Some(Some(batch)) if batch > 0 => batch,
+                        Some(_) => {
+                            let out = out_err(4, "Value is not an integer or out of range");
+                            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+                        },
+                        None => K_MIGRATE_BATCH,
+                    };
+                    start_migration(slot, batch, state)
+                }
+            }
+        },
+        b"keyslot" | b"addslots" | b"delslots" | b"setslot" | b"countkeysinslot" | b"getkeysinslot" | b"migrate" => {
+            out_err(4, "Syntax error")
+        },
+        _ => out_err(1, "Unknown cluster subcommand"),
+    };
+    wake_slots(&changed, state);
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// Lets connections blocked on keys of the slots try again, `serve_blocked`
+// redirects the ones whose slot is served elsewhere now
+fn wake_slots(slots: &[u16], state: &mut State) {
+    if slots.is_empty() {
+        return;
+    }
+    for key in state.blocking.keys() {
+        if slots.contains(&cluster::key_slot(&key)) {
+            state.blocking.touch(&key);
+        }
+    }
+}
+
+// cluster setslot slot node|migrating|importing host:port / cluster setslot slot stable
+fn do_cluster_setslot(command: &[Vec<u8>], slot: u16, cluster: &mut Cluster) -> Vec<u8> {
+    let action = command.get(3).map(|a| a.to_ascii_lowercase()).unwrap_or_default();
+    if action == b"stable" && command.len() == 4 {
+        cluster.stable(slot);
+        return out_nil();
+    }
+    if command.len() != 5 {
+        return out_err(4, "Syntax error");
+    }
+    let node = match resolve_v4(&String::from_utf8_lossy(&command[4])) {
+        Some(node) => node,
+        None => {
+            return out_err(4, "Invalid node address");
+        }
+    };
+    match action.as_slice() {
+        b"node" => {
+            cluster.assign(slot, node);
+        },
+        b"migrating" if !cluster.is_mine(slot) => {
+            return out_err(10, &format!("I'm not the owner of hash slot {}", slot));
+        },
+        b"migrating" => {
+            cluster.set_migrating(slot, node);
+        },
+        b"importing" if cluster.is_mine(slot) => {
+            return out_err(10, &format!("I'm already the owner of hash slot {}", slot));
+        },
+        b"importing" => {
+            cluster.set_importing(slot, node);
+        },
+        _ => {
+            return out_err(4, "Syntax error");
+        }
+    }
+    return out_nil();
+}
+
+// cluster migrate slot [batch], moves the keys of a migrating slot to its
+// target. The event loop sends one batch per round so clients are served
+// in between. A round waits for the target's replies, so a slow target
+// stalls every connection for up to K_MIGRATE_TIMEOUT_MS each round.
+fn start_migration(slot: u16, batch: usize, state: &mut State) -> Vec<u8> {
+    let target = match state.cluster.as_ref().and_then(|cluster| cluster.migrating(slot)) {
+        Some(target) => target,
+        None => {
+            return out_err(10, &format!("Hash slot {} is not migrating", slot));
+        }
+    };
+    if state.migration.is_some() {
+        return out_err(10, "A slot migration is already in progress");
+    }
+    let client = match migration_client(target) {
+        Ok(client) => client,
+        Err(e) => {
+            return out_err(10, &format!("Can't reach {}: {}", target, e));
+        }
+    };
+    let keys = state.keyspace.iter(now_ms())
+        .filter(|(key, _)| cluster::key_slot(key) == slot)
+        .map(|(key, _)| key.to_vec())
+        .collect();
+    state.migration = Some(Migration { slot: slot, target: target, keys: keys, batch: batch, client: client, next_at: 0 });
+    return out_str(b"Slot migration started");
+}
+
+fn migration_client(target: SocketAddr) -> Result<FerdisClient, ClientError> {
+    let mut client = FerdisClient::connect(&target.to_string())?;
+    client.set_timeout(Some(Duration::from_millis(K_MIGRATE_TIMEOUT_MS)))?;
+    return Ok(client);
+}
+
+// Keeps a migration whose batch did not go through for a later try, over a
+// fresh connection since replies to the failed one may still be on the way
+fn retry_migration(mut job: Migration, moving: Vec<Vec<u8>>, state: &mut State) {
+    job.keys.extend(moving);
+    match migration_client(job.target) {
+        Ok(client) => {
+            job.client = client;
+        },
+        Err(e) => {
+            println!("Can't reach {}: {}", job.target, e);
+        }
+    }
+    job.next_at = now_ms() + K_MIGRATE_RETRY_MS;
+    state.migration = Some(job);
+}
+
+// Moves the next batch of a running migration. A key is deleted here only
+// once the target has it, and no command runs in between, so every version
+// of a key is either still here or already there. Restores replace what the
+// target has, which is an older copy left by a batch that failed halfway.
+fn migrate_batch(state: &mut State) {
+    let mut job = match state.migration.take() {
+        Some(job) => job,
+        None => {
+            return;
+        }
+    };
+    let still_migrating = state.cluster.as_ref().and_then(|cluster| cluster.migrating(job.slot)) == Some(job.target);
+    if !still_migrating {
+        println!("Migration of slot {} stopped", job.slot);
+        return;
+    }
+    let now = now_ms();
+    if now < job.next_at {
+        state.migration = Some(job);
+        return;
+    }
+    let mut commands: Vec<Vec<Vec<u8>>> = Vec::new();
+    let mut moving: Vec<Vec<u8>> = Vec::new();
+    let mut bytes = 0;
+    while moving.len() < job.batch && bytes < K_MIGRATE_BATCH_BYTES {
+        let key = match job.keys.pop() {
+            Some(key) => key,
+            None => break,
+        };
+        // keys deleted or expired since the migration started are skipped
+        if let Some(entry) = state.keyspace.get(&key, now) {
+            let expire_at = entry.expire_at.unwrap_or(0).to_string().into_bytes();
+            let payload = snapshot::dump_value(&entry.value);
+            bytes += key.len() + payload.len();
+            commands.push(vec![b"asking".to_vec()]);
+            commands.push(vec![b"restore".to_vec(), key.clone(), expire_at, payload, b"replace".to_vec()]);
+            moving.push(key);
+        }
+    }
+    if !moving.is_empty() {
+        let replies = match job.client.pipeline(&commands) {
+            Ok(replies) => replies,
+            Err(e) => {
+                println!("Error {} while migrating slot {} to {}, retrying", e, job.slot, job.target);
+                retry_migration(job, moving, state);
+                return;
+            }
+        };
+        let mut failed = false;
+        for (key, reply) in moving.into_iter().zip(replies.chunks(2)) {
+            if reply[1] == Reply::Nil {
+                state.keyspace.delete(&key, now);
+                // its waiters are sent to the target
+                state.blocking.touch(&key);
+                propagate(&[b"del".to_vec(), key], state);
+            } else {
+                println!("Target refused {}: {}", String::from_utf8_lossy(&key), reply[1]);
+                failed = true;
+            }
+        }
+        if failed {
+            println!("Migration of slot {} to {} aborted", job.slot, job.target);
+            return;
+        }
+    }
+    if !job.keys.is_empty() {
+        state.migration = Some(job);
+        return;
+    }
+    // the last key is over: the target takes the slot, then this node
+    // sends clients there
+    let slot = job.slot.to_string();
+    let target = job.target.to_string();
+    match job.client.command(&["cluster", "setslot", &slot, "node", &target]) {
+        Ok(Reply::Nil) => {
+            if let Some(cluster) = state.cluster.as_mut() {
+                cluster.assign(job.slot, job.target);
+            }
+            wake_slots(&[job.slot], state);
+            println!("Slot {} migrated to {}", job.slot, job.target);
+        },
+        other => {
+            println!("Target did not take slot {}: {:?}, retrying", job.slot, other);
+            retry_migration(job, Vec::new(), state);
+        }
+    }
+}
+
+// psync replid offset, turns the connection into a follower's. Both answers
+// and the stream are queued on the connection right away.
+fn do_psync(command: &[Vec<u8>], conn: &mut Conn, state: &mut State) {
+    let offset = command.get(2).and_then(|a| parse_int(a)).and_then(|o| u64::try_from(o).ok());
+    let missed = match (command.get(1), offset) {
+        (Some(replid), Some(offset)) if replid.as_slice() == state.repl.replid.as_bytes() => {
+            state.repl.backlog.since(offset)
+        },
+        _ => None,
+    };
+    match missed {
+        Some(missed) => {
+            state.repl.partial_syncs += 1;
+            let line = format!("CONTINUE {}", state.repl.replid);
+            push_value(conn, &out_str(line.as_bytes()));
+            conn.wbuf.extend_from_slice(&missed);
+        },
+        None => {
+            state.repl.full_syncs += 1;
+            let line = format!("FULLRESYNC {} {}", state.repl.replid, state.repl.backlog.offset());
+            push_value(conn, &out_str(line.as_bytes()));
+            // writes after this point reach the follower through the stream
+            let data = snapshot::encode(state.keyspace.iter(now_ms()));
+            push_value(conn, &out_str(&data));
+        }
+    }
+    println!("Follower attached, {} full and {} partial syncs so far", state.repl.full_syncs, state.repl.partial_syncs);
+    conn.kind = ConnKind::Replica;
+    conn.max_msg = usize::MAX;
+    state.repl.replicas.push(conn.fd);
+}
+
+// Queues a serialized value that does not answer a request, framed for the
+// connection's protocol. RESP3 marks it as a push.
+fn push_value(conn: &mut Conn, value: &[u8]) {
+    match conn.proto {
+        Protocol::Ferdis => {
+            conn.wbuf.extend_from_slice(&u32::try_from(value.len()).unwrap().to_le_bytes());
+            conn.wbuf.extend_from_slice(value);
+        },
+        Protocol::Resp2 => {
+            conn.wbuf.extend_from_slice(&resp::encode_reply(value, false));
+        },
+        Protocol::Resp3 => {
+            conn.wbuf.extend_from_slice(&resp::encode_push(value));
+        }
+    }
+}
+
+// Replaces the keyspace with the leader's snapshot
+// Runs a write from the leader on a follower
+fn apply_replicated(command: &[Vec<u8>], state: &mut State) {
+    match execute(command, state) {
+        Ok(_) => {
+            // readers blocked on this follower wait for the leader's writes
+            for key in command_keys(command) {
+                state.blocking.touch(key);
+            }
+        },
+        Err(e) => {
+            println!("Error {} while applying a replicated command", e);
+        }
+    }
+}
+
+fn load_full_sync(entries: Vec<(Vec<u8>, Entry)>, replid: String, offset: u64, state: &mut State) {
+    state.keyspace.clear();
+    state.repl.queued = None;
+    let count = entries.len();
+    load_entries(&mut state.keyspace, entries, now_ms());
+    state.repl.replid = replid;
+    state.repl.backlog.reset(offset);
+    // our own followers have a history that no longer exists
+    let replicas: Vec<RawFd> = state.repl.replicas.drain(..).collect();
+    state.closing.extend(replicas);
+    if state.aof.is_some() {
+        if let Err(e) = start_aof_rewrite(state) {
+            println!("Error {} while rewriting the AOF after a full sync", e);
+        }
+    }
+    println!("Full sync with {} keys at offset {}", count, offset);
+}
+
+fn load_entries(keyspace: &mut Keyspace, entries: Vec<(Vec<u8>, Entry)>, now: u64) {
+    for (key, entry) in entries {
+        match entry.expire_at {
+            Some(at) if at <= now => {},
+            Some(at) => {
+                keyspace.set_with_deadline(&key, entry.value, at);
+            },
+            None => {
+                keyspace.set(&key, entry.value);
+            }
+        }
+    }
+}
+
+// lastsave, unix time in seconds of the last successful save
+fn do_lastsave(command: &[Vec<u8>], state: &mut State) -> Result<Response,Errno> {
+    if command.len() > 1 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = out_int(i64::try_from(state.last_save / 1000).unwrap());
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    return std::str::from_utf8(arg).ok()?.parse::<i64>().ok();
+}
+
+fn parse_score(arg: &[u8]) -> Option<f64> {
+    match std::str::from_utf8(arg).ok()?.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Some(score),
+        _ => None,
+    }
+}
+
+// zadd key score name
+fn do_zadd(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let score = match parse_score(&command[2]) {
+        Some(score) => score,
+        None => {
+            let out = out_err(4, "Value is not a valid float");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match &mut keyspace.get_or_insert(&command[1], now_ms(), || Value::ZSet(ZSet::new())).value {
+        Value::ZSet(zset) => out_int(if zset.add(&command[3], score) { 1 } else { 0 }),
+        _ => out_wrongtype(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// zrem key name
+fn do_zrem(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::ZSet(zset) => {
+                let removed = zset.remove(&command[2]);
+                now_empty = zset.is_empty();
+                out_int(if removed { 1 } else { 0 })
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    // an empty sorted set does not keep its key around
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// zscore key name
+fn do_zscore(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::ZSet(zset) => match zset.score(&command[2]) {
+                Some(score) => out_dbl(score),
+                None => out_nil(),
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// zrank key name, the 0-based position by ascending score
+fn do_zrank(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::ZSet(zset) => match zset.rank(&command[2]) {
+                Some(rank) => out_int(i64::try_from(rank).unwrap()),
+                None => out_nil(),
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// zrange key start stop [withscores]
+fn do_zrange(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let with_scores = match command.get(4) {
+        Some(opt) if opt.eq_ignore_ascii_case(b"withscores") => true,
+        Some(_) => {
+            let out = out_err(4, "Syntax error");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => false,
+    };
+    let (start, stop) = match (parse_int(&command[2]), parse_int(&command[3])) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::ZSet(zset) => {
+                let mut items = Vec::new();
+                for node in zset.range(start, stop) {
+                    items.push(out_str(&node.name));
+                    if with_scores {
+                        items.push(out_dbl(node.score));
+                    }
+                }
+                out_arr_raw(items)
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// zquery key score name offset limit, name and score pairs starting from
+// the first node >= (score, name)
+fn do_zquery(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 6 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let score = match parse_score(&command[2]) {
+        Some(score) => score,
+        None => {
+            let out = out_err(4, "Value is not a valid float");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let (offset, limit) = match (parse_int(&command[4]), parse_int(&command[5]).and_then(|l| usize::try_from(l).ok())) {
+        (Some(offset), Some(limit)) => (offset, limit),
+        _ => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::ZSet(zset) => {
+                let mut items = Vec::new();
+                for node in zset.query(score, &command[3], offset, limit) {
+                    items.push(out_str(&node.name));
+                    items.push(out_dbl(node.score));
+                }
+                out_arr_raw(items)
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// lpush key item... / rpush key item..., adds the items one after the other
+// at the head or tail and returns the new length
+fn do_push(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let front = command[0] == b"lpush";
+    let out = match &mut keyspace.get_or_insert(&command[1], now_ms(), || Value::List(List::new())).value {
+        Value::List(list) => {
+            for item in command[2..].iter() {
+                if front {
+                    list.push_front(item);
+                } else {
+                    list.push_back(item);
+                }
+            }
+            out_int(i64::try_from(list.len()).unwrap())
+        },
+        _ => out_wrongtype(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// lpop key [count] / rpop key [count], one item or, with a count, an array
+// of up to count items. NIL when the key does not exist.
+fn do_pop(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let count = match command.get(2).map(|arg| parse_int(arg)) {
+        Some(Some(count)) if count >= 0 => Some(usize::try_from(count).unwrap()),
+        Some(_) => {
+            let out = out_err(4, "Value is out of range, must be positive");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => None,
+    };
+    let front = command[0] == b"lpop";
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::List(list) => {
+                let mut items = Vec::new();
+                while items.len() < count.unwrap_or(1) {
+                    let item = if front { list.pop_front() } else { list.pop_back() };
+                    match item {
+                        Some(item) => items.push(item),
+                        None => break,
+                    }
+                }
+                now_empty = list.is_empty();
+                match count {
+                    Some(_) => out_arr(items),
+                    None => out_str(&items[0]),
+                }
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    // an empty list does not keep its key around
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// blpop key... timeout / brpop key... timeout, pops from the first of the
+// keys that holds a list and returns [key, item]. Here it never waits: NIL
+// means there was nothing, `conn_command` then parks the connection. Inside
+// a transaction or when replayed it answers right away.
+fn do_bpop(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if parse_timeout(command.last().unwrap()).is_none() {
+        let out = out_err(4, "Timeout is not a float or out of range");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let front = command[0] == b"blpop";
+    let now = now_ms();
+    for key in command[1..command.len() - 1].iter() {
+        match keyspace.get(key, now).map(|entry| &entry.value) {
+            Some(Value::List(_)) => {
+                let item = pop_list(keyspace, key, front, now).unwrap();
+                let out = out_arr(vec![key.clone(), item]);
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            },
+            Some(_) => {
+                let out = out_wrongtype();
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            },
+            None => {}
+        }
+    }
+    let out = out_nil();
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// Seconds to wait, fractions allowed, 0 waits forever
+fn parse_timeout(arg: &[u8]) -> Option<f64> {
+    return parse_score(arg).filter(|timeout| *timeout >= 0.0 && *timeout < 1e10);
+}
+
+// Pops one item of the list at `key`, an emptied list loses its key. None
+// when the key holds no list.
+fn pop_list(keyspace: &mut Keyspace, key: &[u8], front: bool, now: u64) -> Option<Vec<u8>> {
+    let list = match &mut keyspace.get_mut(key, now)?.value {
+        Value::List(list) => list,
+        _ => {
+            return None;
+        }
+    };
+    let item = if front { list.pop_front() } else { list.pop_back() };
+    if list.is_empty() {
+        keyspace.delete(key, now);
+    }
+    return item;
+}
+
+// llen key
+fn do_llen(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::List(list) => out_int(i64::try_from(list.len()).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// lrange key start stop, the items with positions in [start, stop];
+// negative positions count from the end
+fn do_lrange(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let (start, stop) = match (parse_int(&command[2]), parse_int(&command[3])) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::List(list) => out_arr(list.range(start, stop).into_iter().map(|item| item.to_vec()).collect()),
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// lindex key index, negative indexes count from the end
+fn do_lindex(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let index = match parse_int(&command[2]) {
+        Some(index) => index,
+        None => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::List(list) => match list.get(index) {
+                Some(item) => out_str(item),
+                None => out_nil(),
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// ltrim key start stop, keeps only the items with positions in [start, stop]
+fn do_ltrim(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let (start, stop) = match (parse_int(&command[2]), parse_int(&command[3])) {
+        (Some(start), Some(stop)) => (start, stop),
+        _ => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::List(list) => {
+                list.trim(start, stop);
+                now_empty = list.is_empty();
+                out_nil()
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hset key field value [field value...], returns the number of new fields
+fn do_hset(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if !command.len().is_multiple_of(2) {
+        let out = out_err(4, "Fields and values must come in pairs");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match &mut keyspace.get_or_insert(&command[1], now_ms(), || Value::Hash(Hash::new())).value {
+        Value::Hash(hash) => {
+            let mut added = 0;
+            for pair in command[2..].chunks(2) {
+                if hash.insert(&pair[0], &pair[1]) {
+                    added += 1;
+                }
+            }
+            out_int(added)
+        },
+        _ => out_wrongtype(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hget key field
+fn do_hget(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Hash(hash) => match hash.get(&command[2]) {
+                Some(value) => out_str(value),
+                None => out_nil(),
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hdel key field..., returns the number of fields removed
+fn do_hdel(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::Hash(hash) => {
+                let removed = command[2..].iter().filter(|field| hash.remove(field)).count();
+                now_empty = hash.is_empty();
+                out_int(i64::try_from(removed).unwrap())
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    // an empty hash does not keep its key around
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hgetall key, fields and values as one flat array
+fn do_hgetall(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Hash(hash) => {
+                let mut items = Vec::with_capacity(hash.len() * 2);
+                for (field, value) in hash.iter() {
+                    items.push(field.to_vec());
+                    items.push(value.to_vec());
+                }
+                out_arr(items)
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hincrby key field delta, like incrby for one field
+fn do_hincrby(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let delta = match parse_int(&command[3]) {
+        Some(delta) => delta,
+        None => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let current = match keyspace.get(&command[1], now).map(|entry| &entry.value) {
+        Some(Value::Hash(hash)) => hash.get(&command[2]).map(parse_int),
+        Some(_) => {
+            let out = out_wrongtype();
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => None,
+    };
+    let out = match current.unwrap_or(Some(0)).map(|current| current.checked_add(delta)) {
+        Some(Some(next)) => {
+            if let Value::Hash(hash) = &mut keyspace.get_or_insert(&command[1], now, || Value::Hash(Hash::new())).value {
+                hash.insert(&command[2], next.to_string().as_bytes());
+            }
+            out_int(next)
+        },
+        Some(None) => out_err(14, "Increment or decrement would overflow"),
+        None => out_err(4, "Hash value is not an integer"),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// hlen key
+fn do_hlen(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Hash(hash) => out_int(i64::try_from(hash.len()).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// sadd key member..., returns the number of new members
+fn do_sadd(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match &mut keyspace.get_or_insert(&command[1], now_ms(), || Value::Set(Set::new())).value {
+        Value::Set(set) => {
+            let added = command[2..].iter().filter(|member| set.insert(member)).count();
+            out_int(i64::try_from(added).unwrap())
+        },
+        _ => out_wrongtype(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// srem key member..., returns the number of members removed
+fn do_srem(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::Set(set) => {
+                let removed = command[2..].iter().filter(|member| set.remove(member)).count();
+                now_empty = set.is_empty();
+                out_int(i64::try_from(removed).unwrap())
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    // an empty set does not keep its key around
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// sismember key member, 1 when the member is in the set
+fn do_sismember(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Set(set) => out_int(i64::from(set.contains(&command[2]))),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// smembers key, in no particular order
+fn do_smembers(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Set(set) => out_arr(set.iter().map(|member| member.to_vec()).collect()),
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// scard key
+fn do_scard(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Set(set) => out_int(i64::try_from(set.len()).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// Intersection ("inter"), union or difference of the sets at `keys`, a
+// missing key counts as an empty set. Err holds the reply when one of the
+// keys holds something else.
+fn set_algebra(op: &[u8], keys: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Set, Vec<u8>> {
+    let now = now_ms();
+    // every key is checked before any work, like the other multi key commands
+    for key in keys {
+        if keyspace.get(key, now).is_some_and(|entry| !matches!(entry.value, Value::Set(_))) {
+            return Err(out_wrongtype());
+        }
+    }
+    let mut out = match keyspace.get(&keys[0], now).map(|entry| &entry.value) {
+        Some(Value::Set(set)) => set.clone(),
+        _ => Set::new(),
+    };
+    for key in keys[1..].iter() {
+        let other = match keyspace.get(key, now).map(|entry| &entry.value) {
+            Some(Value::Set(set)) => Some(set),
+            _ => None,
+        };
+        match (op, other) {
+            (b"inter", None) => {
+                return Ok(Set::new());
+            },
+            (b"inter", Some(other)) => {
+                let gone: Vec<Vec<u8>> = out.iter().filter(|m| !other.contains(m)).map(|m| m.to_vec()).collect();
+                for member in gone {
+                    out.remove(&member);
+                }
+            },
+            (b"diff", Some(other)) => {
+                let gone: Vec<Vec<u8>> = out.iter().filter(|m| other.contains(m)).map(|m| m.to_vec()).collect();
+                for member in gone {
+                    out.remove(&member);
+                }
+            },
+            (b"union", Some(other)) => {
+                for member in other.iter() {
+                    out.insert(member);
+                }
+            },
+            _ => {}
+        }
+    }
+    return Ok(out);
+}
+
+// sinter key... / sunion key... / sdiff key..., sdiff takes the members of
+// the first set that are in none of the others
+fn do_salgebra(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match set_algebra(&command[0][1..], &command[1..], keyspace) {
+        Ok(set) => out_arr(set.iter().map(|member| member.to_vec()).collect()),
+        Err(out) => out,
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// sinterstore dest key... and friends, store the result at dest and return
+// its size. An empty result deletes dest.
+fn do_salgebra_store(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let op = &command[0][1..command[0].len() - "store".len()];
+    let out = match set_algebra(op, &command[2..], keyspace) {
+        Ok(set) => {
+            let len = i64::try_from(set.len()).unwrap();
+            if set.is_empty() {
+                keyspace.delete(&command[1], now_ms());
+            } else {
+                keyspace.set(&command[1], Value::Set(set));
+            }
+            out_int(len)
+        },
+        Err(out) => out,
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// srandmember key [count], a random member, or up to count distinct members.
+// A negative count asks for exactly -count members that may repeat, as many
+// as fit in a reply of at most max_msg bytes.
+fn do_srandmember(command: &[Vec<u8>], keyspace: &mut Keyspace, max_msg: usize) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let count = match command.get(2).map(|arg| parse_int(arg)) {
+        Some(Some(count)) => Some(count),
+        Some(None) => {
+            let out = out_err(4, "Value is not an integer or out of range");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => None,
+    };
+    let out = match (keyspace.get(&command[1], now_ms()).map(|entry| &entry.value), count) {
+        (Some(Value::Set(set)), Some(count)) => {
+            let n = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+            if count < 0 && n > max_msg / 8 {
+                // every member takes at least its 8 byte header
+                let out = out_err(6, "Reply too long");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+            out_arr(set.random_members(n, count >= 0).into_iter().map(|member| member.to_vec()).collect())
+        },
+        (Some(Value::Set(set)), None) => out_str(set.random().unwrap()),
+        (Some(_), _) => out_wrongtype(),
+        (None, Some(_)) => out_arr(Vec::new()),
+        (None, None) => out_nil(),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// spop key [count], removes and returns a random member, or up to count of
+// them
+fn do_spop(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 3 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let count = match command.get(2).map(|arg| parse_int(arg)) {
+        Some(Some(count)) if count >= 0 => Some(usize::try_from(count).unwrap()),
+        Some(_) => {
+            let out = out_err(4, "Value is out of range, must be positive");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => None,
+    };
+    let now = now_ms();
+    let mut now_empty = false;
+    let out = match keyspace.get_mut(&command[1], now) {
+        Some(entry) => match &mut entry.value {
+            Value::Set(set) => {
+                let mut members = Vec::new();
+                while members.len() < count.unwrap_or(1) {
+                    match set.pop() {
+                        Some(member) => members.push(member),
+                        None => break,
+                    }
+                }
+                now_empty = set.is_empty();
+                match count {
+                    Some(_) => out_arr(members),
+                    None => out_str(&members[0]),
+                }
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_nil(),
+    };
+    // an empty set does not keep its key around
+    if now_empty {
+        keyspace.delete(&command[1], now);
+    }
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// How xadd and xtrim trim a stream: down to a number of entries, or to the
+// entries from an id on
+enum Trim {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+// MAXLEN|MINID [=|~] threshold starting at `pos`, returns the rule and the
+// position after it. "~" trims exactly like "=".
+fn parse_trim(command: &[Vec<u8>], mut pos: usize) -> Result<(Trim, usize), Vec<u8>> {
+    let by_len = command[pos].eq_ignore_ascii_case(b"maxlen");
+    pos += 1;
+    if command.get(pos).is_some_and(|arg| arg == b"=" || arg == b"~") {
+        pos += 1;
+    }
+    let arg = match command.get(pos) {
+        Some(arg) => arg,
+        None => {
+            return Err(out_err(2, "Insufficient arguments"));
+        }
+    };
+    let trim = if by_len {
+        match parse_int(arg).and_then(|n| usize::try_from(n).ok()) {
+            Some(n) => Trim::MaxLen(n),
+            None => {
+                return Err(out_err(4, "The MAXLEN argument must be >= 0"));
+            }
+        }
+    } else {
+        match StreamId::parse(arg, 0) {
+            Some(id) => Trim::MinId(id),
+            None => {
+                return Err(out_err(4, "Invalid stream ID specified as stream command argument"));
+            }
+        }
+    };
+    return Ok((trim, pos + 1));
+}
+
+fn apply_trim(stream: &mut Stream, trim: &Trim) -> usize {
+    match trim {
+        Trim::MaxLen(n) => stream.trim_len(*n),
+        Trim::MinId(id) => stream.trim_before(*id),
+    }
+}
+
+// The id xadd gives a new entry: "*" takes one from the clock, "ms-*" the
+// next free one in that millisecond, anything else is used as it is. Ids
+// have to grow past `last`, the last id of the stream.
+fn new_stream_id(arg: &[u8], last: StreamId, now: u64) -> Result<StreamId, &'static str> {
+    let invalid = "Invalid stream ID specified as stream command argument";
+    let id = if arg == b"*" {
+        if now > last.ms { Some(StreamId::new(now, 0)) } else { last.next() }
+    } else if let Some(ms) = arg.strip_suffix(b"-*") {
+        let ms = StreamId::parse(ms, 0).filter(|_| !ms.contains(&b'-')).ok_or(invalid)?.ms;
+        if ms > last.ms { Some(StreamId::new(ms, 0)) } else { last.next().filter(|id| id.ms == ms) }
+    } else {
+        Some(StreamId::parse(arg, 0).ok_or(invalid)?)
+    };
+    match id {
+        Some(id) if id > last => Ok(id),
+        _ => Err("The ID specified in XADD is equal or smaller than the target stream top item"),
+    }
+}
+
+// "-" and "+" are the ends of the stream, an id without a sequence number
+// stands for the whole millisecond
+fn parse_range_id(arg: &[u8], start: bool) -> Option<StreamId> {
+    match arg {
+        b"-" => Some(StreamId::MIN),
+        b"+" => Some(StreamId::MAX),
+        _ => StreamId::parse(arg, if start { 0 } else { u64::MAX }),
+    }
+}
+
+// One stream entry as [id, [field, value...]], NIL fields for an entry that
+// is gone
+fn out_entry(id: StreamId, fields: Option<&[Vec<u8>]>) -> Vec<u8> {
+    let fields = match fields {
+        Some(fields) => out_arr(fields.to_vec()),
+        None => out_nil(),
+    };
+    return out_arr_raw(vec![out_str(id.to_string().as_bytes()), fields]);
+}
+
+fn out_nogroup(key: &[u8], group: &[u8]) -> Vec<u8> {
+    let message = format!("NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key), String::from_utf8_lossy(group));
+    return out_err(15, &message);
+}
+
+// xadd key [MAXLEN|MINID [=|~] threshold] id field value [field value...],
+// returns the id of the new entry. Trimming happens after the add.
+fn do_xadd(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 5 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let (trim, pos) = match command[2].to_ascii_lowercase().as_slice() {
+        b"maxlen" | b"minid" => match parse_trim(command, 2) {
+            Ok((trim, pos)) => (Some(trim), pos),
+            Err(out) => {
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        },
+        _ => (None, 2),
+    };
+    if command.len() < pos + 3 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if !(command.len() - pos - 1).is_multiple_of(2) {
+        let out = out_err(4, "Fields and values must come in pairs");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let now = now_ms();
+    let last = match keyspace.get(&command[1], now).map(|entry| &entry.value) {
+        Some(Value::Stream(stream)) => stream.last_id(),
+        Some(_) => {
+            let out = out_wrongtype();
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => StreamId::MIN,
+    };
+    let id = match new_stream_id(&command[pos], last, now) {
+        Ok(id) => id,
+        Err(message) => {
+            let out = out_err(4, message);
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    if let Value::Stream(stream) = &mut keyspace.get_or_insert(&command[1], now, || Value::Stream(Stream::new())).value {
+        stream.add(id, command[pos + 1..].to_vec());
+        if let Some(trim) = trim {
+            apply_trim(stream, &trim);
+        }
+    }
+    let out = out_str(id.to_string().as_bytes());
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xlen key
+fn do_xlen(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 2 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Stream(stream) => out_int(i64::try_from(stream.len()).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xrange key start end [COUNT n], the entries with ids in [start, end]
+fn do_xrange(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 6 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let count = if command.len() == 4 {
+        Some(usize::MAX)
+    } else if command.len() == 6 && command[4].eq_ignore_ascii_case(b"count") {
+        parse_int(&command[5]).and_then(|n| usize::try_from(n).ok())
+    } else {
+        None
+    };
+    let (start, end, count) = match (parse_range_id(&command[2], true), parse_range_id(&command[3], false), count) {
+        (Some(start), Some(end), Some(count)) => (start, end, count),
+        _ => {
+            let out = out_err(4, "Syntax error");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get(&command[1], now_ms()) {
+        Some(entry) => match &entry.value {
+            Value::Stream(stream) => {
+                out_arr_raw(stream.range(start, end, count).into_iter().map(|(id, fields)| out_entry(id, Some(fields))).collect())
+            },
+            _ => out_wrongtype(),
+        },
+        None => out_arr(Vec::new()),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xtrim key MAXLEN|MINID [=|~] threshold, returns the number of entries
+// removed
+fn do_xtrim(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if !command[2].eq_ignore_ascii_case(b"maxlen") && !command[2].eq_ignore_ascii_case(b"minid") {
+        let out = out_err(4, "Syntax error, expected MAXLEN or MINID");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let trim = match parse_trim(command, 2) {
+        Ok((_, pos)) if pos < command.len() => {
+            let out = out_err(3, "Too many arguments");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        Ok((trim, _)) => trim,
+        Err(out) => {
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get_mut(&command[1], now_ms()) {
+        Some(entry) => match &mut entry.value {
+            Value::Stream(stream) => out_int(i64::try_from(apply_trim(stream, &trim)).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// Options of xread and xreadgroup. The keys follow STREAMS at `streams`,
+// then one id per key.
+struct ReadOptions {
+    // group and consumer of xreadgroup
+    group: Option<(Vec<u8>, Vec<u8>)>,
+    count: usize,
+    // milliseconds to wait, 0 forever
+    block: Option<u64>,
+    noack: bool,
+    streams: usize,
+}
+
+fn parse_read_options(command: &[Vec<u8>]) -> Result<ReadOptions, Vec<u8>> {
+    let mut options = ReadOptions { group: None, count: usize::MAX, block: None, noack: false, streams: 0 };
+    let mut pos = 1;
+    if command[0] == b"xreadgroup" {
+        if command.len() < 4 {
+            return Err(out_err(2, "Insufficient arguments"));
+        }
+        if !command[1].eq_ignore_ascii_case(b"group") {
+            return Err(out_err(4, "Syntax error, expected GROUP group consumer"));
+        }
+        options.group = Some((command[2].clone(), command[3].clone()));
+        pos = 4;
+    }
+    while pos < command.len() {
+        match command[pos].to_ascii_lowercase().as_slice() {
+            b"count" | b"block" => {
+                let value = match command.get(pos + 1).and_then(|arg| parse_int(arg)).and_then(|n| u64::try_from(n).ok()) {
+                    Some(value) => value,
+                    None => {
+                        return Err(out_err(4, "Value is not an integer or out of range"));
+                    }
+                };
+                if command[pos].eq_ignore_ascii_case(b"block") {
+                    options.block = Some(value);
+                } else if value > 0 {
+                    // COUNT 0 reads everything, like no COUNT
+                    options.count = usize::try_from(value).unwrap_or(usize::MAX);
+                }
+                pos += 2;
+            },
+            b"noack" if options.group.is_some() => {
+                options.noack = true;
+                pos += 1;
+            },
+            b"streams" => {
+                let rest = command.len() - pos - 1;
+                if rest == 0 || !rest.is_multiple_of(2) {
+                    return Err(out_err(4, "Unbalanced list of streams: for each stream key an id must be given"));
+                }
+                options.streams = pos + 1;
+                return Ok(options);
+            },
+            _ => {
+                return Err(out_err(4, "Syntax error"));
+            }
+        }
+    }
+    return Err(out_err(2, "Insufficient arguments"));
+}
+
+// xread [COUNT n] [BLOCK ms] STREAMS key... id..., the entries after each id
+// as [[key, [entry...]]...] leaving out streams without any, NIL when there
+// are none at all. "$" stands for the last id of the stream. Here it never
+// waits, `conn_command` parks the connection on NIL when BLOCK is given.
+fn do_xread(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let options = match parse_read_options(command) {
+        Ok(options) => options,
+        Err(out) => {
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let n = (command.len() - options.streams) / 2;
+    let mut ids = Vec::with_capacity(n);
+    for arg in command[options.streams + n..].iter() {
+        match StreamId::parse(arg, 0) {
+            Some(id) => ids.push(Some(id)),
+            None if arg == b"$" => ids.push(None),
+            None => {
+                let out = out_err(4, "Invalid stream ID specified as stream command argument");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        }
+    }
+    let now = now_ms();
+    let mut streams = Vec::new();
+    for (key, id) in command[options.streams..options.streams + n].iter().zip(ids) {
+        let stream = match keyspace.get(key, now).map(|entry| &entry.value) {
+            Some(Value::Stream(stream)) => stream,
+            Some(_) => {
+                let out = out_wrongtype();
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            },
+            None => continue,
+        };
+        let entries = stream.after(id.unwrap_or(stream.last_id()), options.count);
+        if !entries.is_empty() {
+            let entries = entries.into_iter().map(|(id, fields)| out_entry(id, Some(fields))).collect();
+            streams.push(out_arr_raw(vec![out_str(key), out_arr_raw(entries)]));
+        }
+    }
+    let out = if streams.is_empty() { out_nil() } else { out_arr_raw(streams) };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xreadgroup GROUP group consumer [COUNT n] [BLOCK ms] [NOACK] STREAMS
+// key... id..., reads as a consumer of the group. ">" hands out entries no
+// consumer of the group got yet and puts them on its pending list, any
+// other id delivers the consumer's own pending entries after it again. NIL
+// when ">" found nothing anywhere, then BLOCK parks the connection.
+fn do_xreadgroup(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 7 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let options = match parse_read_options(command) {
+        Ok(options) => options,
+        Err(out) => {
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let (group, consumer) = options.group.as_ref().unwrap();
+    let n = (command.len() - options.streams) / 2;
+    let keys = &command[options.streams..options.streams + n];
+    let mut ids = Vec::with_capacity(n);
+    for arg in command[options.streams + n..].iter() {
+        match StreamId::parse(arg, 0) {
+            Some(id) => ids.push(Some(id)),
+            None if arg == b">" => ids.push(None),
+            None => {
+                let out = out_err(4, "Invalid stream ID specified as stream command argument");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        }
+    }
+    let now = now_ms();
+    // all keys are checked first, so a failing call changes nothing
+    for key in keys {
+        let out = match keyspace.get(key, now).map(|entry| &entry.value) {
+            Some(Value::Stream(stream)) if stream.group(group).is_some() => continue,
+            Some(Value::Stream(_)) | None => out_nogroup(key, group),
+            Some(_) => out_wrongtype(),
+        };
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let mut streams = Vec::new();
+    let mut found = false;
+    for (key, id) in keys.iter().zip(ids) {
+        let stream = match keyspace.get_mut(key, now).map(|entry| &mut entry.value) {
+            Some(Value::Stream(stream)) => stream,
+            _ => continue,
+        };
+        let entries: Vec<Vec<u8>> = match id {
+            None => {
+                let entries = stream.read_group(group, consumer, options.count, options.noack, now).unwrap();
+                entries.iter().map(|(id, fields)| out_entry(*id, Some(fields))).collect()
+            },
+            Some(after) => {
+                let entries = stream.read_pending(group, consumer, after, options.count, now).unwrap();
+                entries.iter().map(|(id, fields)| out_entry(*id, fields.as_deref())).collect()
+            }
+        };
+        if id.is_some() || !entries.is_empty() {
+            found = true;
+            streams.push(out_arr_raw(vec![out_str(key), out_arr_raw(entries)]));
+        }
+    }
+    let out = if found { out_arr_raw(streams) } else { out_nil() };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xack key group id..., takes entries off the group's pending list and
+// returns how many were on it
+fn do_xack(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let ids: Option<Vec<StreamId>> = command[3..].iter().map(|arg| StreamId::parse(arg, 0)).collect();
+    let ids = match ids {
+        Some(ids) => ids,
+        None => {
+            let out = out_err(4, "Invalid stream ID specified as stream command argument");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let out = match keyspace.get_mut(&command[1], now_ms()) {
+        Some(entry) => match &mut entry.value {
+            Value::Stream(stream) => out_int(i64::try_from(stream.ack(&command[2], &ids).unwrap_or(0)).unwrap()),
+            _ => out_wrongtype(),
+        },
+        None => out_int(0),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xgroup create key group id|$ [MKSTREAM], xgroup setid key group id|$,
+// xgroup destroy key group, xgroup createconsumer key group consumer and
+// xgroup delconsumer key group consumer. "$" is the last id of the stream,
+// a group starting there only gets entries added later.
+fn do_xgroup(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 4 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let sub = command[1].to_ascii_lowercase();
+    let (min, max) = match sub.as_slice() {
+        b"create" => (5, 6),
+        b"setid" | b"createconsumer" | b"delconsumer" => (5, 5),
+        b"destroy" => (4, 4),
+        _ => {
+            let out = out_err(1, "Unknown xgroup subcommand");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    if command.len() < min || command.len() > max {
+        let out = if command.len() < min { out_err(2, "Insufficient arguments") } else { out_err(3, "Too many arguments") };
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let mkstream = match command.get(5) {
+        Some(opt) if opt.eq_ignore_ascii_case(b"mkstream") => true,
+        Some(_) => {
+            let out = out_err(4, "Syntax error");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => false,
+    };
+    // the id of create and setid, None for "$"
+    let mut id = None;
+    if (sub == b"create" || sub == b"setid") && command[4] != b"$" {
+        match StreamId::parse(&command[4], 0) {
+            Some(parsed) => id = Some(parsed),
+            None => {
+                let out = out_err(4, "Invalid stream ID specified as stream command argument");
+                return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+            }
+        }
+    }
+    let (key, group) = (&command[2], &command[3]);
+    let now = now_ms();
+    let entry = if mkstream {
+        Some(keyspace.get_or_insert(key, now, || Value::Stream(Stream::new())))
+    } else {
+        keyspace.get_mut(key, now)
+    };
+    let stream = match entry.map(|entry| &mut entry.value) {
+        Some(Value::Stream(stream)) => stream,
+        Some(_) => {
+            let out = out_wrongtype();
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => {
+            let out = out_err(4, "The XGROUP subcommand requires the key to exist, CREATE takes MKSTREAM for that");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let id = id.unwrap_or(stream.last_id());
+    let out = match sub.as_slice() {
+        b"create" => {
+            if stream.create_group(group, id) {
+                out_nil()
+            } else {
+                out_err(12, "BUSYGROUP Consumer Group name already exists")
+            }
+        },
+        b"setid" => {
+            if stream.set_group_id(group, id) {
+                out_nil()
+            } else {
+                out_nogroup(key, group)
+            }
+        },
+        b"destroy" => out_int(i64::from(stream.destroy_group(group))),
+        b"createconsumer" => match stream.create_consumer(group, &command[4]) {
+            Some(created) => out_int(i64::from(created)),
+            None => out_nogroup(key, group),
+        },
+        _ => match stream.delete_consumer(group, &command[4]) {
+            Some(pending) => out_int(i64::try_from(pending).unwrap()),
+            None => out_nogroup(key, group),
+        },
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+// xpending key group [start end count [consumer]], the group's pending
+// entries as [[id, consumer, idle milliseconds, deliveries]...]
+fn do_xpending(command: &[Vec<u8>], keyspace: &mut Keyspace) -> Result<Response,Errno> {
+    if command.len() < 3 || command.len() == 4 || command.len() == 5 {
+        let out = out_err(2, "Insufficient arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    if command.len() > 7 {
+        let out = out_err(3, "Too many arguments");
+        return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+    }
+    let (start, end, count) = if command.len() == 3 {
+        (Some(StreamId::MIN), Some(StreamId::MAX), Some(usize::MAX))
+    } else {
+        (parse_range_id(&command[3], true), parse_range_id(&command[4], false),
+            parse_int(&command[5]).and_then(|n| usize::try_from(n).ok()))
+    };
+    let (start, end, count) = match (start, end, count) {
+        (Some(start), Some(end), Some(count)) => (start, end, count),
+        _ => {
+            let out = out_err(4, "Syntax error");
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        }
+    };
+    let now = now_ms();
+    let group = match keyspace.get(&command[1], now).map(|entry| &entry.value) {
+        Some(Value::Stream(stream)) => stream.group(&command[2]),
+        Some(_) => {
+            let out = out_wrongtype();
+            return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+        },
+        None => None,
+    };
+    let out = match group {
+        Some(_) if start > end => out_arr(Vec::new()),
+        Some(group) => {
+            let entries = group.pending.range(start..=end)
+                .filter(|(_, pending)| command.get(6).is_none_or(|consumer| pending.consumer == *consumer))
+                .take(count)
+                .map(|(id, pending)| out_arr_raw(vec![
+                    out_str(id.to_string().as_bytes()),
+                    out_str(&pending.consumer),
+                    out_int(i64::try_from(now.saturating_sub(pending.delivered_at)).unwrap_or(i64::MAX)),
+                    out_int(i64::try_from(pending.deliveries).unwrap_or(i64::MAX)),
+                ]))
+                .collect();
+            out_arr_raw(entries)
+        },
+        None => out_nogroup(&command[1], &command[2]),
+    };
+    return Ok(Response {length: u32::try_from(out.len()).unwrap(), message: out});
+}
+
+fn out_nil() -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::NIL as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    return out;
+}
+
+fn out_str(val: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::STR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    (val.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    out.extend_from_slice(val);
+    return out;
+}
+
+fn out_int(val: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::INT as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    val.to_le_bytes().iter().for_each(|b| out.push(*b));
+    return out;
+}
+
+fn out_dbl(val: f64) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::DBL as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    val.to_le_bytes().iter().for_each(|b| out.push(*b));
+    return out;
+}
+
+fn out_err(code: u32, message: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::ERR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    code.to_le_bytes().iter().for_each(|b| out.push(*b));
+    (message.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    message.bytes().for_each(|b| out.push(b));
+    return out;
+}
+
+fn out_wrongtype() -> Vec<u8> {
+    return out_err(5, "WRONGTYPE Operation against a key holding the wrong kind of value");
+}
+
+fn out_arr(values: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::ARR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    (values.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    for val in values {
+        out_str(&val).iter().for_each(|b| out.push(*b));
+    }
+    return out;
+}
+
+// Array of already serialized values, for replies that mix types
+fn out_arr_raw(items: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    (ResType::ARR as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    (items.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+    for item in items {
+        out.extend_from_slice(&item);
+    }
+    return out;
+}
+
+// A request is a list of binary strings:
+// | nstr (u32) | len (u32) | bytes | len (u32) | bytes | ...
+pub(crate) fn parse_request(req_buf: &[u8]) -> Result<Vec<Vec<u8>>, Errno> {
+    let nstr = read_u32(req_buf, 0).ok_or(Errno::EPROTO)?;
+    let mut args: Vec<Vec<u8>> = Vec::new();
+    let mut pos = 4;
+    for _ in 0..nstr {
+        let len = read_u32(req_buf, pos).ok_or(Errno::EPROTO)?;
+        let start = pos + 4;
+        let end = start + usize::try_from(len).unwrap();
+        if end > req_buf.len() {
+            return Err(Errno::EPROTO);
+        }
+        args.push(req_buf[start..end].to_vec());
+        pos = end;
+    }
+    if pos != req_buf.len() {
+        // trailing garbage
+        return Err(Errno::EPROTO);
+    }
+    Ok(args)
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    let bytes = buf.get(pos..pos + 4)?;
+    return Some(u32::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+fn try_one_request(conn: &mut Conn, state: &mut State) -> bool {
+    if state.blocking.is_blocked(conn.fd) {
+        // later requests wait until the blocking one is answered
+        return false;
+    }
+    if conn.proto != Protocol::Ferdis {
+        return try_one_resp_request(conn, state);
+    }
+    let start = conn.rbuf_read;
+    if conn.rbuf.len() - start < 4 {
+        // not enough data in the buffer, retry
+        return false;
+    }
+
+    let mut len_buf: [u8; 4] = [0;4];
+    len_buf.copy_from_slice(&conn.rbuf[start..start + 4]);
+    let length = usize::try_from(u32::from_le_bytes(len_buf)).unwrap();
+
+    if length > conn.max_msg {
+        println!("Message too long");
+        conn.state = ConnState::END;
+        return false;
+    }
+
+    if 4 + length > conn.rbuf.len() - start {
+        // not enough data in the buffer, retry
+        return false;
+    }
+
+    // mark the request as consumed, the buffer is compacted once all
+    // complete requests are handled
+    conn.rbuf_read += 4 + length;
+    match conn.kind {
+        ConnKind::Leader => {
+            return leader_frame(conn, start, length, state);
+        },
+        ConnKind::Replica => {
+            // followers have nothing to say once the stream is running
+            return true;
+        },
+        ConnKind::Client => {}
+    }
+    let command = match parse_request(&conn.rbuf[start + 4..start + 4 + length]) {
+        Ok(command) => command,
+        Err(_) => {
+            println!("Could not do request");
+            conn.state = ConnState::END;
+            return false;
+        }
+    };
+    if command.first().is_some_and(|name| name.eq_ignore_ascii_case(b"psync")) {
+        do_psync(&command, conn, state);
+        conn.state = ConnState::RES;
+        return true;
+    }
+    if is_subscription_command(&command) && conn.queued.is_none() {
+        do_subscriptions(conn, &command, state);
+        conn.state = ConnState::RES;
+        return true;
+    }
+
+    // get one request and generate a response
+    match conn_command(conn, command, state) {
+        Ok(_) if state.blocking.is_blocked(conn.fd) => {
+            return false;
+        },
+        Ok(mut res) => {
+            if res.message.len() > conn.max_msg {
+                res.message = out_err(6, "Reply too long");
+                res.length = u32::try_from(res.message.len()).unwrap();
+            }
+            conn.wbuf.extend_from_slice(&res.length.to_le_bytes());
+            conn.wbuf.extend_from_slice(&res.message);
+        },
+        Err(_) => {
+            println!("Could not do request");
+            conn.state = ConnState::END;
+            return false;
+        }
+    }
+
+
+    // change state
+    conn.state = ConnState::RES;
+
+    return true;
+}
+
+// One frame on the link to the leader: the answer to psync, the snapshot of
+// a full resync, then the stream of write commands
+fn leader_frame(conn: &mut Conn, start: usize, length: usize, state: &mut State) -> bool {
+    let frame = &conn.rbuf[start + 4..start + 4 + length];
+    let ok = match state.repl.link_state {
+        LinkState::Handshake => match deserialize_response(frame) {
+            Ok(Reply::Str(line)) => {
+                let line = String::from_utf8_lossy(&line).into_owned();
+                let words: Vec<&str> = line.split(' ').collect();
+                match words.as_slice() {
+                    ["FULLRESYNC", replid, offset] => match offset.parse::<u64>() {
+                        Ok(offset) => {
+                            state.repl.pending = Some((replid.to_string(), offset));
+                            state.repl.link_state = LinkState::Snapshot;
+                            true
+                        },
+                        Err(_) => false,
+                    },
+                    ["CONTINUE", replid] => {
+                        println!("Resuming replication at offset {}", state.repl.backlog.offset());
+                        state.repl.replid = replid.to_string();
+                        state.repl.link_state = LinkState::Connected;
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            _ => false,
+        },
+        LinkState::Snapshot => {
+            let entries = match deserialize_response(frame) {
+                Ok(Reply::Str(data)) => snapshot::decode(&data).ok(),
+                _ => None,
+            };
+            match (entries, state.repl.pending.take()) {
+                (Some(entries), Some((replid, offset))) => {
+                    load_full_sync(entries, replid, offset, state);
+                    state.repl.link_state = LinkState::Connected;
+                    true
+                },
+                _ => false,
+            }
+        },
+        LinkState::Connected => match parse_request(frame) {
+            Ok(command) if !command.is_empty() => {
+                // the leader only sends writes that succeeded, so they are
+                // passed on as they are and the offsets stay in step. A
+                // transaction is applied and passed on once its exec
+                // arrives, a link lost before that resends it from multi.
+                if command == [b"multi"] {
+                    state.repl.queued = Some(Vec::new());
+                } else if command == [b"exec"] {
+                    if let Some(queued) = state.repl.queued.take() {
+                        for command in queued.iter() {
+                            apply_replicated(command, state);
                         }
-                        if let Err(e) = poll(&mut poll_args, 1000) {
-                            println!("Error {} while polling file descriptors", e);
-                            return;
+                        propagate(&[b"multi".to_vec()], state);
+                        for command in queued.iter() {
+                            propagate(command, state);
                         }
+                        propagate(&command, state);
+                    }
+                } else if let Some(queued) = state.repl.queued.as_mut() {
+                    queued.push(command);
+                } else {
+                    apply_replicated(&command, state);
+                    propagate(&command, state);
+                }
+                true
+            },
+            _ => false,
+        },
+        LinkState::Connect => false,
+    };
+    if !ok {
+        println!("Unexpected data from the leader");
+        conn.state = ConnState::END;
+    }
+    return ok;
+}
 
-                        for poll_fd in poll_args.iter() {
-                            let conn = fd2conn.get_mut(&poll_fd.as_raw_fd()).unwrap();
-                            connection_io(conn);
-                            if conn.state == ConnState::END {
-                                fd2conn.remove(&poll_fd.as_raw_fd());
-                                let _ = close(poll_fd.as_raw_fd());
-                            }
-                        }
+fn try_one_resp_request(conn: &mut Conn, state: &mut State) -> bool {
+    let start = conn.rbuf_read;
+    let (command, used) = match resp::parse_request(&conn.rbuf[start..], conn.max_msg) {
+        Ok(Some(parsed)) => parsed,
+        Ok(None) => {
+            // not enough data in the buffer, retry
+            if conn.rbuf.len() - start > conn.max_msg {
+                println!("Message too long");
+                conn.state = ConnState::END;
+            }
+            return false;
+        },
+        Err(resp::RespError::Protocol(reason)) => {
+            // tell the client why before hanging up, like Redis does
+            let _ = write(conn.fd, &resp::encode_error(&format!("Protocol error: {}", reason)));
+            conn.state = ConnState::END;
+            return false;
+        }
+    };
+    conn.rbuf_read += used;
+    if command.is_empty() {
+        // blank inline line
+        return true;
+    }
 
-                        if let Some(_) = listening_id.revents() {
-                            let _ = accept_new_conn(&mut fd2conn, fd);
-                        }
+    let reply;
+    if command[0].eq_ignore_ascii_case(b"hello") {
+        match command.get(1).map(|v| v.as_slice()) {
+            None => {
+                reply = resp::hello_reply(conn.proto == Protocol::Resp3);
+            },
+            Some(b"2") => {
+                conn.proto = Protocol::Resp2;
+                reply = resp::hello_reply(false);
+            },
+            Some(b"3") => {
+                conn.proto = Protocol::Resp3;
+                reply = resp::hello_reply(true);
+            },
+            Some(_) => {
+                reply = resp::encode_error("NOPROTO unsupported protocol version");
+            }
+        }
+    } else if is_subscription_command(&command) && conn.queued.is_none() {
+        do_subscriptions(conn, &command, state);
+        conn.state = ConnState::RES;
+        return true;
+    } else {
+        match conn_command(conn, command, state) {
+            Ok(_) if state.blocking.is_blocked(conn.fd) => {
+                return false;
+            },
+            Ok(mut res) => {
+                if res.message.len() > conn.max_msg {
+                    res.message = out_err(6, "Reply too long");
+                }
+                reply = resp::encode_reply(&res.message, conn.proto == Protocol::Resp3);
+            },
+            Err(_) => {
+                println!("Could not do request");
+                conn.state = ConnState::END;
+                return false;
+            }
+        }
+    }
+    conn.wbuf.extend_from_slice(&reply);
+    conn.state = ConnState::RES;
+    return true;
+}
+
+fn state_res(conn: &mut Conn) {
+    while try_flush_buffer(conn) {}
+}
+
+fn try_flush_buffer(conn: &mut Conn) -> bool {
+    loop {
+        // a large reply leaves with several writes, the poll loop calls
+        // back here whenever the socket is writable again
+        match write(conn.fd, &conn.wbuf[conn.wbuf_sent..]) {
+            Ok(rv) => {
+                conn.wbuf_sent += rv;
+                assert!(conn.wbuf_sent <= conn.wbuf.len());
+                if conn.wbuf_sent == conn.wbuf.len() {
+                    conn.state = ConnState::REQ;
+                    conn.wbuf.clear();
+                    conn.wbuf_sent = 0;
+                    return false;
+                }
+                break;
+            },
+            Err(e) => {
+                match e {
+                    Errno::EINTR => {
+                        continue;
+                    },
+                    Errno::EAGAIN => {
+                        return false;
+                    },
+                    _ => {
+                        println!("read() error while flushing buffer");
+                        conn.state = ConnState::END;
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+    return true;
+}
+
+// Poll timeout in milliseconds until the nearest timer, -1 to wait forever
+fn next_timer_ms(state: &State) -> i32 {
+    let mut aof_deadline = state.aof.as_ref().and_then(|aof| aof.next_fsync());
+    if state.bgsave.is_some() || state.aof_rewrite.is_some() {
+        // look for the end of background jobs now and then
+        let check = now_ms() + K_BG_CHECK_MS;
+        aof_deadline = Some(aof_deadline.map_or(check, |at| at.min(check)));
+    }
+    // a running migration moves on in every round, or after a pause once
+    // its target failed
+    let migration = state.migration.as_ref().map(|job| job.next_at.max(now_ms()));
+    let deadline = [state.keyspace.next_deadline(), aof_deadline, state.repl.next_connect(), migration,
+        state.blocking.next_deadline()]
+        .into_iter()
+        .flatten()
+        .min();
+    match deadline {
+        Some(at) => {
+            let wait = at.saturating_sub(now_ms());
+            return i32::try_from(wait).unwrap_or(i32::MAX);
+        },
+        None => {
+            return -1;
+        }
+    }
+}
+
+fn process_timers(state: &mut State) {
+    let now = now_ms();
+    state.keyspace.remove_expired(now);
+    if state.bgsave.as_ref().is_some_and(|job| job.is_finished()) {
+        match state.bgsave.take().unwrap().join() {
+            Ok(Ok(())) => {
+                println!("Background saving terminated with success");
+                state.last_save = now;
+            },
+            Ok(Err(e)) => {
+                println!("Error {} while saving in the background", e);
+            },
+            Err(_) => {
+                println!("Background saving thread panicked");
+            }
+        }
+    }
+    if state.aof_rewrite.as_ref().is_some_and(|job| job.is_finished()) {
+        let res = state.aof_rewrite.take().unwrap().join();
+        if let Some(aof) = state.aof.as_mut() {
+            match res {
+                Ok(Ok(())) => match aof.finish_rewrite() {
+                    Ok(()) => {
+                        println!("Background AOF rewrite finished successfully");
+                    },
+                    Err(e) => {
+                        println!("Error {} while switching to the rewritten AOF", e);
                     }
                 },
+                Ok(Err(e)) => {
+                    println!("Error {} while rewriting the AOF", e);
+                    aof.abort_rewrite();
+                },
+                Err(_) => {
+                    println!("AOF rewrite thread panicked");
+                    aof.abort_rewrite();
+                }
+            }
+        }
+    }
+    migrate_batch(state);
+    if let Some(aof) = state.aof.as_mut() {
+        if let Err(e) = aof.tick(now) {
+            println!("Error {} while syncing the AOF", e);
+        }
+    }
+}
+
+// First IPv4 address "host:port" resolves to
+fn resolve_v4(addr: &str) -> Option<SocketAddr> {
+    return addr.to_socket_addrs().ok()?.find(|a| a.is_ipv4());
+}
+
+// Forgets a connection along with everything that refers to it
+fn close_conn(fd2conn: &mut HashMap<RawFd, Conn>, fd: RawFd, state: &mut State) {
+    let mut conn = match fd2conn.remove(&fd) {
+        Some(conn) => conn,
+        None => {
+            return;
+        }
+    };
+    match conn.kind {
+        ConnKind::Replica => {
+            state.repl.replicas.retain(|r| *r != fd);
+        },
+        ConnKind::Leader => {
+            if state.repl.link == Some(fd) {
+                println!("Lost the link to the leader");
+                state.repl.link_lost(now_ms());
+            }
+        },
+        ConnKind::Client => {}
+    }
+    state.pubsub.remove_conn(fd);
+    state.blocking.unblock(fd);
+    unwatch_all(&mut conn, &mut state.keyspace);
+    // the descriptor may be reused by the next accepted connection
+    state.outbox.retain(|(to, _)| *to != fd);
+    let _ = close(fd);
+}
+
+// Answers parked connections. When a key they wait for is written their
+// commands run again, longest waiting first, and the ones that get more than
+// NIL are answered. An error keeps waiting too, the key went to another
+// type meanwhile. Connections whose timeout ran out get NIL. A served
+// connection goes on with the requests it sent in the meantime, which may
+// write and serve others in turn.
+fn serve_blocked(fd2conn: &mut HashMap<RawFd, Conn>, state: &mut State) {
+    loop {
+        let mut served: Vec<(RawFd, Vec<u8>)> = Vec::new();
+        for key in state.blocking.take_ready() {
+            for fd in state.blocking.waiting(&key) {
+                // served through another of its keys already
+                let (command, asking) = match state.blocking.waiter(fd) {
+                    Some(waiter) => (waiter.command.clone(), waiter.asking),
+                    None => continue,
+                };
+                // the slot may be served elsewhere by now
+                if let Some(out) = cluster_redirect(&command, asking, state) {
+                    state.blocking.unblock(fd);
+                    served.push((fd, out));
+                    continue;
+                }
+                let reply = match execute(&command, state) {
+                    Ok(res) => res.message,
+                    Err(_) => continue,
+                };
+                if read_u32(&reply, 0) == Some(ResType::NIL as u32) || is_error(&reply) {
+                    continue;
+                }
+                state.blocking.unblock(fd);
+                replicate(&command, &reply, state);
+                served.push((fd, reply));
+            }
+        }
+        for fd in state.blocking.expired(now_ms()) {
+            state.blocking.unblock(fd);
+            served.push((fd, out_nil()));
+        }
+        if served.is_empty() {
+            return;
+        }
+        for (fd, reply) in served {
+            let conn = match fd2conn.get_mut(&fd) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let reply = if reply.len() > conn.max_msg { out_err(6, "Reply too long") } else { reply };
+            match conn.proto {
+                Protocol::Ferdis => {
+                    conn.wbuf.extend_from_slice(&u32::try_from(reply.len()).unwrap().to_le_bytes());
+                    conn.wbuf.extend_from_slice(&reply);
+                },
+                _ => {
+                    conn.wbuf.extend_from_slice(&resp::encode_reply(&reply, conn.proto == Protocol::Resp3));
+                }
+            }
+            conn.state = ConnState::RES;
+            handle_requests(conn, state);
+            state_res(conn);
+            if conn.state == ConnState::END {
+                state.closing.push(fd);
+            }
+        }
+    }
+}
+
+// Queues what commands left in the outbox on its connections, then closes
+// the connections commands asked to close
+fn deliver(fd2conn: &mut HashMap<RawFd, Conn>, state: &mut State) {
+    let mut touched: Vec<RawFd> = Vec::new();
+    for (fd, outgoing) in std::mem::take(&mut state.outbox) {
+        if let Some(conn) = fd2conn.get_mut(&fd) {
+            match outgoing {
+                Outgoing::Raw(data) => {
+                    conn.wbuf.extend_from_slice(&data);
+                },
+                Outgoing::Push(value) => {
+                    push_value(conn, &value);
+                }
+            }
+            conn.state = ConnState::RES;
+            if !touched.contains(&fd) {
+                touched.push(fd);
+            }
+        }
+    }
+    for fd in touched {
+        let conn = fd2conn.get_mut(&fd).unwrap();
+        state_res(conn);
+        if conn.wbuf.len() - conn.wbuf_sent > K_PUSH_BUF_MAX {
+            println!("Dropping a connection that does not keep up");
+            conn.state = ConnState::END;
+        }
+        if conn.state == ConnState::END {
+            close_conn(fd2conn, fd, state);
+        }
+    }
+    for fd in std::mem::take(&mut state.closing) {
+        close_conn(fd2conn, fd, state);
+    }
+}
+
+// Binds a TCP listener to "host:port", port 0 picks a free port
+fn bind_listener(addr: &str) -> Result<RawFd, Errno> {
+    let sockaddr = match resolve_v4(addr) {
+        Some(SocketAddr::V4(v4)) => SockaddrIn::from(v4),
+        _ => {
+            return Err(Errno::EINVAL);
+        }
+    };
+    let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None)?;
+    let _ = setsockopt(fd, ReuseAddr, &true);
+    let res = bind(fd, &sockaddr)
+        .and_then(|_| listen(fd, 128))
+        .and_then(|_| set_nb_mode(fd));
+    if let Err(e) = res {
+        let _ = close(fd);
+        return Err(e);
+    }
+    return Ok(fd);
+}
+
+// Starts a non-blocking connect to the leader and queues psync, the poll
+// loop sends it once the connection is up
+fn connect_leader(state: &mut State) -> Result<Conn, Errno> {
+    let sockaddr = match state.repl.leader {
+        Some(SocketAddr::V4(v4)) => SockaddrIn::from(v4),
+        _ => {
+            return Err(Errno::EINVAL);
+        }
+    };
+    let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::SOCK_NONBLOCK, None)?;
+    match connect(fd, &sockaddr) {
+        Ok(()) | Err(Errno::EINPROGRESS) => {},
+        Err(e) => {
+            let _ = close(fd);
+            return Err(e);
+        }
+    }
+    let offset = state.repl.backlog.offset().to_string();
+    let mut conn = Conn::new(fd, Protocol::Ferdis, usize::MAX);
+    conn.kind = ConnKind::Leader;
+    conn.wbuf = serialize_request(&[b"psync", state.repl.replid.as_bytes(), offset.as_bytes()]);
+    conn.state = ConnState::RES;
+    return Ok(conn);
+}
+
+// The address a listener ended up on, with the real port when bound to port 0
+fn bound_addr(fd: RawFd) -> Result<SocketAddr, Errno> {
+    let sockaddr: SockaddrIn = getsockname(fd)?;
+    return sockaddr.to_string().parse().map_err(|_| Errno::EINVAL);
+}
+
+fn io_errno(e: std::io::Error) -> Errno {
+    return e.raw_os_error().map(Errno::from_i32).unwrap_or(Errno::EINVAL);
+}
+
+// Stops a running server from any thread. The server notices on its next
+// poll wakeup, which the handle forces by writing to a socket pair.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+    waker: Arc<UnixStream>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // a full socket buffer already wakes the server up
+        let _ = (&*self.waker).write(&[1]);
+    }
+}
+
+// Everything commands can touch besides the connection they arrive on
+struct State {
+    keyspace: Keyspace,
+    aof: Option<Aof>,
+    snapshot_path: Option<String>,
+    // snapshot being written by a background thread
+    bgsave: Option<JoinHandle<io::Result<()>>>,
+    // rewritten AOF being written by a background thread
+    aof_rewrite: Option<JoinHandle<io::Result<()>>>,
+    // unix time in milliseconds of the last successful snapshot
+    last_save: u64,
+    repl: Replication,
+    // bytes for other connections, handed over by the event loop
+    outbox: Vec<(RawFd, Outgoing)>,
+    // connections the event loop should close
+    closing: Vec<RawFd>,
+    // slot ownership in cluster mode
+    cluster: Option<Cluster>,
+    migration: Option<Migration>,
+    pubsub: PubSub,
+    // connections waiting in blpop or brpop
+    blocking: Blocking,
+    // largest reply a client connection takes
+    max_msg: usize,
+}
+
+// Bytes for a connection that did not ask for them
+enum Outgoing {
+    // already framed, like the replication stream
+    Raw(Vec<u8>),
+    // a serialized value, framed by `push_value`
+    Push(Vec<u8>),
+}
+
+impl State {
+    fn load(config: &ServerConfig) -> Result<State, Errno> {
+        let mut state = State {
+            keyspace: Keyspace::new(),
+            aof: None,
+            snapshot_path: config.snapshot_path.clone(),
+            bgsave: None,
+            aof_rewrite: None,
+            last_save: now_ms(),
+            repl: Replication::new(),
+            outbox: Vec::new(),
+            closing: Vec::new(),
+            cluster: None,
+            migration: None,
+            pubsub: PubSub::new(),
+            blocking: Blocking::new(),
+            max_msg: config.max_msg,
+        };
+        // the AOF is more up to date than a snapshot, so it wins when enabled
+        if let (None, Some(path)) = (&config.aof_path, &config.snapshot_path) {
+            if std::path::Path::new(path).exists() {
+                let entries = snapshot::read_snapshot(path).map_err(|e| {
+                    println!("Error {} while loading {}", e, path);
+                    io_errno(e)
+                })?;
+                let count = entries.len();
+                load_entries(&mut state.keyspace, entries, now_ms());
+                println!("Loaded {} keys from {}", count, path);
+            }
+        }
+        if let Some(path) = &config.aof_path {
+            let (aof, commands) = Aof::open(path, config.fsync).map_err(|e| {
+                println!("Error {} while loading {}", e, path);
+                io_errno(e)
+            })?;
+            for command in commands.iter() {
+                // logged commands are already in absolute form
+                execute(command, &mut state)?;
+            }
+            println!("Replayed {} commands from {}", commands.len(), path);
+            state.aof = Some(aof);
+        }
+        return Ok(state);
+    }
+}
+
+// A bound server with its own keyspace. Listeners are open once `build`
+// returns, so `local_addr` can be handed to clients before `run` is called.
+pub struct Server {
+    config: ServerConfig,
+    listeners: Vec<(RawFd, Protocol)>,
+    addr: SocketAddr,
+    resp_addr: Option<SocketAddr>,
+    state: State,
+    stop: Arc<AtomicBool>,
+    wake_rx: UnixStream,
+    wake_tx: Arc<UnixStream>,
+}
+
+impl Server {
+    pub fn builder() -> ServerConfig {
+        return ServerConfig::default();
+    }
+
+    fn bind(config: ServerConfig) -> Result<Server, Errno> {
+        // the keyspace is restored before any client can connect
+        let mut state = State::load(&config)?;
+        if let Some(addr) = &config.replicaof {
+            state.repl.leader = Some(resolve_v4(addr).ok_or(Errno::EINVAL)?);
+        }
+        let (wake_rx, wake_tx) = UnixStream::pair().map_err(io_errno)?;
+        wake_rx.set_nonblocking(true).map_err(io_errno)?;
+        wake_tx.set_nonblocking(true).map_err(io_errno)?;
+        let mut listeners: Vec<(RawFd, Protocol)> = Vec::new();
+        let mut addrs = vec![config.addr.clone()];
+        addrs.extend(config.resp_addr.iter().cloned());
+        for (i, addr) in addrs.iter().enumerate() {
+            match bind_listener(addr) {
+                Ok(fd) => {
+                    listeners.push((fd, if i == 0 { Protocol::Ferdis } else { Protocol::Resp2 }));
+                },
                 Err(e) => {
-                    println!("Error while calling listen {}", e);
+                    for (fd, _) in listeners {
+                        let _ = close(fd);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let addr = bound_addr(listeners[0].0)?;
+        let resp_addr = match listeners.get(1) {
+            Some((fd, _)) => Some(bound_addr(*fd)?),
+            None => None,
+        };
+        if config.cluster_enabled {
+            // other nodes can not reach a wildcard address, announce loopback
+            let mut myself = addr;
+            if myself.ip().is_unspecified() {
+                myself.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+            }
+            state.cluster = Some(Cluster::new(myself));
+        }
+        return Ok(Server {
+            config: config,
+            listeners: listeners,
+            addr: addr,
+            resp_addr: resp_addr,
+            state: state,
+            stop: Arc::new(AtomicBool::new(false)),
+            wake_rx: wake_rx,
+            wake_tx: Arc::new(wake_tx),
+        });
+    }
+
+    // Address of the ferdis protocol listener
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    pub fn resp_addr(&self) -> Option<SocketAddr> {
+        return self.resp_addr;
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        return ShutdownHandle { stop: self.stop.clone(), waker: self.wake_tx.clone() };
+    }
+
+    // Serves clients until the shutdown handle is used, then closes every
+    // connection and listener
+    pub fn run(mut self) {
+        let mut fd2conn: HashMap<RawFd,Conn> = HashMap::new();
+        let mut poll_args: Vec<PollFd> = Vec::new();
+        // the waker comes first, then the listening sockets
+        let first_conn = 1 + self.listeners.len();
+        while !self.stop.load(Ordering::SeqCst) {
+            if self.state.repl.next_connect().is_some_and(|at| at <= now_ms()) {
+                match connect_leader(&mut self.state) {
+                    Ok(conn) => {
+                        self.state.repl.link = Some(conn.fd);
+                        self.state.repl.link_state = LinkState::Handshake;
+                        fd2conn.insert(conn.fd, conn);
+                    },
+                    Err(e) => {
+                        println!("Error {} while connecting to the leader", e);
+                        self.state.repl.link_lost(now_ms());
+                    }
+                }
+            }
+
+            poll_args.clear();
+            poll_args.push(PollFd::new(self.wake_rx.as_raw_fd(), PollFlags::POLLIN));
+            for (fd, _) in self.listeners.iter() {
+                poll_args.push(PollFd::new(*fd, PollFlags::POLLIN));
+            }
+            for (fd, conn) in fd2conn.iter() {
+                let mut pfd = PollFd::new(*fd, PollFlags::empty());
+                if conn.state == ConnState::REQ {
+                    pfd.set_events(PollFlags::POLLERR | PollFlags::POLLIN);
+                } else {
+                    pfd.set_events(PollFlags::POLLERR | PollFlags::POLLIN | PollFlags::POLLOUT);
+                }
+                poll_args.push(pfd);
+            }
+            if let Err(e) = poll(&mut poll_args, next_timer_ms(&self.state)) {
+                if e == Errno::EINTR {
+                    continue;
+                }
+                println!("Error {} while polling file descriptors", e);
+                break;
+            }
+
+            if poll_args[0].revents().is_some_and(|r| !r.is_empty()) {
+                let mut buf = [0; 64];
+                while (&self.wake_rx).read(&mut buf).is_ok_and(|n| n > 0) {}
+            }
+
+            for poll_fd in poll_args[first_conn..].iter() {
+                let revents = match poll_fd.revents() {
+                    Some(r) if !r.is_empty() => r,
+                    _ => continue,
+                };
+                let conn = fd2conn.get_mut(&poll_fd.as_raw_fd()).unwrap();
+                connection_io(conn, revents, &mut self.state);
+                if conn.state == ConnState::END {
+                    close_conn(&mut fd2conn, poll_fd.as_raw_fd(), &mut self.state);
                 }
             }
+
+            for (i, (fd, proto)) in self.listeners.iter().enumerate() {
+                if poll_args[1 + i].revents().is_some_and(|r| !r.is_empty()) {
+                    let _ = accept_new_conn(&mut fd2conn, *fd, *proto, self.config.max_msg);
+                }
+            }
+
+            process_timers(&mut self.state);
+            serve_blocked(&mut fd2conn, &mut self.state);
+            deliver(&mut fd2conn, &mut self.state);
+        }
+        for fd in fd2conn.keys() {
+            let _ = close(*fd);
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for (fd, _) in self.listeners.iter() {
+            let _ = close(*fd);
+        }
+    }
+}
+
+pub fn run_server() {
+    run_server_with(ServerConfig::default());
+}
+
+pub fn run_server_with(config: ServerConfig) {
+    let addr = config.addr.clone();
+    match config.build() {
+        Ok(server) => {
+            println!("Listening on {}", server.local_addr());
+            server.run();
         },
         Err(e) => {
-            println!("Error while opening socket {}", e);
+            println!("Error {} while listening on {}", e, addr);
         }
     }
 }
@@ -0,0 +1,141 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::oa_map::OAMap;
+
+const K_INITIAL_CAPACITY: usize = 8;
+
+// Every RandomState gets fresh keys, so hashing nothing with one is a
+// cheap random number
+fn random_u64() -> u64 {
+    return RandomState::new().build_hasher().finish();
+}
+
+// Unordered set of binary strings, the members are the keys of an OAMap
+#[derive(Clone, Debug)]
+pub struct Set {
+    members: OAMap<Vec<u8>, ()>,
+}
+
+impl Default for Set {
+    fn default() -> Set {
+        return Set { members: OAMap::new_with_capacity(K_INITIAL_CAPACITY) };
+    }
+}
+
+impl Set {
+    pub fn new() -> Set {
+        return Set::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.members.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.members.is_empty();
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        return self.members.get_ref(&member.to_vec()).is_some();
+    }
+
+    // Returns true when the member was not there before
+    pub fn insert(&mut self, member: &[u8]) -> bool {
+        if self.contains(member) {
+            return false;
+        }
+        self.members.put(member.to_vec(), ());
+        return true;
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        return self.members.remove(&member.to_vec()).is_some();
+    }
+
+    // Members in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        return self.members.iter().map(|(m, _)| m.as_slice());
+    }
+
+    pub fn random(&self) -> Option<&[u8]> {
+        return self.members.random_key(random_u64()).map(|m| m.as_slice());
+    }
+
+    // `count` random members. Distinct members stop at the size of the set,
+    // otherwise a member can come up more than once.
+    pub fn random_members(&self, count: usize, distinct: bool) -> Vec<&[u8]> {
+        if !distinct {
+            return (0..count).filter_map(|_| self.random()).collect();
+        }
+        if count >= self.len() {
+            return self.iter().collect();
+        }
+        if count * 3 > self.len() {
+            // most of the set is wanted, dropping members beats drawing them
+            let mut out: Vec<&[u8]> = self.iter().collect();
+            while out.len() > count {
+                let i = usize::try_from(random_u64() % u64::try_from(out.len()).unwrap()).unwrap();
+                out.swap_remove(i);
+            }
+            return out;
+        }
+        let mut seen = HashSet::with_capacity(count);
+        let mut out = Vec::with_capacity(count);
+        while out.len() < count {
+            let member = self.random().unwrap();
+            if seen.insert(member) {
+                out.push(member);
+            }
+        }
+        return out;
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let member = self.random()?.to_vec();
+        self.remove(&member);
+        return Some(member);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_members_and_sampling() {
+        let mut set = Set::new();
+        assert!(set.insert(b"a"));
+        assert!(!set.insert(b"a"));
+        assert!(set.contains(b"a"));
+        assert!(!set.contains(b"b"));
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert!(set.is_empty());
+        assert_eq!(set.random(), None);
+        assert_eq!(set.pop(), None);
+
+        for i in 0..100 {
+            set.insert(i.to_string().as_bytes());
+        }
+        assert_eq!(set.len(), 100);
+        assert_eq!(set.iter().count(), 100);
+        for count in [0, 10, 50, 100, 150] {
+            let picked = set.random_members(count, true);
+            assert_eq!(picked.len(), count.min(100));
+            assert_eq!(picked.iter().collect::<HashSet<_>>().len(), picked.len());
+            assert!(picked.iter().all(|m| set.contains(m)));
+        }
+        let picked = set.random_members(150, false);
+        assert_eq!(picked.len(), 150);
+        assert!(picked.iter().all(|m| set.contains(m)));
+
+        let mut popped = HashSet::new();
+        while let Some(member) = set.pop() {
+            assert!(popped.insert(member));
+        }
+        assert_eq!(popped.len(), 100);
+        assert!(set.is_empty());
+    }
+}
@@ -0,0 +1,478 @@
+// Point-in-time snapshot of the keyspace:
+//
+// | magic "FRDS" | version (u32) | entry* | 0xFF | crc32 (u32) |
+//
+// entry: | type (u8) | expire_at (u64, 0 = none) | key len (u32) | key | value |
+// value: string | len (u32) | bytes |
+//        zset   | n (u32) | (score (f64) | name len (u32) | name)* |
+//        list   | n (u32) | (len (u32) | bytes)* |
+//        hash   | n (u32) | (field len (u32) | field | value len (u32) | value)* |
+//        set    | n (u32) | (len (u32) | member)* |
+//        stream | n (u32) | (id | n (u32) | (len (u32) | bytes)*)* | last id | groups (u32) | group* |
+// group: | name len (u32) | name | last delivered id | consumers (u32) | (len (u32) | name)*
+//        | pending (u32) | (id | consumer len (u32) | consumer | delivered at (u64) | deliveries (u64))* |
+// id:    | ms (u64) | seq (u64) |
+//
+// Numbers are little endian like on the wire. The checksum covers every byte
+// before it.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use crate::keyspace::{Entry, Value};
+use crate::zset::ZSet;
+use crate::list::List;
+use crate::hash::Hash;
+use crate::set::Set;
+use crate::stream::{Group, Pending, Stream, StreamId};
+
+const MAGIC: &[u8; 4] = b"FRDS";
+pub const VERSION: u32 = 1;
+
+const TYPE_STR: u8 = 0;
+const TYPE_ZSET: u8 = 1;
+const TYPE_LIST: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_SET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+const END: u8 = 0xFF;
+
+// CRC-32 (IEEE), table built at compile time
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc = CRC_TABLE[((crc ^ u32::from(*b)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+fn invalid(reason: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("bad snapshot: {}", reason));
+}
+
+fn put_u32(out: &mut Vec<u8>, val: usize) {
+    out.extend_from_slice(&u32::try_from(val).unwrap().to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, val: &[u8]) {
+    put_u32(out, val.len());
+    out.extend_from_slice(val);
+}
+
+fn put_id(out: &mut Vec<u8>, id: StreamId) {
+    out.extend_from_slice(&id.ms.to_le_bytes());
+    out.extend_from_slice(&id.seq.to_le_bytes());
+}
+
+// Type tag and serialized form of one value, also used to move single keys
+pub fn encode_value(value: &Value, out: &mut Vec<u8>) -> u8 {
+    match value {
+        Value::Str(data) => {
+            put_bytes(out, data);
+            return TYPE_STR;
+        },
+        Value::ZSet(zset) => {
+            put_u32(out, zset.len());
+            for node in zset.range(0, -1) {
+                out.extend_from_slice(&node.score.to_le_bytes());
+                put_bytes(out, &node.name);
+            }
+            return TYPE_ZSET;
+        },
+        Value::List(list) => {
+            put_u32(out, list.len());
+            for item in list.iter() {
+                put_bytes(out, item);
+            }
+            return TYPE_LIST;
+        },
+        Value::Hash(hash) => {
+            put_u32(out, hash.len());
+            for (field, value) in hash.iter() {
+                put_bytes(out, field);
+                put_bytes(out, value);
+            }
+            return TYPE_HASH;
+        },
+        Value::Set(set) => {
+            put_u32(out, set.len());
+            for member in set.iter() {
+                put_bytes(out, member);
+            }
+            return TYPE_SET;
+        },
+        Value::Stream(stream) => {
+            put_u32(out, stream.len());
+            for (id, fields) in stream.iter() {
+                put_id(out, id);
+                put_u32(out, fields.len());
+                for field in fields {
+                    put_bytes(out, field);
+                }
+            }
+            put_id(out, stream.last_id());
+            put_u32(out, stream.groups().count());
+            for (name, group) in stream.groups() {
+                put_bytes(out, name);
+                put_id(out, group.last_delivered);
+                put_u32(out, group.consumers.len());
+                for consumer in group.consumers.iter() {
+                    put_bytes(out, consumer);
+                }
+                put_u32(out, group.pending.len());
+                for (id, pending) in group.pending.iter() {
+                    put_id(out, *id);
+                    put_bytes(out, &pending.consumer);
+                    out.extend_from_slice(&pending.delivered_at.to_le_bytes());
+                    out.extend_from_slice(&pending.deliveries.to_le_bytes());
+                }
+            }
+            return TYPE_STREAM;
+        },
+    }
+}
+
+// Reads from a buffer and fails instead of panicking on short input
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("unexpected end of data"));
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(out);
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> io::Result<usize> {
+        let bytes = self.take(4)?;
+        return Ok(usize::try_from(u32::from_le_bytes(bytes.try_into().unwrap())).unwrap());
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()?;
+        return Ok(self.take(len)?.to_vec());
+    }
+
+    fn id(&mut self) -> io::Result<StreamId> {
+        return Ok(StreamId::new(self.u64()?, self.u64()?));
+    }
+}
+
+fn read_value(reader: &mut Reader, type_tag: u8) -> io::Result<Value> {
+    match type_tag {
+        TYPE_STR => {
+            return Ok(Value::Str(reader.bytes()?));
+        },
+        TYPE_ZSET => {
+            let n = reader.u32()?;
+            let mut zset = ZSet::new();
+            for _ in 0..n {
+                let score = f64::from_bits(reader.u64()?);
+                let name = reader.bytes()?;
+                zset.add(&name, score);
+            }
+            return Ok(Value::ZSet(zset));
+        },
+        TYPE_LIST => {
+            let n = reader.u32()?;
+            let mut list = List::new();
+            for _ in 0..n {
+                list.push_back(&reader.bytes()?);
+            }
+            return Ok(Value::List(list));
+        },
+        TYPE_HASH => {
+            let n = reader.u32()?;
+            let mut hash = Hash::new();
+            for _ in 0..n {
+                let field = reader.bytes()?;
+                hash.insert(&field, &reader.bytes()?);
+            }
+            return Ok(Value::Hash(hash));
+        },
+        TYPE_SET => {
+            let n = reader.u32()?;
+            let mut set = Set::new();
+            for _ in 0..n {
+                set.insert(&reader.bytes()?);
+            }
+            return Ok(Value::Set(set));
+        },
+        TYPE_STREAM => {
+            let n = reader.u32()?;
+            let mut stream = Stream::new();
+            for _ in 0..n {
+                let id = reader.id()?;
+                let nfields = reader.u32()?;
+                let mut fields = Vec::new();
+                for _ in 0..nfields {
+                    fields.push(reader.bytes()?);
+                }
+                if !stream.add(id, fields) {
+                    return Err(invalid("stream ids out of order"));
+                }
+            }
+            stream.set_last_id(reader.id()?);
+            let ngroups = reader.u32()?;
+            for _ in 0..ngroups {
+                let name = reader.bytes()?;
+                let mut group = Group { last_delivered: reader.id()?, ..Group::default() };
+                let nconsumers = reader.u32()?;
+                for _ in 0..nconsumers {
+                    group.consumers.insert(reader.bytes()?);
+                }
+                let npending = reader.u32()?;
+                for _ in 0..npending {
+                    let id = reader.id()?;
+                    let pending = Pending { consumer: reader.bytes()?, delivered_at: reader.u64()?, deliveries: reader.u64()? };
+                    group.pending.insert(id, pending);
+                }
+                stream.insert_group(&name, group);
+            }
+            return Ok(Value::Stream(stream));
+        },
+        _ => {
+            return Err(invalid("unknown value type"));
+        }
+    }
+}
+
+// Counterpart of `encode_value`
+pub fn decode_value(type_tag: u8, data: &[u8]) -> io::Result<Value> {
+    let mut reader = Reader { buf: data, pos: 0 };
+    let value = read_value(&mut reader, type_tag)?;
+    if reader.pos != data.len() {
+        return Err(invalid("trailing bytes after value"));
+    }
+    return Ok(value);
+}
+
+// Payload of dump and restore, one value on its own:
+// | type (u8) | value | version (u32) | crc32 (u32) |
+pub fn dump_value(value: &Value) -> Vec<u8> {
+    let mut out = vec![0];
+    out[0] = encode_value(value, &mut out);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    return out;
+}
+
+pub fn restore_value(data: &[u8]) -> io::Result<Value> {
+    if data.len() < 1 + 4 + 4 {
+        return Err(invalid("payload too short"));
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid("checksum mismatch"));
+    }
+    let (value, version) = body.split_at(body.len() - 4);
+    if u32::from_le_bytes(version.try_into().unwrap()) != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    return decode_value(value[0], &value[1..]);
+}
+
+pub fn encode<'a, I: Iterator<Item = (&'a [u8], &'a Entry)>>(entries: I) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let mut value = Vec::new();
+    for (key, entry) in entries {
+        value.clear();
+        let type_tag = encode_value(&entry.value, &mut value);
+        out.push(type_tag);
+        out.extend_from_slice(&entry.expire_at.unwrap_or(0).to_le_bytes());
+        put_bytes(&mut out, key);
+        out.extend_from_slice(&value);
+    }
+    out.push(END);
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    return out;
+}
+
+pub fn decode(buf: &[u8]) -> io::Result<Vec<(Vec<u8>, Entry)>> {
+    if buf.len() < MAGIC.len() + 4 + 1 + 4 || &buf[..4] != MAGIC {
+        return Err(invalid("not a ferdis snapshot"));
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid("checksum mismatch"));
+    }
+    let mut reader = Reader { buf: body, pos: 4 };
+    let version = u32::try_from(reader.u32()?).unwrap();
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    let mut entries = Vec::new();
+    loop {
+        let type_tag = reader.u8()?;
+        if type_tag == END {
+            break;
+        }
+        let expire_at = match reader.u64()? {
+            0 => None,
+            at => Some(at),
+        };
+        let key = reader.bytes()?;
+        let value = read_value(&mut reader, type_tag)?;
+        entries.push((key, Entry { value: value, expire_at: expire_at }));
+    }
+    if reader.pos != body.len() {
+        return Err(invalid("trailing bytes after end marker"));
+    }
+    return Ok(entries);
+}
+
+// Reads a snapshot file without a running server, for backup tooling
+pub fn read_snapshot(path: &str) -> io::Result<Vec<(Vec<u8>, Entry)>> {
+    return decode(&fs::read(path)?);
+}
+
+// Writes next to `path` and renames, so a crash never leaves half a snapshot
+pub fn write_snapshot(path: &str, data: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_roundtrip_and_corruption() {
+        let mut zset = ZSet::new();
+        zset.add(b"a", 1.5);
+        zset.add(b"\x00b", -2.0);
+        let mut list = List::new();
+        list.push_back(b"x");
+        list.push_back(b"");
+        let entries = [
+            (b"str".to_vec(), Entry { value: Value::Str(b"\xffvalue".to_vec()), expire_at: Some(1234) }),
+            (b"zset".to_vec(), Entry { value: Value::ZSet(zset), expire_at: None }),
+            (b"list".to_vec(), Entry { value: Value::List(list), expire_at: None }),
+        ];
+        let buf = encode(entries.iter().map(|(k, e)| (k.as_slice(), e)));
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0].0, b"str");
+        assert!(matches!(&decoded[0].1.value, Value::Str(v) if v == b"\xffvalue"));
+        assert_eq!(decoded[0].1.expire_at, Some(1234));
+        match &decoded[1].1.value {
+            Value::ZSet(zset) => {
+                assert_eq!(zset.score(b"\x00b"), Some(-2.0));
+                assert_eq!(zset.rank(b"a"), Some(1));
+            },
+            _ => panic!("expected a zset"),
+        }
+        assert_eq!(decoded[1].1.expire_at, None);
+        match &decoded[2].1.value {
+            Value::List(list) => {
+                assert_eq!(list.range(0, -1), vec![b"x".as_slice(), b""]);
+            },
+            _ => panic!("expected a list"),
+        }
+
+        // every flipped byte and every truncation is caught
+        for i in 0..buf.len() {
+            let mut bad = buf.clone();
+            bad[i] ^= 0x40;
+            assert!(decode(&bad).is_err());
+            assert!(decode(&buf[..i]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let mut zset = ZSet::new();
+        zset.add(b"m", 3.0);
+        let dump = dump_value(&Value::ZSet(zset));
+        match restore_value(&dump).unwrap() {
+            Value::ZSet(zset) => {
+                assert_eq!(zset.score(b"m"), Some(3.0));
+            },
+            _ => panic!("expected a zset"),
+        }
+        let mut hash = Hash::new();
+        hash.insert(b"f", b"\x00v");
+        match restore_value(&dump_value(&Value::Hash(hash))).unwrap() {
+            Value::Hash(hash) => {
+                assert_eq!(hash.get(b"f"), Some(b"\x00v".as_slice()));
+                assert_eq!(hash.len(), 1);
+            },
+            _ => panic!("expected a hash"),
+        }
+        let mut set = Set::new();
+        set.insert(b"a");
+        set.insert(b"");
+        match restore_value(&dump_value(&Value::Set(set))).unwrap() {
+            Value::Set(set) => {
+                assert!(set.contains(b"a") && set.contains(b""));
+                assert_eq!(set.len(), 2);
+            },
+            _ => panic!("expected a set"),
+        }
+        let mut stream = Stream::new();
+        stream.add(StreamId::new(1, 0), vec![b"f".to_vec(), b"v".to_vec()]);
+        stream.add(StreamId::new(2, 0), vec![b"f".to_vec(), b"w".to_vec()]);
+        stream.trim_len(1);
+        stream.create_group(b"g", StreamId::MIN);
+        stream.read_group(b"g", b"c", 1, false, 42);
+        match restore_value(&dump_value(&Value::Stream(stream))).unwrap() {
+            Value::Stream(stream) => {
+                assert_eq!(stream.len(), 1);
+                assert_eq!(stream.last_id(), StreamId::new(2, 0));
+                let group = stream.group(b"g").unwrap();
+                assert_eq!(group.last_delivered, StreamId::new(2, 0));
+                assert!(group.consumers.contains(b"c".as_slice()));
+                assert_eq!(group.pending[&StreamId::new(2, 0)], Pending { consumer: b"c".to_vec(), delivered_at: 42, deliveries: 1 });
+            },
+            _ => panic!("expected a stream"),
+        }
+        let dump = dump_value(&Value::Str(b"v".to_vec()));
+        assert!(matches!(restore_value(&dump).unwrap(), Value::Str(v) if v == b"v"));
+        for i in 0..dump.len() {
+            let mut bad = dump.clone();
+            bad[i] ^= 0x01;
+            assert!(restore_value(&bad).is_err());
+            assert!(restore_value(&dump[..i]).is_err());
+        }
+    }
+}
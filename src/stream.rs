@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Entry id: the millisecond it was added in and a sequence number for
+// entries of the same millisecond. Ids only grow within a stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        return StreamId { ms: ms, seq: seq };
+    }
+
+    // "ms-seq", or "ms" alone which gets `seq`
+    pub fn parse(arg: &[u8], seq: u64) -> Option<StreamId> {
+        let text = std::str::from_utf8(arg).ok()?;
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        match text.split_once('-') {
+            Some((ms, s)) if digits(ms) && digits(s) => {
+                return Some(StreamId { ms: ms.parse().ok()?, seq: s.parse().ok()? });
+            },
+            None if digits(text) => {
+                return Some(StreamId { ms: text.parse().ok()?, seq: seq });
+            },
+            _ => {
+                return None;
+            }
+        }
+    }
+
+    // The smallest id above this one, None above MAX
+    pub fn next(self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            return Some(StreamId { ms: self.ms, seq: self.seq + 1 });
+        }
+        if self.ms < u64::MAX {
+            return Some(StreamId { ms: self.ms + 1, seq: 0 });
+        }
+        return None;
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// Fields and values of an entry, alternating
+pub type Fields = Vec<Vec<u8>>;
+
+// An entry handed to a consumer of a group and not acknowledged yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pending {
+    pub consumer: Vec<u8>,
+    // unix time in milliseconds of the last delivery
+    pub delivered_at: u64,
+    pub deliveries: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Group {
+    // entries up to this id were handed out already
+    pub last_delivered: StreamId,
+    pub pending: BTreeMap<StreamId, Pending>,
+    pub consumers: BTreeSet<Vec<u8>>,
+}
+
+// Append-only log of field/value entries ordered by id, with the consumer
+// groups reading it. Unlike the other collections an emptied stream keeps
+// its key: the last id and the groups outlive the entries.
+#[derive(Clone, Debug, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    // highest id ever added, trimmed or not
+    last_id: StreamId,
+    groups: BTreeMap<Vec<u8>, Group>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        return Stream::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        return self.last_id;
+    }
+
+    // Only moves the last id up, used when loading a stream
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = self.last_id.max(id);
+    }
+
+    // Appends an entry, false when `id` is not above the last id
+    pub fn add(&mut self, id: StreamId, fields: Fields) -> bool {
+        if id <= self.last_id {
+            return false;
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        return true;
+    }
+
+    // Entries with ids in [start, end], at most `count` of them
+    pub fn range(&self, start: StreamId, end: StreamId, count: usize) -> Vec<(StreamId, &[Vec<u8>])> {
+        if start > end {
+            return Vec::new();
+        }
+        return self.entries.range(start..=end).take(count).map(|(id, fields)| (*id, fields.as_slice())).collect();
+    }
+
+    // Entries with ids above `id`
+    pub fn after(&self, id: StreamId, count: usize) -> Vec<(StreamId, &[Vec<u8>])> {
+        match id.next() {
+            Some(start) => self.range(start, StreamId::MAX, count),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (StreamId, &[Vec<u8>])> {
+        return self.entries.iter().map(|(id, fields)| (*id, fields.as_slice()));
+    }
+
+    // Drops the oldest entries until at most `max` are left, returns how
+    // many went
+    pub fn trim_len(&mut self, max: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > max {
+            self.entries.pop_first();
+            removed += 1;
+        }
+        return removed;
+    }
+
+    // Drops the entries with ids below `min`
+    pub fn trim_before(&mut self, min: StreamId) -> usize {
+        let kept = self.entries.split_off(&min);
+        let removed = self.entries.len();
+        self.entries = kept;
+        return removed;
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&[u8], &Group)> {
+        return self.groups.iter().map(|(name, group)| (name.as_slice(), group));
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&Group> {
+        return self.groups.get(name);
+    }
+
+    // Adds a group that has seen everything up to `last_delivered`, false
+    // when the name is taken
+    pub fn create_group(&mut self, name: &[u8], last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups.insert(name.to_vec(), Group { last_delivered: last_delivered, ..Group::default() });
+        return true;
+    }
+
+    pub fn insert_group(&mut self, name: &[u8], group: Group) {
+        self.groups.insert(name.to_vec(), group);
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        return self.groups.remove(name).is_some();
+    }
+
+    pub fn set_group_id(&mut self, name: &[u8], last_delivered: StreamId) -> bool {
+        match self.groups.get_mut(name) {
+            Some(group) => {
+                group.last_delivered = last_delivered;
+                return true;
+            },
+            None => {
+                return false;
+            }
+        }
+    }
+
+    // Returns true when the consumer is new, None without the group
+    pub fn create_consumer(&mut self, group: &[u8], consumer: &[u8]) -> Option<bool> {
+        return Some(self.groups.get_mut(group)?.consumers.insert(consumer.to_vec()));
+    }
+
+    // Removes a consumer and its pending entries, returns how many it had
+    pub fn delete_consumer(&mut self, group: &[u8], consumer: &[u8]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        group.consumers.remove(consumer);
+        let before = group.pending.len();
+        group.pending.retain(|_, p| p.consumer != consumer);
+        return Some(before - group.pending.len());
+    }
+
+    // Hands the entries the group has not seen yet to `consumer`, they stay
+    // pending until acknowledged unless `noack`. None without the group.
+    pub fn read_group(&mut self, group: &[u8], consumer: &[u8], count: usize, noack: bool, now: u64)
+        -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.consumers.insert(consumer.to_vec());
+        let mut out = Vec::new();
+        let start = match group.last_delivered.next() {
+            Some(start) => start,
+            None => {
+                return Some(out);
+            }
+        };
+        for (id, fields) in self.entries.range(start..).take(count) {
+            group.last_delivered = *id;
+            if !noack {
+                group.pending.insert(*id, Pending { consumer: consumer.to_vec(), delivered_at: now, deliveries: 1 });
+            }
+            out.push((*id, fields.clone()));
+        }
+        return Some(out);
+    }
+
+    // Delivers the consumer's pending entries above `after` once more.
+    // Entries trimmed away since come back without fields.
+    pub fn read_pending(&mut self, group: &[u8], consumer: &[u8], after: StreamId, count: usize, now: u64)
+        -> Option<Vec<(StreamId, Option<Fields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.consumers.insert(consumer.to_vec());
+        let mut out = Vec::new();
+        let start = match after.next() {
+            Some(start) => start,
+            None => {
+                return Some(out);
+            }
+        };
+        for (id, pending) in group.pending.range_mut(start..).filter(|(_, p)| p.consumer == consumer).take(count) {
+            pending.delivered_at = now;
+            pending.deliveries += 1;
+            out.push((*id, self.entries.get(id).cloned()));
+        }
+        return Some(out);
+    }
+
+    // Takes entries off the group's pending list, returns how many were on
+    // it. None without the group.
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        return Some(ids.iter().filter(|id| group.pending.remove(id).is_some()).count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(value: &str) -> Vec<Vec<u8>> {
+        return vec![b"f".to_vec(), value.as_bytes().to_vec()];
+    }
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(StreamId::parse(b"5", u64::MAX), Some(StreamId::new(5, u64::MAX)));
+        assert_eq!(StreamId::parse(b"5-", 0), None);
+        assert_eq!(StreamId::parse(b"-5", 0), None);
+        assert_eq!(StreamId::parse(b"+5", 0), None);
+        assert_eq!(StreamId::parse(b"a-1", 0), None);
+        assert_eq!(StreamId::new(5, u64::MAX).next(), Some(StreamId::new(6, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(12, 7).to_string(), "12-7");
+        assert!(StreamId::new(1, 9) < StreamId::new(2, 0));
+    }
+
+    #[test]
+    fn test_add_range_trim() {
+        let mut stream = Stream::new();
+        assert!(!stream.add(StreamId::MIN, fields("zero")));
+        for i in 1..=10 {
+            assert!(stream.add(StreamId::new(i, 0), fields(&i.to_string())));
+        }
+        assert!(!stream.add(StreamId::new(10, 0), fields("again")));
+        assert!(!stream.add(StreamId::new(9, 5), fields("late")));
+        assert_eq!(stream.len(), 10);
+        let ids = |entries: Vec<(StreamId, &[Vec<u8>])>| entries.iter().map(|(id, _)| id.ms).collect::<Vec<_>>();
+        assert_eq!(ids(stream.range(StreamId::new(3, 0), StreamId::new(5, 0), usize::MAX)), vec![3, 4, 5]);
+        assert_eq!(ids(stream.range(StreamId::MIN, StreamId::MAX, 2)), vec![1, 2]);
+        assert!(stream.range(StreamId::new(5, 0), StreamId::new(3, 0), usize::MAX).is_empty());
+        assert_eq!(ids(stream.after(StreamId::new(8, 0), usize::MAX)), vec![9, 10]);
+        assert_eq!(stream.range(StreamId::new(2, 0), StreamId::new(2, 0), 1)[0].1, fields("2").as_slice());
+
+        assert_eq!(stream.trim_before(StreamId::new(4, 0)), 3);
+        assert_eq!(stream.trim_len(5), 2);
+        assert_eq!(ids(stream.range(StreamId::MIN, StreamId::MAX, usize::MAX)), vec![6, 7, 8, 9, 10]);
+        assert_eq!(stream.trim_len(0), 5);
+        assert!(stream.is_empty());
+        // the last id stays, so ids keep growing
+        assert_eq!(stream.last_id(), StreamId::new(10, 0));
+        assert!(!stream.add(StreamId::new(10, 0), fields("again")));
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut stream = Stream::new();
+        for i in 1..=5 {
+            stream.add(StreamId::new(i, 0), fields(&i.to_string()));
+        }
+        assert!(stream.create_group(b"g", StreamId::new(1, 0)));
+        assert!(!stream.create_group(b"g", StreamId::MIN));
+        assert!(stream.read_group(b"nope", b"c", 1, false, 0).is_none());
+
+        // new entries go to one consumer each
+        let first = stream.read_group(b"g", b"alice", 2, false, 100).unwrap();
+        assert_eq!(first.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(), vec![2, 3]);
+        let second = stream.read_group(b"g", b"bob", usize::MAX, false, 100).unwrap();
+        assert_eq!(second.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(), vec![4, 5]);
+        assert!(stream.read_group(b"g", b"bob", usize::MAX, false, 100).unwrap().is_empty());
+        assert_eq!(stream.group(b"g").unwrap().pending.len(), 4);
+
+        // history reads only see the consumer's own entries
+        stream.trim_before(StreamId::new(3, 0));
+        let history = stream.read_pending(b"g", b"alice", StreamId::MIN, usize::MAX, 200).unwrap();
+        assert_eq!(history, vec![(StreamId::new(2, 0), None), (StreamId::new(3, 0), Some(fields("3")))]);
+        let pending = &stream.group(b"g").unwrap().pending[&StreamId::new(3, 0)];
+        assert_eq!((pending.delivered_at, pending.deliveries), (200, 2));
+
+        assert_eq!(stream.ack(b"g", &[StreamId::new(2, 0), StreamId::new(4, 0), StreamId::new(9, 0)]), Some(2));
+        assert_eq!(stream.delete_consumer(b"g", b"bob"), Some(1));
+        assert_eq!(stream.group(b"g").unwrap().pending.keys().copied().collect::<Vec<_>>(), vec![StreamId::new(3, 0)]);
+
+        // nothing pending with noack
+        stream.add(StreamId::new(6, 0), fields("6"));
+        assert_eq!(stream.read_group(b"g", b"carol", 1, true, 300).unwrap().len(), 1);
+        assert_eq!(stream.group(b"g").unwrap().pending.len(), 1);
+        assert!(stream.destroy_group(b"g"));
+        assert!(stream.ack(b"g", &[StreamId::new(3, 0)]).is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use std::cmp::Ordering;
+use crate::avl::AvlTree;
+use crate::oa_map::OAMap;
+
+#[derive(Clone, Debug)]
+pub struct ZNode {
+    pub score: f64,
+    pub name: Vec<u8>,
+}
+
+// Ordered by score, ties broken by name
+impl Ord for ZNode {
+    fn cmp(&self, other: &ZNode) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| self.name.cmp(&other.name))
+    }
+}
+
+impl PartialOrd for ZNode {
+    fn partial_cmp(&self, other: &ZNode) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ZNode {
+    fn eq(&self, other: &ZNode) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ZNode {}
+
+// Sorted set: the tree keeps (score, name) pairs in order and answers rank
+// queries, the map finds the tree node of a name.
+#[derive(Clone, Debug)]
+pub struct ZSet {
+    tree: AvlTree<ZNode>,
+    index: OAMap<Vec<u8>, usize>,
+}
+
+impl Default for ZSet {
+    fn default() -> ZSet {
+        return ZSet { tree: AvlTree::new(), index: OAMap::new_with_capacity(8) };
+    }
+}
+
+impl ZSet {
+    pub fn new() -> ZSet {
+        return ZSet::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.tree.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.tree.is_empty();
+    }
+
+    // Returns true when the name was not in the set before
+    pub fn add(&mut self, name: &[u8], score: f64) -> bool {
+        let name = name.to_vec();
+        if let Some(id) = self.index.get_ref(&name) {
+            let node = self.tree.get(*id);
+            if node.score == score {
+                return false;
+            }
+            let old = node.clone();
+            self.tree.remove(&old);
+            let id = self.tree.insert(ZNode { score, name: name.clone() });
+            self.index.put(name, id);
+            return false;
+        }
+        let id = self.tree.insert(ZNode { score, name: name.clone() });
+        self.index.put(name, id);
+        return true;
+    }
+
+    pub fn remove(&mut self, name: &[u8]) -> bool {
+        match self.index.remove(&name.to_vec()) {
+            Some(id) => {
+                let node = self.tree.get(id).clone();
+                self.tree.remove(&node);
+                return true;
+            },
+            None => {
+                return false;
+            }
+        }
+    }
+
+    pub fn score(&self, name: &[u8]) -> Option<f64> {
+        let id = self.index.get_ref(&name.to_vec())?;
+        return Some(self.tree.get(*id).score);
+    }
+
+    pub fn rank(&self, name: &[u8]) -> Option<usize> {
+        let id = self.index.get_ref(&name.to_vec())?;
+        return Some(self.tree.count_less(self.tree.get(*id)));
+    }
+
+    // Nodes with ranks in [start, stop]; negative ranks count from the end
+    pub fn range(&self, start: i64, stop: i64) -> Vec<&ZNode> {
+        let len = i64::try_from(self.len()).unwrap();
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        let mut out = Vec::new();
+        let mut rank = start;
+        while rank <= stop {
+            let id = self.tree.select(usize::try_from(rank).unwrap()).unwrap();
+            out.push(self.tree.get(id));
+            rank += 1;
+        }
+        return out;
+    }
+
+    // Up to `limit` nodes starting `offset` positions away from the first
+    // node that is >= (score, name)
+    pub fn query(&self, score: f64, name: &[u8], offset: i64, limit: usize) -> Vec<&ZNode> {
+        let key = ZNode { score, name: name.to_vec() };
+        let first = i64::try_from(self.tree.count_less(&key)).unwrap();
+        // ranks before the first node are skipped rather than stepped over
+        let start = usize::try_from(first.saturating_add(offset).max(0)).unwrap();
+        if start >= self.len() {
+            return Vec::new();
+        }
+        let stop = start.saturating_add(limit).min(self.len());
+        let mut out = Vec::new();
+        for rank in start..stop {
+            let id = self.tree.select(rank).unwrap();
+            out.push(self.tree.get(id));
+        }
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_update_remove() {
+        let mut zset = ZSet::new();
+        assert!(zset.add(b"a", 2.0));
+        assert!(zset.add(b"b", 1.0));
+        assert!(!zset.add(b"a", 0.5));
+        assert_eq!(zset.score(b"a"), Some(0.5));
+        assert_eq!(zset.rank(b"a"), Some(0));
+        assert_eq!(zset.rank(b"b"), Some(1));
+        assert!(zset.remove(b"a"));
+        assert!(!zset.remove(b"a"));
+        assert_eq!(zset.len(), 1);
+        assert_eq!(zset.score(b"a"), None);
+    }
+
+    #[test]
+    fn test_range_and_query() {
+        let mut zset = ZSet::new();
+        for (i, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            zset.add(name.as_bytes(), i as f64);
+        }
+        let names = |nodes: Vec<&ZNode>| nodes.iter().map(|n| String::from_utf8(n.name.clone()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(names(zset.range(1, 2)), vec!["b", "c"]);
+        assert_eq!(names(zset.range(-2, -1)), vec!["d", "e"]);
+        assert_eq!(names(zset.range(3, 100)), vec!["d", "e"]);
+        assert!(zset.range(4, 2).is_empty());
+        assert_eq!(names(zset.query(1.5, b"", 0, 2)), vec!["c", "d"]);
+        assert_eq!(names(zset.query(2.0, b"c", 1, 10)), vec!["d", "e"]);
+        assert_eq!(names(zset.query(2.0, b"c", -1, 2)), vec!["b", "c"]);
+        assert!(zset.query(10.0, b"", 0, 2).is_empty());
+        assert_eq!(names(zset.query(0.0, b"", i64::MIN, 1)), vec!["a"]);
+        assert!(zset.query(5.0, b"", i64::MAX, 1).is_empty());
+    }
+}
@@ -1,154 +1,1498 @@
-use ferdis::server::run_server;
-use ferdis::client::send_message;
-use ferdis::client::FerdisResponse;
-use ferdis::server::ResType;
+use ferdis::server::Server;
+use ferdis::aof::FsyncPolicy;
+use ferdis::client::{ClientError, FerdisClient, Reply};
 use std::thread;
 use std::time::Duration;
+use std::net::SocketAddr;
+use ferdis::cluster::K_SLOTS;
+
+// Every test gets its own server on a free port and a client connected to it
+fn start_server() -> FerdisClient {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let client = FerdisClient::connect(&server.local_addr().to_string()).unwrap();
+    thread::spawn(move || server.run());
+    client
+}
+
+fn expect_str(client: &mut FerdisClient, req: &str, expected: &str) {
+    match client.command(&req.split(' ').collect::<Vec<_>>()) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Str(expected.as_bytes().to_vec()), "{}", req);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+fn expect_int(client: &mut FerdisClient, req: &str, expected: i64) {
+    match client.command(&req.split(' ').collect::<Vec<_>>()) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Int(expected), "{}", req);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+fn expect_dbl(client: &mut FerdisClient, req: &str, expected: f64) {
+    match client.command(&req.split(' ').collect::<Vec<_>>()) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Dbl(expected), "{}", req);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+fn expect_arr(client: &mut FerdisClient, req: &str, expected: Vec<Reply>) {
+    match client.command(&req.split(' ').collect::<Vec<_>>()) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Arr(expected), "{}", req);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+fn name(value: &str) -> Reply {
+    Reply::Str(value.as_bytes().to_vec())
+}
+
+fn expect_nil_args(client: &mut FerdisClient, args: &[&[u8]]) {
+    match client.command(args) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Nil);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+fn expect_nil(client: &mut FerdisClient, req: &str) {
+    match client.command(&req.split(' ').collect::<Vec<_>>()) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Nil, "{}", req);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
 
 #[test]
 fn end_to_end_test() {
-    thread::spawn(|| {
-        run_server();
-    });
-    thread::sleep(Duration::from_secs(1));
+    let mut client = start_server();
+
+    match client.command(&["get", "my_key"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Nil);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+
+    match client.command(&["set", "my_key", "my_value"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Nil);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
 
-    match send_message("get my_key".to_string()) {
+    match client.command(&["get", "my_key"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "NIL");
-            assert_eq!(res.res_code, 0);
-            assert!(res.message.is_none());
+            assert_eq!(res, Reply::Str(b"my_value".to_vec()));
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("set my_key my_value".to_string()) {
+    match client.command(&["set", "my_key", "other_value"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "NIL");
-            assert_eq!(res.res_code, 0);
-            assert!(res.message.is_none());
+            assert_eq!(res, Reply::Nil);
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("get my_key".to_string()) {
+    match client.command(&["get", "my_key"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "STR");
-            assert_eq!(res.res_code, 0);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "my_value");
+            assert_eq!(res, Reply::Str(b"other_value".to_vec()));
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("set my_key other_value".to_string()) {
+    match client.command(&["del", "my_key"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "NIL");
-            assert_eq!(res.res_code, 0);
-            assert!(res.message.is_none());
+            assert_eq!(res, Reply::Str(b"other_value".to_vec()));
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("get my_key".to_string()) {
+    match client.command(&["get", "my_key"]) {
         Ok(res) => {
-            assert_eq!(res.res_code, 0);
-            assert_eq!(res.res_type.as_str(), "STR");
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "other_value");
+            assert_eq!(res, Reply::Nil);
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("del my_key".to_string()) {
+    match client.command(&["get"]) {
         Ok(res) => {
-            assert_eq!(res.res_code, 0);
-            assert_eq!(res.res_type.as_str(), "STR");
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "other_value");
+            assert_eq!(res, Reply::Err { code: 2, msg: String::from("Insufficient arguments") });
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("get my_key".to_string()) {
+    match client.command(&["del"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "NIL");
-            assert_eq!(res.res_code, 0);
-            assert!(res.message.is_none());
+            assert_eq!(res, Reply::Err { code: 2, msg: String::from("Insufficient arguments") });
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("get".to_string()) {
+    match client.command(&["set"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "ERR");
-            assert_eq!(res.res_code, 2);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "Insufficient arguments");
+            assert_eq!(res, Reply::Err { code: 2, msg: String::from("Insufficient arguments") });
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("del".to_string()) {
+    match client.command(&["set", "key"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "ERR");
-            assert_eq!(res.res_code, 2);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "Insufficient arguments");
+            assert_eq!(res, Reply::Err { code: 2, msg: String::from("Insufficient arguments") });
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
 
-    match send_message("set".to_string()) {
+    match client.command(&["sadfasdf"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "ERR");
-            assert_eq!(res.res_code, 2);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "Insufficient arguments");
+            assert_eq!(res, Reply::Err { code: 1, msg: String::from("Unknown command") });
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
+}
+
+#[test]
+fn expire_test() {
+    let mut client = start_server();
+
+    expect_nil(&mut client, "set ttl_key ttl_value");
+    expect_int(&mut client, "ttl ttl_key", -1);
+    expect_int(&mut client, "ttl ttl_missing", -2);
+    expect_int(&mut client, "expire ttl_key 100", 1);
+    expect_int(&mut client, "ttl ttl_key", 100);
+    expect_int(&mut client, "persist ttl_key", 1);
+    expect_int(&mut client, "ttl ttl_key", -1);
+    expect_int(&mut client, "expire ttl_missing 100", 0);
+    for req in ["expire", "pexpire", "expireat", "pexpireat"] {
+        assert!(matches!(client.command(&[req, "ttl_key", "10", "x"]), Ok(Reply::Err { code: 3, .. })), "{}", req);
+    }
+    expect_int(&mut client, "ttl ttl_key", -1);
 
-    match send_message("set key".to_string()) {
+    expect_int(&mut client, "pexpire ttl_key 100", 1);
+    thread::sleep(Duration::from_millis(200));
+    expect_nil(&mut client, "get ttl_key");
+    expect_int(&mut client, "ttl ttl_key", -2);
+
+    expect_nil(&mut client, "set ttl_key ttl_value px 100");
+    expect_str(&mut client, "get ttl_key", "ttl_value");
+    thread::sleep(Duration::from_millis(200));
+    expect_nil(&mut client, "get ttl_key");
+
+    expect_nil(&mut client, "set ttl_key ttl_value ex 10");
+    expect_int(&mut client, "ttl ttl_key", 10);
+    expect_nil(&mut client, "set ttl_key ttl_value");
+    expect_int(&mut client, "ttl ttl_key", -1);
+
+    match client.command(&["set", "ttl_key", "ttl_value", "ex", "abc"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "ERR");
-            assert_eq!(res.res_code, 2);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "Insufficient arguments");
+            assert!(matches!(res, Reply::Err { code: 4, .. }));
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
+}
 
-    match send_message("sadfasdf".to_string()) {
+#[test]
+fn zset_test() {
+    let mut client = start_server();
+
+    expect_int(&mut client, "zadd board 10 alice", 1);
+    expect_int(&mut client, "zadd board 20 bob", 1);
+    expect_int(&mut client, "zadd board 15 carol", 1);
+    expect_int(&mut client, "zadd board 5 alice", 0);
+    expect_dbl(&mut client, "zscore board alice", 5.0);
+    expect_nil(&mut client, "zscore board dave");
+    expect_int(&mut client, "zrank board carol", 1);
+    expect_arr(&mut client, "zrange board 0 -1", vec![name("alice"), name("carol"), name("bob")]);
+    expect_arr(&mut client, "zrange board 0 0 withscores", vec![name("alice"), Reply::Dbl(5.0)]);
+    expect_arr(&mut client, "zquery board 15 carol 0 10", vec![name("carol"), Reply::Dbl(15.0), name("bob"), Reply::Dbl(20.0)]);
+    expect_arr(&mut client, "zquery board 0 a 1 1", vec![name("carol"), Reply::Dbl(15.0)]);
+    expect_int(&mut client, "zrem board carol", 1);
+    expect_int(&mut client, "zrem board carol", 0);
+    expect_arr(&mut client, "zrange board 0 -1", vec![name("alice"), name("bob")]);
+
+    match client.command(&["get", "board"]) {
         Ok(res) => {
-            assert_eq!(res.res_type.as_str(), "ERR");
-            assert_eq!(res.res_code, 1);
-            assert!(res.message.is_some());
-            assert_eq!(res.message.unwrap(), "Unknown command");
+            assert!(matches!(res, Reply::Err { code: 5, .. }));
         },
         Err(_) => {
-            assert!(false);
+            panic!("request failed");
         }
     }
+    expect_nil(&mut client, "set zset_str value");
+    match client.command(&["zadd", "zset_str", "1", "a"]) {
+        Ok(res) => {
+            assert!(matches!(res, Reply::Err { code: 5, .. }));
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+#[test]
+fn type_test() {
+    let mut client = start_server();
+
+    expect_nil(&mut client, "set type_str value");
+    expect_int(&mut client, "zadd type_zset 1 a", 1);
+    expect_str(&mut client, "type type_str", "string");
+    expect_str(&mut client, "type type_zset", "zset");
+    expect_str(&mut client, "type type_missing", "none");
+
+    // a key of another type is replaced by set and removed by del
+    expect_nil(&mut client, "set type_zset value");
+    expect_str(&mut client, "type type_zset", "string");
+    expect_int(&mut client, "zadd type_zset2 1 a", 1);
+    expect_int(&mut client, "del type_zset2", 1);
+    expect_str(&mut client, "type type_zset2", "none");
+
+    expect_int(&mut client, "dbsize", 2);
+
+    for req in ["get type_zset2", "zscore type_str a", "zrank type_str a", "zrange type_str 0 -1", "zrem type_str a"] {
+        let _ = client.command(&["zadd", "type_zset2", "1", "a"]);
+        match client.command(&req.split(' ').collect::<Vec<_>>()) {
+            Ok(res) => {
+                match res {
+                    Reply::Err { code, msg } => {
+                        assert_eq!(code, 5, "{}", req);
+                        assert!(msg.starts_with("WRONGTYPE"));
+                    },
+                    _ => {
+                        panic!("expected an error for {}", req);
+                    }
+                }
+            },
+            Err(_) => {
+                panic!("request failed");
+            }
+        }
+    }
+}
+
+#[test]
+fn binary_values_test() {
+    let mut client = start_server();
+
+    let json = "{\"name\": \"ferdis\", \"tags\": [\"a b\", \"\"]}";
+    expect_nil_args(&mut client, &[b"set".as_slice(), b"bin_json", json.as_bytes()]);
+    match client.command(&["get", "bin_json"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Str(json.as_bytes().to_vec()));
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+
+    let blob: Vec<u8> = vec![0, 159, 146, 150, b' ', 255];
+    expect_nil_args(&mut client, &[b"set".as_slice(), b"bin_blob", &blob]);
+    match client.command(&["get", "bin_blob"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Str(blob.to_vec()));
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+
+    expect_nil_args(&mut client, &[b"set".as_slice(), b"bin key", b""]);
+    match client.command(&["get", "bin key"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Str(b"".to_vec()));
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+
+    // command names are matched case-insensitively
+    expect_str(&mut client, "GET bin_json", json);
+}
+
+#[test]
+fn large_message_test() {
+    let mut client = start_server();
+
+    // well above the socket buffers, so the reply leaves in several writes
+    let value: Vec<u8> = (0..8 << 20).map(|i| (i % 251) as u8).collect();
+    expect_nil_args(&mut client, &[b"set".as_slice(), b"large_value", &value]);
+    match client.command(&["get", "large_value"]) {
+        Ok(res) => {
+            assert_eq!(res, Reply::Str(value.to_vec()));
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+#[test]
+fn max_msg_limit_test() {
+    let server = Server::builder().bind("127.0.0.1:0").max_msg(1024).build().unwrap();
+    let addr = server.local_addr().to_string();
+    thread::spawn(move || server.run());
+    let mut client = FerdisClient::connect(&addr).unwrap();
+
+    let name = vec![b'x'; 400];
+    for score in ["1", "2", "3"] {
+        let mut member = name.clone();
+        member.extend_from_slice(score.as_bytes());
+        assert_eq!(client.zadd("limit_zset", score.parse().unwrap(), &member), Ok(Reply::Int(1)));
+    }
+    // the reply would be larger than the limit
+    assert!(matches!(client.zrange("limit_zset", 0, -1), Ok(Reply::Err { code: 6, .. })));
+    // so would the reply to a reader woken by two entries at once
+    let reader = {
+        let addr = addr.clone();
+        thread::spawn(move || FerdisClient::connect(&addr).unwrap().command(&["xread", "block", "0", "streams", "limit_stream", "0"]))
+    };
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "1"));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    for id in ["1", "2"] {
+        assert_eq!(client.xadd("limit_stream", id, &[("f", &vec![b'v'; 600])]), Ok(Reply::Str(b"QUEUED".to_vec())));
+    }
+    assert!(matches!(client.exec(), Ok(Reply::Arr(_))));
+    assert!(matches!(reader.join().unwrap(), Ok(Reply::Err { code: 6, .. })));
+    // requests over the limit drop the connection
+    let big = vec![b'y'; 2048];
+    assert!(client.set("limit_key", &big).is_err());
+}
+
+#[test]
+fn pipeline_test() {
+    let mut client = start_server();
+
+    let mut commands: Vec<Vec<String>> = Vec::new();
+    for i in 0..200 {
+        commands.push(vec!["set".to_string(), format!("pipe_{}", i), i.to_string()]);
+        commands.push(vec!["get".to_string(), format!("pipe_{}", i)]);
+    }
+    commands.push(vec!["del".to_string(), "pipe_0".to_string()]);
+    commands.push(vec!["get".to_string(), "pipe_0".to_string()]);
+    match client.pipeline(&commands) {
+        Ok(responses) => {
+            assert_eq!(responses.len(), commands.len());
+            for i in 0..200 {
+                assert_eq!(responses[2 * i], Reply::Nil);
+                assert_eq!(responses[2 * i + 1], Reply::Str(i.to_string().into_bytes()));
+            }
+            assert_eq!(responses[400], Reply::Str(b"0".to_vec()));
+            assert_eq!(responses[401], Reply::Nil);
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+
+    // large replies queue up behind each other without getting mixed
+    let value = vec![b'p'; 1 << 20];
+    expect_nil_args(&mut client, &[b"set".as_slice(), b"pipe_large", &value]);
+    let gets = vec![vec!["get", "pipe_large"]; 8];
+    match client.pipeline(&gets) {
+        Ok(responses) => {
+            for res in responses {
+                assert_eq!(res, Reply::Str(value.clone()));
+            }
+        },
+        Err(_) => {
+            panic!("request failed");
+        }
+    }
+}
+
+#[test]
+fn resp_test() {
+    use std::io::{Read, Write};
+    let server = Server::builder().bind("127.0.0.1:0").resp_bind("127.0.0.1:0").build().unwrap();
+    let (addr, resp_addr) = (server.local_addr(), server.resp_addr().unwrap());
+    thread::spawn(move || server.run());
+
+    let mut stream = std::net::TcpStream::connect(resp_addr).unwrap();
+    let mut expect = |req: &[u8], expected: &[u8]| {
+        stream.write_all(req).unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expected));
+    };
+
+    expect(b"PING\r\n", b"$4\r\nPONG\r\n");
+    expect(b"*3\r\n$3\r\nSET\r\n$8\r\nresp_key\r\n$5\r\na b c\r\n", b"$-1\r\n");
+    expect(b"*2\r\n$3\r\nGET\r\n$8\r\nresp_key\r\n", b"$5\r\na b c\r\n");
+    expect(b"GET resp_missing\r\n", b"$-1\r\n");
+    expect(b"NOSUCHCOMMAND\r\n", b"-ERR Unknown command\r\n");
+    expect(b"ZADD resp_zset 1 a\r\nZADD resp_zset 2 b\r\nZRANGE resp_zset 0 -1\r\n",
+        b":1\r\n:1\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+    expect(b"ZSCORE resp_zset b\r\nTTL resp_zset\r\n", b"$1\r\n2\r\n:-1\r\n");
+    expect(b"GET resp_zset\r\n", b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    expect(b"HELLO 3\r\n", b"%3\r\n$6\r\nserver\r\n$6\r\nferdis\r\n$7\r\nversion\r\n$5\r\n0.1.0\r\n$5\r\nproto\r\n:3\r\n");
+    expect(b"GET resp_missing\r\n", b"_\r\n");
+    expect(b"ZSCORE resp_zset b\r\n", b",2\r\n");
+
+    // the same keyspace is visible through the ferdis protocol
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(&ferdis::client::serialize_request(&["get", "resp_key"])).unwrap();
+    let mut reply = vec![0; 4 + 8 + 5];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply[12..], b"a b c");
+}
+
+#[test]
+fn client_test() {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let port = server.local_addr().port();
+    thread::spawn(move || server.run());
+
+    // host names are resolved
+    let mut client = FerdisClient::connect(&format!("localhost:{}", port)).unwrap();
+    assert_eq!(client.ping(), Ok(Reply::Str(b"PONG".to_vec())));
+    assert_eq!(client.set("client_key", b"\x00value"), Ok(Reply::Nil));
+    assert_eq!(client.get("client_key"), Ok(Reply::Str(b"\x00value".to_vec())));
+    assert_eq!(client.ttl("client_key"), Ok(Reply::Int(-1)));
+    assert_eq!(client.expire("client_key", 100), Ok(Reply::Int(1)));
+    assert_eq!(client.key_type("client_key"), Ok(Reply::Str(b"string".to_vec())));
+    assert_eq!(client.del("client_key"), Ok(Reply::Str(b"\x00value".to_vec())));
+    assert_eq!(client.get("client_key"), Ok(Reply::Nil));
+    assert_eq!(client.zadd("client_zset", 1.5, "a"), Ok(Reply::Int(1)));
+    assert_eq!(client.zscore("client_zset", "a"), Ok(Reply::Dbl(1.5)));
+    assert_eq!(client.zrange("client_zset", 0, -1), Ok(Reply::Arr(vec![Reply::Str(b"a".to_vec())])));
+    assert_eq!(client.dbsize(), Ok(Reply::Int(1)));
+    // the same connection carries many commands
+    for i in 0..1000 {
+        assert_eq!(client.set_px("client_loop", i.to_string(), 10000), Ok(Reply::Nil));
+    }
+    assert_eq!(client.get("client_loop"), Ok(Reply::Str(b"999".to_vec())));
+
+    assert!(matches!(FerdisClient::connect("no-such-host.invalid:1"), Err(ClientError::Address(_))));
+    assert!(matches!(FerdisClient::connect("127.0.0.1:1"), Err(ClientError::Io(_))));
+}
+
+#[test]
+fn shutdown_test() {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let addr = server.local_addr().to_string();
+    let handle = server.shutdown_handle();
+    let runner = thread::spawn(move || server.run());
+
+    let mut client = FerdisClient::connect(&addr).unwrap();
+    assert_eq!(client.set("shutdown_key", "value"), Ok(Reply::Nil));
+    handle.shutdown();
+    runner.join().unwrap();
+    // open connections and the listener are closed
+    assert!(client.get("shutdown_key").is_err());
+    assert!(FerdisClient::connect(&addr).is_err());
+
+    // servers are independent, a second one starts with an empty keyspace
+    let mut other = start_server();
+    assert_eq!(other.get("shutdown_key"), Ok(Reply::Nil));
+}
+
+#[test]
+fn aof_test() {
+    let path = std::env::temp_dir().join(format!("ferdis-it-{}.aof", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let start = || {
+        let server = Server::builder().bind("127.0.0.1:0").aof(&path, FsyncPolicy::Always).build().unwrap();
+        let client = FerdisClient::connect(&server.local_addr().to_string()).unwrap();
+        let handle = server.shutdown_handle();
+        (client, handle, thread::spawn(move || server.run()))
+    };
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.set("aof_key", b"\x00value"), Ok(Reply::Nil));
+    assert_eq!(client.zadd("aof_zset", 2.5, "a"), Ok(Reply::Int(1)));
+    assert_eq!(client.set_px("aof_gone", "v", 50), Ok(Reply::Nil));
+    assert_eq!(client.set("aof_ttl", "v"), Ok(Reply::Nil));
+    assert_eq!(client.expire("aof_ttl", 100), Ok(Reply::Int(1)));
+    assert_eq!(client.set("aof_del", "v"), Ok(Reply::Nil));
+    assert_eq!(client.del("aof_del"), Ok(Reply::Str(b"v".to_vec())));
+    // rejected commands are not logged
+    assert!(matches!(client.expire("aof_key", i64::MAX), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.set("aof_tx1", "v"), Ok(name("QUEUED")));
+    assert_eq!(client.set("aof_tx2", "v"), Ok(name("QUEUED")));
+    assert_eq!(client.exec(), Ok(Reply::Arr(vec![Reply::Nil, Reply::Nil])));
+    handle.shutdown();
+    runner.join().unwrap();
+
+    // a transaction and a write cut short by a crash
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    std::io::Write::write_all(&mut file, &ferdis::client::serialize_request(&["multi"])).unwrap();
+    std::io::Write::write_all(&mut file, &ferdis::client::serialize_request(&["set", "aof_half", "v"])).unwrap();
+    std::io::Write::write_all(&mut file, &ferdis::client::serialize_request(&["set", "aof_partial", "v"])[..9]).unwrap();
+    // the deadline of aof_gone passes while the server is down
+    thread::sleep(Duration::from_millis(100));
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.get("aof_key"), Ok(Reply::Str(b"\x00value".to_vec())));
+    assert_eq!(client.zscore("aof_zset", "a"), Ok(Reply::Dbl(2.5)));
+    assert_eq!(client.get("aof_gone"), Ok(Reply::Nil));
+    assert_eq!(client.ttl("aof_key"), Ok(Reply::Int(-1)));
+    assert!(matches!(client.ttl("aof_ttl"), Ok(Reply::Int(ttl)) if ttl > 90 && ttl <= 100));
+    assert_eq!(client.get("aof_del"), Ok(Reply::Nil));
+    assert_eq!(client.get("aof_partial"), Ok(Reply::Nil));
+    assert_eq!(client.get("aof_tx2"), Ok(Reply::Str(b"v".to_vec())));
+    assert_eq!(client.get("aof_half"), Ok(Reply::Nil));
+    assert_eq!(client.set("aof_after", "v"), Ok(Reply::Nil));
+    handle.shutdown();
+    runner.join().unwrap();
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.get("aof_after"), Ok(Reply::Str(b"v".to_vec())));
+    handle.shutdown();
+    runner.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn snapshot_test() {
+    let path = std::env::temp_dir().join(format!("ferdis-it-{}.fdb", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let start = || {
+        let server = Server::builder().bind("127.0.0.1:0").snapshot(&path).build().unwrap();
+        let client = FerdisClient::connect(&server.local_addr().to_string()).unwrap();
+        let handle = server.shutdown_handle();
+        (client, handle, thread::spawn(move || server.run()))
+    };
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.set("snap_key", b"\x00value"), Ok(Reply::Nil));
+    assert_eq!(client.zadd("snap_zset", 2.5, "a"), Ok(Reply::Int(1)));
+    assert_eq!(client.set("snap_ttl", "v"), Ok(Reply::Nil));
+    assert_eq!(client.expire("snap_ttl", 100), Ok(Reply::Int(1)));
+    assert_eq!(client.command(&["save"]), Ok(Reply::Nil));
+
+    // the offline reader sees what was saved
+    let entries = ferdis::snapshot::read_snapshot(&path).unwrap();
+    assert_eq!(entries.len(), 3);
+
+    assert_eq!(client.set("snap_bg", "v"), Ok(Reply::Nil));
+    assert_eq!(client.command(&["bgsave"]), Ok(Reply::Str(b"Background saving started".to_vec())));
+    let mut saved = false;
+    for _ in 0..50 {
+        if ferdis::snapshot::read_snapshot(&path).is_ok_and(|entries| entries.len() == 4) {
+            saved = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(saved);
+    // writes after the save are lost on restart
+    assert_eq!(client.set("snap_late", "v"), Ok(Reply::Nil));
+    handle.shutdown();
+    runner.join().unwrap();
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.get("snap_key"), Ok(Reply::Str(b"\x00value".to_vec())));
+    assert_eq!(client.zscore("snap_zset", "a"), Ok(Reply::Dbl(2.5)));
+    assert!(matches!(client.ttl("snap_ttl"), Ok(Reply::Int(ttl)) if ttl > 90 && ttl <= 100));
+    assert_eq!(client.get("snap_bg"), Ok(Reply::Str(b"v".to_vec())));
+    assert_eq!(client.get("snap_late"), Ok(Reply::Nil));
+    handle.shutdown();
+    runner.join().unwrap();
+
+    // a damaged snapshot keeps the server from starting
+    let mut data = std::fs::read(&path).unwrap();
+    data[10] ^= 1;
+    std::fs::write(&path, &data).unwrap();
+    assert!(Server::builder().bind("127.0.0.1:0").snapshot(&path).build().is_err());
+    let _ = std::fs::remove_file(&path);
+
+    let mut client = start_server();
+    assert!(matches!(client.command(&["save"]), Ok(Reply::Err { code: 7, .. })));
+}
+
+#[test]
+fn aof_rewrite_test() {
+    let path = std::env::temp_dir().join(format!("ferdis-it-rewrite-{}.aof", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let start = || {
+        let server = Server::builder().bind("127.0.0.1:0").aof(&path, FsyncPolicy::No).build().unwrap();
+        let client = FerdisClient::connect(&server.local_addr().to_string()).unwrap();
+        let handle = server.shutdown_handle();
+        (client, handle, thread::spawn(move || server.run()))
+    };
+
+    let (mut client, handle, runner) = start();
+    assert!(matches!(client.command(&["bgrewriteaof"]), Ok(Reply::Str(_))));
+    let mut commands: Vec<Vec<String>> = Vec::new();
+    for i in 0..2000 {
+        commands.push(vec!["set".to_string(), "rw_key".to_string(), i.to_string()]);
+    }
+    client.pipeline(&commands).unwrap();
+    assert_eq!(client.zadd("rw_zset", 1.0, "a"), Ok(Reply::Int(1)));
+    assert_eq!(client.zadd("rw_zset", 0.1, "b"), Ok(Reply::Int(1)));
+    assert_eq!(client.set_px("rw_ttl", "v", 100000), Ok(Reply::Nil));
+    assert_eq!(client.xadd("rw_stream", "1-1", &[("f", "v")]), Ok(name("1-1")));
+    assert_eq!(client.xgroup_create("rw_stream", "g", "0"), Ok(Reply::Nil));
+    assert!(matches!(client.xreadgroup("g", "c", &["rw_stream"], &[">"]), Ok(Reply::Arr(_))));
+    let before = std::fs::metadata(&path).unwrap().len();
+    let mut rewritten = false;
+    for _ in 0..100 {
+        // the log from the first rewrite has to be done before a second one
+        if let Ok(Reply::Str(_)) = client.command(&["bgrewriteaof"]) {
+            rewritten = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(rewritten);
+    // written while the second rewrite may still run
+    assert_eq!(client.set("rw_during", "v"), Ok(Reply::Nil));
+    let mut small = false;
+    for _ in 0..100 {
+        if std::fs::metadata(&path).unwrap().len() < before / 10 {
+            small = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(small);
+    assert_eq!(client.set("rw_after", "v"), Ok(Reply::Nil));
+    handle.shutdown();
+    runner.join().unwrap();
+
+    let (mut client, handle, runner) = start();
+    assert_eq!(client.get("rw_key"), Ok(Reply::Str(b"1999".to_vec())));
+    assert_eq!(client.zrange("rw_zset", 0, -1), Ok(Reply::Arr(vec![Reply::Str(b"b".to_vec()), Reply::Str(b"a".to_vec())])));
+    assert!(matches!(client.ttl("rw_ttl"), Ok(Reply::Int(ttl)) if ttl > 90 && ttl <= 100));
+    assert_eq!(client.get("rw_during"), Ok(Reply::Str(b"v".to_vec())));
+    assert_eq!(client.get("rw_after"), Ok(Reply::Str(b"v".to_vec())));
+    // streams come back with their groups and pending entries
+    assert!(matches!(client.xpending("rw_stream", "g"), Ok(Reply::Arr(p)) if p.len() == 1));
+    assert!(matches!(client.xadd("rw_stream", "1-1", &[("f", "v")]), Ok(Reply::Err { code: 4, .. })));
+    handle.shutdown();
+    runner.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    let mut client = start_server();
+    assert!(matches!(client.command(&["bgrewriteaof"]), Ok(Reply::Err { code: 7, .. })));
+}
+
+// Value of one "name:value" line of `info`
+fn info_field(client: &mut FerdisClient, field: &str) -> String {
+    let info = match client.command(&["info"]) {
+        Ok(Reply::Str(info)) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected info reply {:?}", other),
+    };
+    let prefix = format!("{}:", field);
+    info.split("\r\n").find_map(|line| line.strip_prefix(&prefix)).unwrap_or("").to_string()
+}
+
+// Retries `check` for a while, replication is asynchronous
+fn eventually<F: FnMut() -> bool>(mut check: F) -> bool {
+    for _ in 0..250 {
+        if check() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn replication_test() {
+    let leader_server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let port = leader_server.local_addr().port().to_string();
+    let mut leader = FerdisClient::connect(&leader_server.local_addr().to_string()).unwrap();
+    thread::spawn(move || leader_server.run());
+    let follower_server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let follower_addr = follower_server.local_addr().to_string();
+    let mut follower = FerdisClient::connect(&follower_addr).unwrap();
+    thread::spawn(move || follower_server.run());
+
+    // data from before the follower attaches comes with the full sync
+    assert_eq!(leader.set("repl_a", "1"), Ok(Reply::Nil));
+    assert_eq!(leader.zadd("repl_z", 2.5, "m"), Ok(Reply::Int(1)));
+    assert_eq!(leader.set_px("repl_ttl", "v", 100000), Ok(Reply::Nil));
+    assert_eq!(follower.command(&["replicaof", "127.0.0.1", &port]), Ok(Reply::Nil));
+    assert!(eventually(|| follower.get("repl_a") == Ok(Reply::Str(b"1".to_vec()))));
+    assert_eq!(follower.zscore("repl_z", "m"), Ok(Reply::Dbl(2.5)));
+    assert!(matches!(follower.pttl("repl_ttl"), Ok(Reply::Int(ttl)) if ttl > 90000));
+
+    // then writes are streamed
+    assert_eq!(leader.set("repl_b", "2"), Ok(Reply::Nil));
+    assert_eq!(leader.del("repl_a"), Ok(Reply::Str(b"1".to_vec())));
+    assert!(eventually(|| follower.get("repl_b") == Ok(Reply::Str(b"2".to_vec()))));
+    assert!(eventually(|| follower.get("repl_a") == Ok(Reply::Nil)));
+    assert_eq!(leader.multi(), Ok(Reply::Nil));
+    assert_eq!(leader.set("repl_tx1", "v"), Ok(name("QUEUED")));
+    assert_eq!(leader.set("repl_tx2", "v"), Ok(name("QUEUED")));
+    assert_eq!(leader.exec(), Ok(Reply::Arr(vec![Reply::Nil, Reply::Nil])));
+    assert!(eventually(|| follower.get("repl_tx2") == Ok(Reply::Str(b"v".to_vec()))));
+    assert_eq!(follower.get("repl_tx1"), Ok(Reply::Str(b"v".to_vec())));
+    assert!(eventually(|| info_field(&mut follower, "master_repl_offset") == info_field(&mut leader, "master_repl_offset")));
+    assert_eq!(info_field(&mut follower, "role"), "slave");
+    assert_eq!(info_field(&mut follower, "master_link_status"), "up");
+    assert_eq!(info_field(&mut leader, "connected_slaves"), "1");
+
+    // a blocking pop reaches followers as the pop it did
+    let addr = format!("127.0.0.1:{}", port);
+    let waiter = thread::spawn(move || FerdisClient::connect(&addr).unwrap().blpop(&["repl_l"], 0.0));
+    assert!(eventually(|| info_field(&mut leader, "blocked_clients") == "1"));
+    assert_eq!(leader.rpush("repl_l", &["x", "y"]), Ok(Reply::Int(2)));
+    assert_eq!(waiter.join().unwrap(), Ok(Reply::Arr(vec![name("repl_l"), name("x")])));
+    assert!(eventually(|| follower.lrange("repl_l", 0, -1) == Ok(Reply::Arr(vec![name("y")]))));
+
+    // and a random pop as the members it took
+    assert_eq!(leader.sadd("repl_s", &["a", "b", "c"]), Ok(Reply::Int(3)));
+    let popped = match leader.spop("repl_s") {
+        Ok(Reply::Str(member)) => member,
+        other => panic!("unexpected spop reply {:?}", other),
+    };
+    assert!(eventually(|| follower.scard("repl_s") == Ok(Reply::Int(2))));
+    assert_eq!(follower.sismember("repl_s", &popped), Ok(Reply::Int(0)));
+
+    // and an entry with the id the leader picked
+    assert!(matches!(leader.xadd("repl_x", "*", &[("f", "v")]), Ok(Reply::Str(_))));
+    let entries = leader.xrange("repl_x", "-", "+");
+    assert!(eventually(|| follower.xrange("repl_x", "-", "+") == entries));
+
+    // readers blocked on a follower wake up on the writes it gets
+    let reader = thread::spawn(move || FerdisClient::connect(&follower_addr).unwrap().command(&["xread", "block", "0", "streams", "repl_x", "$"]));
+    assert!(eventually(|| info_field(&mut follower, "blocked_clients") == "1"));
+    assert_eq!(leader.xadd("repl_x", "99999999999999-1", &[("f", "w")]), Ok(name("99999999999999-1")));
+    assert_eq!(reader.join().unwrap(), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("repl_x"), Reply::Arr(vec![entry("99999999999999-1", &["f", "w"])])])])));
+
+    // followers are read only
+    assert!(matches!(follower.set("repl_c", "3"), Ok(Reply::Err { code: 8, .. })));
+
+    // a reconnect picks up the writes it missed from the backlog
+    assert_eq!(follower.command(&["replicaof", "127.0.0.1", &port]), Ok(Reply::Nil));
+    assert_eq!(leader.set("repl_c", "3"), Ok(Reply::Nil));
+    assert!(eventually(|| follower.get("repl_c") == Ok(Reply::Str(b"3".to_vec()))));
+    assert_eq!(info_field(&mut leader, "sync_full"), "1");
+    assert_eq!(info_field(&mut leader, "sync_partial_ok"), "1");
+
+    // a promoted follower takes writes again
+    assert_eq!(follower.command(&["replicaof", "no", "one"]), Ok(Reply::Nil));
+    assert_eq!(follower.set("repl_c", "4"), Ok(Reply::Nil));
+    assert_eq!(info_field(&mut follower, "role"), "master");
+    assert_eq!(leader.get("repl_c"), Ok(Reply::Str(b"3".to_vec())));
+}
+
+#[test]
+fn cluster_test() {
+    let start_node = || {
+        let server = Server::builder().bind("127.0.0.1:0").cluster_enabled(true).build().unwrap();
+        let addr = server.local_addr();
+        let client = FerdisClient::connect(&addr.to_string()).unwrap();
+        thread::spawn(move || server.run());
+        (client, addr)
+    };
+    let (mut a, a_addr) = start_node();
+    let (mut b, b_addr) = start_node();
+
+    expect_int(&mut a, "cluster keyslot 123456789", 12739);
+    expect_int(&mut a, "cluster keyslot {123456789}.suffix", 12739);
+    assert!(matches!(a.get("foo"), Ok(Reply::Err { code: 10, .. })));
+
+    // a serves the lower half of the slots and b the upper half
+    let half = K_SLOTS / 2;
+    let mut lower = vec!["cluster".to_string(), "addslots".to_string()];
+    lower.extend((0..half).map(|slot| slot.to_string()));
+    let mut upper = vec!["cluster".to_string(), "addslots".to_string()];
+    upper.extend((half..K_SLOTS).map(|slot| slot.to_string()));
+    assert_eq!(a.command(&lower), Ok(Reply::Nil));
+    assert_eq!(b.command(&upper), Ok(Reply::Nil));
+    let setslots = |range: std::ops::Range<usize>, node: SocketAddr| -> Vec<Vec<String>> {
+        range.map(|slot| vec!["cluster".to_string(), "setslot".to_string(), slot.to_string(), "node".to_string(), node.to_string()]).collect()
+    };
+    assert!(a.pipeline(&setslots(half..K_SLOTS, b_addr)).unwrap().iter().all(|r| *r == Reply::Nil));
+    assert!(b.pipeline(&setslots(0..half, a_addr)).unwrap().iter().all(|r| *r == Reply::Nil));
+    assert!(matches!(a.command(&["cluster", "addslots", "0"]), Ok(Reply::Err { code: 4, .. })));
+
+    // "foo" hashes to slot 12182, "bar" to 5061
+    assert_eq!(a.set("bar", "1"), Ok(Reply::Nil));
+    assert_eq!(a.set("foo", "1"), Ok(Reply::Err { code: 9, msg: format!("MOVED 12182 {}", b_addr) }));
+    assert_eq!(b.set("foo", "2"), Ok(Reply::Nil));
+    assert_eq!(b.get("bar"), Ok(Reply::Err { code: 9, msg: format!("MOVED 5061 {}", a_addr) }));
+    assert_eq!(b.get("foo"), Ok(Reply::Str(b"2".to_vec())));
+    // a transaction with a key served elsewhere runs none of its commands
+    assert_eq!(a.multi(), Ok(Reply::Nil));
+    assert_eq!(a.set("bar", "2"), Ok(name("QUEUED")));
+    assert_eq!(a.set("foo", "2"), Ok(name("QUEUED")));
+    assert_eq!(a.exec(), Ok(Reply::Err { code: 9, msg: format!("MOVED 12182 {}", b_addr) }));
+    assert_eq!(a.get("bar"), Ok(Reply::Str(b"1".to_vec())));
+    // commands without keys run anywhere
+    expect_int(&mut a, "dbsize", 1);
+
+    let info = match a.command(&["cluster", "info"]) {
+        Ok(Reply::Str(info)) => String::from_utf8(info).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(info.contains("cluster_state:ok\r\n"));
+    assert!(info.contains("cluster_known_nodes:2\r\n"));
+    let node = |addr: SocketAddr| Reply::Arr(vec![name(&addr.ip().to_string()), Reply::Int(i64::from(addr.port()))]);
+    assert_eq!(a.command(&["cluster", "slots"]), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![Reply::Int(0), Reply::Int(8191), node(a_addr)]),
+        Reply::Arr(vec![Reply::Int(8192), Reply::Int(16383), node(b_addr)]),
+    ])));
+    assert_eq!(b.command(&["cluster", "nodes"]), Ok(Reply::Str(format!("{} myself,master 8192-16383\n{} master 0-8191\n", b_addr, a_addr).into_bytes())));
+
+    assert_eq!(b.command(&["cluster", "delslots", "12182"]), Ok(Reply::Nil));
+    assert!(matches!(b.get("foo"), Ok(Reply::Err { code: 10, .. })));
+
+    // a client blocked on a key is sent on when its slot moves
+    let waiter = thread::spawn(move || FerdisClient::connect(&a_addr.to_string()).unwrap().blpop(&["bar:queue"], 0.0));
+    assert!(eventually(|| info_field(&mut a, "blocked_clients") == "1"));
+    let slot = match a.command(&["cluster", "keyslot", "bar:queue"]) {
+        Ok(Reply::Int(slot)) => slot.to_string(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert_eq!(a.command(&["cluster", "setslot", &slot, "node", &b_addr.to_string()]), Ok(Reply::Nil));
+    assert_eq!(waiter.join().unwrap(), Ok(Reply::Err { code: 9, msg: format!("MOVED {} {}", slot, b_addr) }));
+
+    let mut plain = start_server();
+    assert!(matches!(plain.command(&["cluster", "info"]), Ok(Reply::Err { code: 10, .. })));
+}
+
+// Sets a key on the node that serves it during a migration, following ASK
+// and MOVED like a cluster client
+fn set_following_redirects(source: &mut FerdisClient, target: &mut FerdisClient, key: &str, value: &str) {
+    match source.set(key, value) {
+        Ok(Reply::Nil) => {},
+        Ok(Reply::Err { code: 11, .. }) => {
+            let replies = target.pipeline(&[vec!["asking"], vec!["set", key, value]]).unwrap();
+            assert_eq!(replies, vec![Reply::Nil, Reply::Nil]);
+        },
+        Ok(Reply::Err { code: 9, .. }) => {
+            assert_eq!(target.set(key, value), Ok(Reply::Nil));
+        },
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[test]
+fn slot_migration_test() {
+    let start_node = || {
+        let server = Server::builder().bind("127.0.0.1:0").cluster_enabled(true).build().unwrap();
+        let addr = server.local_addr();
+        let client = FerdisClient::connect(&addr.to_string()).unwrap();
+        thread::spawn(move || server.run());
+        (client, addr)
+    };
+    let (mut source, source_addr) = start_node();
+    let (mut target, target_addr) = start_node();
+    let mut all = vec!["cluster".to_string(), "addslots".to_string()];
+    all.extend((0..K_SLOTS).map(|slot| slot.to_string()));
+    assert_eq!(source.command(&all), Ok(Reply::Nil));
+
+    // every key below has the tag {mig}, so they all live in one slot
+    let slot = match source.command(&["cluster", "keyslot", "{mig}"]) {
+        Ok(Reply::Int(slot)) => slot.to_string(),
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert_eq!(target.command(&["cluster", "setslot", &slot, "node", &source_addr.to_string()]), Ok(Reply::Nil));
+    let mut commands: Vec<Vec<String>> = Vec::new();
+    for i in 0..500 {
+        commands.push(vec!["set".to_string(), format!("{{mig}}{}", i), format!("v1-{}", i)]);
+    }
+    assert!(source.pipeline(&commands).unwrap().iter().all(|r| *r == Reply::Nil));
+    assert_eq!(source.set_px("{mig}ttl", "v", 100000), Ok(Reply::Nil));
+    assert_eq!(source.zadd("{mig}zset", 1.5, "m"), Ok(Reply::Int(1)));
+    expect_int(&mut source, &format!("cluster countkeysinslot {}", slot), 502);
+
+    // the target refuses the slot to clients that do not ask first
+    assert_eq!(target.command(&["cluster", "setslot", &slot, "importing", &source_addr.to_string()]), Ok(Reply::Nil));
+    assert!(matches!(target.get("{mig}1"), Ok(Reply::Err { code: 9, .. })));
+    assert_eq!(source.command(&["cluster", "setslot", &slot, "migrating", &target_addr.to_string()]), Ok(Reply::Nil));
+    assert_eq!(source.get("{mig}new"), Ok(Reply::Err { code: 11, msg: format!("ASK {} {}", slot, target_addr) }));
+    // a copy left on the target by an earlier, failed attempt is replaced
+    assert_eq!(target.pipeline(&[vec!["asking"], vec!["set", "{mig}1", "stale"]]), Ok(vec![Reply::Nil, Reply::Nil]));
+
+    // keys are updated while the migration runs, none of the writes is lost
+    assert_eq!(source.command(&["cluster", "migrate", &slot, "20"]), Ok(Reply::Str(b"Slot migration started".to_vec())));
+    for i in 0..500 {
+        set_following_redirects(&mut source, &mut target, &format!("{{mig}}{}", i), &format!("v2-{}", i));
+    }
+    set_following_redirects(&mut source, &mut target, "{mig}new", "fresh");
+    assert!(eventually(|| matches!(source.get("{mig}1"), Ok(Reply::Err { code: 9, .. }))));
+    expect_int(&mut source, &format!("cluster countkeysinslot {}", slot), 0);
+
+    // the target owns the slot now and serves it without ASKING
+    expect_int(&mut target, &format!("cluster countkeysinslot {}", slot), 503);
+    for i in 0..500 {
+        assert_eq!(target.get(format!("{{mig}}{}", i)), Ok(Reply::Str(format!("v2-{}", i).into_bytes())));
+    }
+    assert_eq!(target.get("{mig}new"), Ok(Reply::Str(b"fresh".to_vec())));
+    assert!(matches!(target.pttl("{mig}ttl"), Ok(Reply::Int(ttl)) if ttl > 90000));
+    assert_eq!(target.zscore("{mig}zset", "m"), Ok(Reply::Dbl(1.5)));
+    assert_eq!(source.get("{mig}1"), Ok(Reply::Err { code: 9, msg: format!("MOVED {} {}", slot, target_addr) }));
+
+    // restore refuses to overwrite unless told to
+    let dump = match target.command(&["dump", "{mig}new"]) {
+        Ok(Reply::Str(dump)) => dump,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(matches!(target.command(&[b"restore".as_slice(), b"{mig}new", b"0", &dump]), Ok(Reply::Err { code: 12, .. })));
+    assert_eq!(target.command(&[b"restore".as_slice(), b"{mig}copy", b"0", &dump]), Ok(Reply::Nil));
+    assert_eq!(target.get("{mig}copy"), Ok(Reply::Str(b"fresh".to_vec())));
+}
+
+#[test]
+fn pubsub_test() {
+    use std::io::{Read, Write};
+    let server = Server::builder().bind("127.0.0.1:0").resp_bind("127.0.0.1:0").build().unwrap();
+    let (addr, resp_addr) = (server.local_addr().to_string(), server.resp_addr().unwrap());
+    thread::spawn(move || server.run());
+    let mut publisher = FerdisClient::connect(&addr).unwrap();
+    let mut sub = FerdisClient::connect(&addr).unwrap();
+    let mut psub = FerdisClient::connect(&addr).unwrap();
+
+    let confirm = |kind: &str, channel: &str, count: i64| Reply::Arr(vec![name(kind), name(channel), Reply::Int(count)]);
+    assert_eq!(sub.subscribe(&["news", "sport"]).unwrap(), vec![confirm("subscribe", "news", 1), confirm("subscribe", "sport", 2)]);
+    assert_eq!(psub.psubscribe(&["news.*"]).unwrap(), vec![confirm("psubscribe", "news.*", 1)]);
+
+    assert_eq!(publisher.publish("news", "hello"), Ok(Reply::Int(1)));
+    assert_eq!(sub.next_message(), Ok(Reply::Arr(vec![name("message"), name("news"), name("hello")])));
+    assert_eq!(publisher.publish("news.tech", "\x00bin"), Ok(Reply::Int(1)));
+    assert_eq!(psub.next_message(), Ok(Reply::Arr(vec![name("pmessage"), name("news.*"), name("news.tech"), name("\x00bin")])));
+    assert_eq!(publisher.publish("weather", "rain"), Ok(Reply::Int(0)));
+    expect_arr(&mut publisher, "pubsub channels", vec![name("news"), name("sport")]);
+    expect_arr(&mut publisher, "pubsub channels s*", vec![name("sport")]);
+    expect_arr(&mut publisher, "pubsub numsub news weather", vec![name("news"), Reply::Int(1), name("weather"), Reply::Int(0)]);
+
+    // a subscribed connection only takes subscription commands and ping
+    assert!(matches!(sub.get("k"), Ok(Reply::Err { code: 13, .. })));
+    assert_eq!(sub.ping(), Ok(name("PONG")));
+    assert_eq!(sub.unsubscribe::<&str>(&[]).unwrap(), vec![confirm("unsubscribe", "news", 1), confirm("unsubscribe", "sport", 0)]);
+    assert_eq!(sub.get("k"), Ok(Reply::Nil));
+    assert_eq!(publisher.publish("sport", "goal"), Ok(Reply::Int(0)));
+
+    // RESP3 clients get pushes
+    let mut stream = std::net::TcpStream::connect(resp_addr).unwrap();
+    let mut expect = |req: &[u8], expected: &[u8]| {
+        stream.write_all(req).unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expected));
+    };
+    expect(b"HELLO 3\r\n", b"%3\r\n$6\r\nserver\r\n$6\r\nferdis\r\n$7\r\nversion\r\n$5\r\n0.1.0\r\n$5\r\nproto\r\n:3\r\n");
+    expect(b"SUBSCRIBE chat\r\n", b">3\r\n$9\r\nsubscribe\r\n$4\r\nchat\r\n:1\r\n");
+    // a closed subscriber no longer counts
+    drop(psub);
+    assert!(eventually(|| publisher.publish("chat", "hi") == Ok(Reply::Int(1))));
+    expect(b"", b">3\r\n$7\r\nmessage\r\n$4\r\nchat\r\n$2\r\nhi\r\n");
+    expect(b"GET k\r\n", b"_\r\n");
+    assert!(eventually(|| publisher.publish("news.x", "m") == Ok(Reply::Int(0))));
+}
+
+#[test]
+fn transaction_test() {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let addr = server.local_addr().to_string();
+    thread::spawn(move || server.run());
+    let mut client = FerdisClient::connect(&addr).unwrap();
+    let mut other = FerdisClient::connect(&addr).unwrap();
+
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert!(matches!(client.multi(), Ok(Reply::Err { code: 13, .. })));
+    assert_eq!(client.set("a", "1"), Ok(name("QUEUED")));
+    assert_eq!(client.zadd("z", 1.0, "m"), Ok(name("QUEUED")));
+    assert_eq!(client.get("a"), Ok(name("QUEUED")));
+    assert_eq!(client.command(&["nosuchcommand"]), Ok(name("QUEUED")));
+    // nothing ran yet
+    assert_eq!(other.get("a"), Ok(Reply::Nil));
+    match client.exec() {
+        Ok(Reply::Arr(replies)) => {
+            assert_eq!(replies.len(), 4);
+            assert_eq!(replies[..3], [Reply::Nil, Reply::Int(1), name("1")]);
+            assert!(matches!(replies[3], Reply::Err { code: 1, .. }));
+        },
+        other => panic!("unexpected exec reply {:?}", other),
+    }
+    assert!(matches!(client.exec(), Ok(Reply::Err { code: 13, .. })));
+
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.set("a", "2"), Ok(name("QUEUED")));
+    assert_eq!(client.discard(), Ok(Reply::Nil));
+    assert_eq!(client.get("a"), Ok(name("1")));
+    assert!(matches!(client.discard(), Ok(Reply::Err { code: 13, .. })));
+
+    // a watched key changed by someone else aborts the transaction
+    assert_eq!(client.watch(&["a", "missing"]), Ok(Reply::Nil));
+    assert_eq!(other.set("a", "3"), Ok(Reply::Nil));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert!(matches!(client.watch(&["a"]), Ok(Reply::Err { code: 13, .. })));
+    assert_eq!(client.set("a", "4"), Ok(name("QUEUED")));
+    assert_eq!(client.exec(), Ok(Reply::Nil));
+    assert_eq!(client.get("a"), Ok(name("3")));
+
+    // exec forgets the watched keys either way
+    assert_eq!(client.watch(&["a"]), Ok(Reply::Nil));
+    assert_eq!(client.get("a"), Ok(name("3")));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.set("a", "5"), Ok(name("QUEUED")));
+    assert_eq!(client.exec(), Ok(Reply::Arr(vec![Reply::Nil])));
+    assert_eq!(other.set("a", "6"), Ok(Reply::Nil));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.get("a"), Ok(name("QUEUED")));
+    assert_eq!(client.exec(), Ok(Reply::Arr(vec![name("6")])));
+
+    // a key created or expiring while watched counts as changed
+    assert_eq!(client.watch(&["missing"]), Ok(Reply::Nil));
+    assert_eq!(other.set("missing", "x"), Ok(Reply::Nil));
+    assert_eq!(other.del("missing"), Ok(name("x")));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.exec(), Ok(Reply::Nil));
+    assert_eq!(client.set_px("short", "x", 50), Ok(Reply::Nil));
+    assert_eq!(client.watch(&["short"]), Ok(Reply::Nil));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.exec(), Ok(Reply::Nil));
+
+    // unwatch forgets the keys right away
+    assert_eq!(client.watch(&["a"]), Ok(Reply::Nil));
+    assert_eq!(client.unwatch(), Ok(Reply::Nil));
+    assert_eq!(other.set("a", "7"), Ok(Reply::Nil));
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.exec(), Ok(Reply::Arr(vec![])));
+}
+
+#[test]
+fn counter_test() {
+    let mut client = start_server();
+    assert_eq!(client.incr("hits"), Ok(Reply::Int(1)));
+    assert_eq!(client.incr("hits"), Ok(Reply::Int(2)));
+    assert_eq!(client.incr_by("hits", 40), Ok(Reply::Int(42)));
+    assert_eq!(client.decr("hits"), Ok(Reply::Int(41)));
+    assert_eq!(client.decr_by("hits", 50), Ok(Reply::Int(-9)));
+    assert_eq!(client.get("hits"), Ok(name("-9")));
+    assert_eq!(client.decr("fresh"), Ok(Reply::Int(-1)));
+
+    // the deadline survives
+    assert_eq!(client.set_px("limited", "10", 60_000), Ok(Reply::Nil));
+    assert_eq!(client.incr("limited"), Ok(Reply::Int(11)));
+    assert!(matches!(client.pttl("limited"), Ok(Reply::Int(ms)) if ms > 0));
+
+    assert_eq!(client.set("text", "abc"), Ok(Reply::Nil));
+    assert!(matches!(client.incr("text"), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["incrby", "hits", "1.5"]), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.set("big", i64::MAX.to_string()), Ok(Reply::Nil));
+    assert!(matches!(client.incr("big"), Ok(Reply::Err { code: 14, .. })));
+    assert!(matches!(client.decr_by("hits", i64::MIN), Ok(Reply::Err { code: 14, .. })));
+    assert_eq!(client.get("big"), Ok(name(&i64::MAX.to_string())));
+    assert_eq!(client.zadd("z", 1.0, "m"), Ok(Reply::Int(1)));
+    assert!(matches!(client.incr("z"), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.command(&["incr"]), Ok(Reply::Err { code: 2, .. })));
+    assert!(matches!(client.command(&["incr", "a", "b"]), Ok(Reply::Err { code: 3, .. })));
+
+    assert_eq!(client.incr_by_float("price", 10.5), Ok(Reply::Dbl(10.5)));
+    assert_eq!(client.incr_by_float("price", -0.25), Ok(Reply::Dbl(10.25)));
+    assert_eq!(client.get("price"), Ok(name("10.25")));
+    assert_eq!(client.incr_by_float("hits", 9.0), Ok(Reply::Dbl(0.0)));
+    assert_eq!(client.get("hits"), Ok(name("0")));
+    assert!(matches!(client.incr_by_float("text", 1.0), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["incrbyfloat", "price", "inf"]), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.set("huge", "1.7e308"), Ok(Reply::Nil));
+    assert!(matches!(client.incr_by_float("huge", 1.7e308), Ok(Reply::Err { code: 14, .. })));
+}
+
+#[test]
+fn list_test() {
+    let mut client = start_server();
+    assert_eq!(client.rpush("jobs", &["b", "c"]), Ok(Reply::Int(2)));
+    assert_eq!(client.lpush("jobs", &["a", "z"]), Ok(Reply::Int(4)));
+    assert_eq!(client.lrange("jobs", 0, -1), Ok(Reply::Arr(vec![name("z"), name("a"), name("b"), name("c")])));
+    assert_eq!(client.lrange("jobs", -2, 100), Ok(Reply::Arr(vec![name("b"), name("c")])));
+    assert_eq!(client.lrange("jobs", 3, 1), Ok(Reply::Arr(vec![])));
+    assert_eq!(client.lrange("missing", 0, -1), Ok(Reply::Arr(vec![])));
+    assert_eq!(client.lindex("jobs", 1), Ok(name("a")));
+    assert_eq!(client.lindex("jobs", -1), Ok(name("c")));
+    assert_eq!(client.lindex("jobs", 4), Ok(Reply::Nil));
+    assert_eq!(client.key_type("jobs"), Ok(name("list")));
+    assert_eq!(client.lpop("jobs"), Ok(name("z")));
+    assert_eq!(client.rpop("jobs"), Ok(name("c")));
+    assert_eq!(client.llen("jobs"), Ok(Reply::Int(2)));
+    expect_arr(&mut client, "rpop jobs 5", vec![name("b"), name("a")]);
+    // the last pop removes the key
+    assert_eq!(client.key_type("jobs"), Ok(name("none")));
+    assert_eq!(client.lpop("jobs"), Ok(Reply::Nil));
+    expect_nil(&mut client, "lpop jobs 2");
+    assert!(matches!(client.command(&["lpop", "jobs", "-1"]), Ok(Reply::Err { code: 4, .. })));
+
+    // a long queue spans many chunks
+    let items: Vec<String> = (0..10000).map(|i| i.to_string()).collect();
+    assert_eq!(client.rpush("q", &items), Ok(Reply::Int(10000)));
+    assert_eq!(client.lindex("q", 5000), Ok(name("5000")));
+    assert_eq!(client.ltrim("q", 100, -101), Ok(Reply::Nil));
+    assert_eq!(client.llen("q"), Ok(Reply::Int(9800)));
+    assert_eq!(client.lrange("q", 0, 1), Ok(Reply::Arr(vec![name("100"), name("101")])));
+    assert_eq!(client.lrange("q", -1, -1), Ok(Reply::Arr(vec![name("9899")])));
+    assert_eq!(client.ltrim("q", 5, 1), Ok(Reply::Nil));
+    assert_eq!(client.key_type("q"), Ok(name("none")));
+
+    assert_eq!(client.set("s", "v"), Ok(Reply::Nil));
+    assert!(matches!(client.lpush("s", &["x"]), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.lrange("s", 0, 1), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.command(&["rpush", "l"]), Ok(Reply::Err { code: 2, .. })));
+    assert_eq!(client.rpush("l", &["x"]), Ok(Reply::Int(1)));
+    assert_eq!(client.del("l"), Ok(Reply::Int(1)));
+}
+
+#[test]
+fn blocking_pop_test() {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let addr = server.local_addr().to_string();
+    thread::spawn(move || server.run());
+    let mut client = FerdisClient::connect(&addr).unwrap();
+
+    // an item that is already there comes back right away
+    assert_eq!(client.rpush("q", &["a"]), Ok(Reply::Int(1)));
+    assert_eq!(client.blpop(&["empty", "q"], 0.0), Ok(Reply::Arr(vec![name("q"), name("a")])));
+    let start = std::time::Instant::now();
+    assert_eq!(client.blpop(&["q"], 0.1), Ok(Reply::Nil));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // workers get items in the order they started waiting, and the server
+    // goes on serving others meanwhile
+    let worker = |keys: &'static [&'static str]| {
+        let addr = addr.clone();
+        thread::spawn(move || FerdisClient::connect(&addr).unwrap().brpop(keys, 0.0))
+    };
+    let first = worker(&["jobs"]);
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "1"));
+    let second = worker(&["other", "jobs"]);
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "2"));
+    assert_eq!(client.ping(), Ok(name("PONG")));
+    assert_eq!(client.rpush("jobs", &["1", "2"]), Ok(Reply::Int(2)));
+    assert_eq!(first.join().unwrap(), Ok(Reply::Arr(vec![name("jobs"), name("2")])));
+    assert_eq!(second.join().unwrap(), Ok(Reply::Arr(vec![name("jobs"), name("1")])));
+    assert_eq!(client.key_type("jobs"), Ok(name("none")));
+    assert_eq!(info_field(&mut client, "blocked_clients"), "0");
+
+    // requests sent after a blocking pop are answered once it is
+    let pipelined = {
+        let addr = addr.clone();
+        thread::spawn(move || FerdisClient::connect(&addr).unwrap().pipeline(&[vec!["blpop", "p", "0"], vec!["get", "x"]]))
+    };
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "1"));
+    assert_eq!(client.set("x", "after"), Ok(Reply::Nil));
+    assert_eq!(client.lpush("p", &["item"]), Ok(Reply::Int(1)));
+    assert_eq!(pipelined.join().unwrap(), Ok(vec![Reply::Arr(vec![name("p"), name("item")]), name("after")]));
+
+    // a waiter that hung up gets nothing
+    let mut gone = FerdisClient::connect(&addr).unwrap();
+    gone.set_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(gone.blpop(&["g"], 0.0).is_err());
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "0"));
+    assert_eq!(client.rpush("g", &["x"]), Ok(Reply::Int(1)));
+    assert_eq!(client.llen("g"), Ok(Reply::Int(1)));
+
+    // inside a transaction a blocking pop does not wait
+    assert_eq!(client.multi(), Ok(Reply::Nil));
+    assert_eq!(client.blpop(&["nothing"], 0.0), Ok(name("QUEUED")));
+    assert_eq!(client.exec(), Ok(Reply::Arr(vec![Reply::Nil])));
+
+    assert_eq!(client.set("s", "v"), Ok(Reply::Nil));
+    assert!(matches!(client.blpop(&["s"], 0.0), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.command(&["blpop", "q", "-1"]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["blpop", "q"]), Ok(Reply::Err { code: 2, .. })));
+}
+
+#[test]
+fn hash_test() {
+    let mut client = start_server();
+    assert_eq!(client.hset("user:1", &[("name", "ann"), ("mail", "ann@example.com")]), Ok(Reply::Int(2)));
+    assert_eq!(client.hset("user:1", &[("name", "anne"), ("visits", "1")]), Ok(Reply::Int(1)));
+    assert_eq!(client.hget("user:1", "name"), Ok(name("anne")));
+    assert_eq!(client.hget("user:1", "phone"), Ok(Reply::Nil));
+    assert_eq!(client.hget("missing", "name"), Ok(Reply::Nil));
+    assert_eq!(client.hlen("user:1"), Ok(Reply::Int(3)));
+    assert_eq!(client.key_type("user:1"), Ok(name("hash")));
+    assert_eq!(client.hincrby("user:1", "visits", 41), Ok(Reply::Int(42)));
+    assert_eq!(client.hincrby("user:1", "fresh", -2), Ok(Reply::Int(-2)));
+    assert!(matches!(client.hincrby("user:1", "name", 1), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.hset("user:1", &[("big", i64::MAX.to_string())]), Ok(Reply::Int(1)));
+    assert!(matches!(client.hincrby("user:1", "big", 1), Ok(Reply::Err { code: 14, .. })));
+    assert_eq!(client.hdel("user:1", &["big", "fresh", "nope"]), Ok(Reply::Int(2)));
+
+    match client.hgetall("user:1") {
+        Ok(Reply::Arr(items)) => {
+            let mut pairs: Vec<(Reply, Reply)> = items.chunks(2).map(|p| (p[0].clone(), p[1].clone())).collect();
+            pairs.sort_by_key(|p| format!("{:?}", p.0));
+            assert_eq!(pairs, vec![(name("mail"), name("ann@example.com")), (name("name"), name("anne")), (name("visits"), name("42"))]);
+        },
+        other => panic!("unexpected hgetall reply {:?}", other),
+    }
+    assert_eq!(client.hgetall("missing"), Ok(Reply::Arr(vec![])));
+
+    // large hashes work the same
+    let fields: Vec<(String, String)> = (0..1000).map(|i| (format!("f{}", i), "x".repeat(i % 100))).collect();
+    assert_eq!(client.hset("big", &fields), Ok(Reply::Int(1000)));
+    assert_eq!(client.hget("big", "f999"), Ok(name(&"x".repeat(99))));
+    assert!(matches!(client.hgetall("big"), Ok(Reply::Arr(items)) if items.len() == 2000));
+
+    // the last field takes the key with it
+    assert_eq!(client.hdel("user:1", &["mail", "name", "visits"]), Ok(Reply::Int(3)));
+    assert_eq!(client.key_type("user:1"), Ok(name("none")));
+
+    assert!(matches!(client.command(&["hset", "h", "f"]), Ok(Reply::Err { code: 2, .. })));
+    assert!(matches!(client.command(&["hset", "h", "f", "v", "g"]), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.set("s", "v"), Ok(Reply::Nil));
+    assert!(matches!(client.hget("s", "f"), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.hset("s", &[("f", "v")]), Ok(Reply::Err { code: 5, .. })));
+}
+
+fn sorted(reply: Result<Reply, ClientError>) -> Vec<String> {
+    match reply {
+        Ok(Reply::Arr(items)) => {
+            let mut out: Vec<String> = items.iter().map(|i| match i {
+                Reply::Str(m) => String::from_utf8(m.clone()).unwrap(),
+                other => panic!("expected a member, got {:?}", other),
+            }).collect();
+            out.sort();
+            out
+        },
+        other => panic!("expected an array, got {:?}", other),
+    }
+}
+
+#[test]
+fn set_test() {
+    let mut client = start_server();
+    assert_eq!(client.sadd("tags:1", &["red", "green", "blue", "red"]), Ok(Reply::Int(3)));
+    assert_eq!(client.sadd("tags:1", &["red", "cyan"]), Ok(Reply::Int(1)));
+    assert_eq!(client.scard("tags:1"), Ok(Reply::Int(4)));
+    assert_eq!(client.sismember("tags:1", "red"), Ok(Reply::Int(1)));
+    assert_eq!(client.sismember("tags:1", "pink"), Ok(Reply::Int(0)));
+    assert_eq!(client.sismember("missing", "red"), Ok(Reply::Int(0)));
+    assert_eq!(client.key_type("tags:1"), Ok(name("set")));
+    assert_eq!(client.srem("tags:1", &["cyan", "pink"]), Ok(Reply::Int(1)));
+    assert_eq!(sorted(client.smembers("tags:1")), vec!["blue", "green", "red"]);
+    assert_eq!(client.smembers("missing"), Ok(Reply::Arr(vec![])));
+
+    // algebra, missing keys are empty sets
+    assert_eq!(client.sadd("tags:2", &["red", "pink", "blue"]), Ok(Reply::Int(3)));
+    assert_eq!(client.sadd("tags:3", &["blue", "gray"]), Ok(Reply::Int(2)));
+    assert_eq!(sorted(client.sinter(&["tags:1", "tags:2"])), vec!["blue", "red"]);
+    assert_eq!(sorted(client.sinter(&["tags:1", "tags:2", "tags:3"])), vec!["blue"]);
+    assert_eq!(client.sinter(&["tags:1", "missing"]), Ok(Reply::Arr(vec![])));
+    assert_eq!(sorted(client.sunion(&["tags:1", "tags:3", "missing"])), vec!["blue", "gray", "green", "red"]);
+    assert_eq!(sorted(client.sdiff(&["tags:1", "tags:2"])), vec!["green"]);
+    assert_eq!(sorted(client.sdiff(&["tags:2", "tags:1", "tags:3"])), vec!["pink"]);
+    assert_eq!(client.sdiff(&["missing", "tags:1"]), Ok(Reply::Arr(vec![])));
+
+    // the store variants replace the destination, an empty result deletes it
+    assert_eq!(client.set("dest", "v"), Ok(Reply::Nil));
+    assert_eq!(client.command(&["sunionstore", "dest", "tags:1", "tags:2"]), Ok(Reply::Int(4)));
+    assert_eq!(client.key_type("dest"), Ok(name("set")));
+    assert_eq!(client.command(&["sinterstore", "dest", "dest", "tags:3"]), Ok(Reply::Int(1)));
+    assert_eq!(sorted(client.smembers("dest")), vec!["blue"]);
+    assert_eq!(client.command(&["sdiffstore", "dest", "tags:3", "tags:1", "tags:2"]), Ok(Reply::Int(1)));
+    assert_eq!(sorted(client.smembers("dest")), vec!["gray"]);
+    assert_eq!(client.command(&["sinterstore", "dest", "tags:1", "missing"]), Ok(Reply::Int(0)));
+    assert_eq!(client.key_type("dest"), Ok(name("none")));
+
+    // random members
+    assert!(matches!(client.srandmember("tags:1"), Ok(Reply::Str(m)) if m == b"red" || m == b"green" || m == b"blue"));
+    assert_eq!(client.srandmember("missing"), Ok(Reply::Nil));
+    assert_eq!(sorted(client.command(&["srandmember", "tags:1", "5"])), vec!["blue", "green", "red"]);
+    assert!(matches!(client.command(&["srandmember", "tags:1", "2"]), Ok(Reply::Arr(m)) if m.len() == 2 && m[0] != m[1]));
+    assert!(matches!(client.command(&["srandmember", "tags:1", "-7"]), Ok(Reply::Arr(m)) if m.len() == 7));
+    assert!(matches!(client.command(&["srandmember", "tags:1", "-9223372036854775808"]), Ok(Reply::Err { code: 6, .. })));
+    assert_eq!(client.scard("tags:1"), Ok(Reply::Int(3)));
+
+    // popping the last member takes the key with it
+    let members: Vec<String> = (0..200).map(|i| format!("m{}", i)).collect();
+    assert_eq!(client.sadd("many", &members), Ok(Reply::Int(200)));
+    assert!(matches!(client.command(&["spop", "many", "150"]), Ok(Reply::Arr(m)) if m.len() == 150));
+    assert_eq!(client.scard("many"), Ok(Reply::Int(50)));
+    assert!(matches!(client.spop("many"), Ok(Reply::Str(_))));
+    assert!(matches!(client.command(&["spop", "many", "100"]), Ok(Reply::Arr(m)) if m.len() == 49));
+    assert_eq!(client.key_type("many"), Ok(name("none")));
+    assert_eq!(client.spop("many"), Ok(Reply::Nil));
+
+    assert!(matches!(client.command(&["sadd", "s"]), Ok(Reply::Err { code: 2, .. })));
+    assert!(matches!(client.command(&["spop", "tags:1", "-1"]), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.set("str", "v"), Ok(Reply::Nil));
+    assert!(matches!(client.sadd("str", &["m"]), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.sunion(&["tags:1", "str"]), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.command(&["sinterstore", "dest", "missing", "str"]), Ok(Reply::Err { code: 5, .. })));
+}
+
+// [id, [field, value...]] of one stream entry
+fn entry(id: &str, fields: &[&str]) -> Reply {
+    Reply::Arr(vec![name(id), Reply::Arr(fields.iter().map(|f| name(f)).collect())])
+}
+
+#[test]
+fn stream_test() {
+    let mut client = start_server();
+    assert_eq!(client.xadd("events", "1-1", &[("kind", "login"), ("user", "ann")]), Ok(name("1-1")));
+    assert_eq!(client.xadd("events", "1-*", &[("kind", "logout")]), Ok(name("1-2")));
+    assert_eq!(client.xadd("events", "5", &[("kind", "login")]), Ok(name("5-0")));
+    assert!(matches!(client.xadd("events", "5-0", &[("kind", "dup")]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.xadd("events", "4-*", &[("kind", "late")]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.xadd("events", "x-1", &[("kind", "bad")]), Ok(Reply::Err { code: 4, .. })));
+    // ids from the clock keep growing
+    let auto = match client.xadd("events", "*", &[("kind", "ping")]) {
+        Ok(Reply::Str(id)) => String::from_utf8(id).unwrap(),
+        other => panic!("unexpected xadd reply {:?}", other),
+    };
+    assert!(auto.split_once('-').unwrap().0.parse::<u64>().unwrap() > 5);
+    assert_eq!(client.xlen("events"), Ok(Reply::Int(4)));
+    assert_eq!(client.key_type("events"), Ok(name("stream")));
+
+    assert_eq!(client.xrange("events", "-", "1"), Ok(Reply::Arr(vec![
+        entry("1-1", &["kind", "login", "user", "ann"]), entry("1-2", &["kind", "logout"])])));
+    assert_eq!(client.xrange("events", "1-2", "5-0"), Ok(Reply::Arr(vec![entry("1-2", &["kind", "logout"]), entry("5-0", &["kind", "login"])])));
+    assert_eq!(client.command(&["xrange", "events", "-", "+", "count", "1"]), Ok(Reply::Arr(vec![entry("1-1", &["kind", "login", "user", "ann"])])));
+    assert_eq!(client.xrange("events", "5-1", "5-0"), Ok(Reply::Arr(vec![])));
+    assert_eq!(client.xrange("missing", "-", "+"), Ok(Reply::Arr(vec![])));
+
+    // xread returns what came after each id
+    assert_eq!(client.xread(&["events", "missing"], &["1-2", "0"]), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("events"), Reply::Arr(vec![entry("5-0", &["kind", "login"]), entry(&auto, &["kind", "ping"])])])])));
+    assert_eq!(client.xread(&["events"], &["$"]), Ok(Reply::Nil));
+    assert_eq!(client.command(&["xread", "count", "1", "streams", "events", "0"]), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("events"), Reply::Arr(vec![entry("1-1", &["kind", "login", "user", "ann"])])])])));
+
+    // trimming keeps the newest entries, and an emptied stream keeps its key
+    assert_eq!(client.xtrim("events", 2), Ok(Reply::Int(2)));
+    assert_eq!(client.command(&["xtrim", "events", "minid", "~", "6"]), Ok(Reply::Int(1)));
+    assert!(matches!(client.command(&["xadd", "events", "maxlen", "=", "2", "*", "kind", "a"]), Ok(Reply::Str(_))));
+    assert_eq!(client.xlen("events"), Ok(Reply::Int(2)));
+    assert!(matches!(client.command(&["xadd", "events", "maxlen", "1", "*", "kind", "b"]), Ok(Reply::Str(_))));
+    assert_eq!(client.xlen("events"), Ok(Reply::Int(1)));
+    assert_eq!(client.xtrim("events", 0), Ok(Reply::Int(1)));
+    assert_eq!(client.key_type("events"), Ok(name("stream")));
+    assert!(matches!(client.xadd("events", "5-0", &[("kind", "old")]), Ok(Reply::Err { code: 4, .. })));
+
+    assert!(matches!(client.command(&["xadd", "events", "*", "kind"]), Ok(Reply::Err { code: 2, .. })));
+    assert!(matches!(client.command(&["xadd", "events", "*", "kind", "a", "user"]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["xread", "streams", "events", "missing", "0"]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["xtrim", "events", "maxlen", "-1"]), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.set("str", "v"), Ok(Reply::Nil));
+    assert!(matches!(client.xadd("str", "*", &[("f", "v")]), Ok(Reply::Err { code: 5, .. })));
+    assert!(matches!(client.xread(&["str"], &["0"]), Ok(Reply::Err { code: 5, .. })));
+}
+
+#[test]
+fn stream_group_test() {
+    let server = Server::builder().bind("127.0.0.1:0").build().unwrap();
+    let addr = server.local_addr().to_string();
+    thread::spawn(move || server.run());
+    let mut client = FerdisClient::connect(&addr).unwrap();
+
+    assert!(matches!(client.xgroup_create("jobs", "workers", "$"), Ok(Reply::Err { code: 4, .. })));
+    assert_eq!(client.command(&["xgroup", "create", "jobs", "workers", "$", "mkstream"]), Ok(Reply::Nil));
+    assert!(matches!(client.xgroup_create("jobs", "workers", "0"), Ok(Reply::Err { code: 12, .. })));
+    for i in 1..=3 {
+        assert_eq!(client.xadd("jobs", format!("{}-0", i), &[("job", i.to_string())]), Ok(name(&format!("{}-0", i))));
+    }
+
+    // every entry goes to one consumer and stays pending until acknowledged
+    let read = |client: &mut FerdisClient, consumer: &str, id: &str| client.command(&["xreadgroup", "group", "workers", consumer, "count", "2", "streams", "jobs", id]);
+    assert_eq!(read(&mut client, "alice", ">"), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("jobs"), Reply::Arr(vec![entry("1-0", &["job", "1"]), entry("2-0", &["job", "2"])])])])));
+    assert_eq!(read(&mut client, "bob", ">"), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("jobs"), Reply::Arr(vec![entry("3-0", &["job", "3"])])])])));
+    assert_eq!(read(&mut client, "bob", ">"), Ok(Reply::Nil));
+    match client.xpending("jobs", "workers") {
+        Ok(Reply::Arr(pending)) => {
+            let owners: Vec<(Reply, Reply, Reply)> = pending.iter().map(|p| match p {
+                Reply::Arr(p) => (p[0].clone(), p[1].clone(), p[3].clone()),
+                other => panic!("unexpected pending entry {:?}", other),
+            }).collect();
+            assert_eq!(owners, vec![(name("1-0"), name("alice"), Reply::Int(1)), (name("2-0"), name("alice"), Reply::Int(1)),
+                (name("3-0"), name("bob"), Reply::Int(1))]);
+        },
+        other => panic!("unexpected xpending reply {:?}", other),
+    }
+
+    // a consumer can read its own pending entries again, trimmed ones come back without fields
+    assert_eq!(client.xack("jobs", "workers", &["1-0", "9-0"]), Ok(Reply::Int(1)));
+    assert_eq!(client.command(&["xtrim", "jobs", "minid", "3"]), Ok(Reply::Int(2)));
+    assert_eq!(read(&mut client, "alice", "0"), Ok(Reply::Arr(vec![
+        Reply::Arr(vec![name("jobs"), Reply::Arr(vec![Reply::Arr(vec![name("2-0"), Reply::Nil])])])])));
+    assert!(matches!(client.command(&["xpending", "jobs", "workers", "-", "+", "10", "alice"]),
+        Ok(Reply::Arr(p)) if p.len() == 1 && matches!(&p[0], Reply::Arr(e) if e[3] == Reply::Int(2))));
+    assert_eq!(client.command(&["xgroup", "delconsumer", "jobs", "workers", "alice"]), Ok(Reply::Int(1)));
+    assert_eq!(client.command(&["xgroup", "setid", "jobs", "workers", "0"]), Ok(Reply::Nil));
+    assert!(matches!(read(&mut client, "carol", ">"), Ok(Reply::Arr(s)) if s.len() == 1));
+
+    // blocked readers wake up on xadd: xread serves everyone, a group hands
+    // each entry to the consumer that has waited longest
+    let reader = |args: &'static [&'static str]| {
+        let addr = addr.clone();
+        thread::spawn(move || FerdisClient::connect(&addr).unwrap().command(args))
+    };
+    let plain = reader(&["xread", "block", "0", "streams", "jobs", "$"]);
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "1"));
+    let first = reader(&["xreadgroup", "group", "workers", "dave", "block", "0", "streams", "jobs", ">"]);
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "2"));
+    let second = reader(&["xreadgroup", "group", "workers", "erin", "block", "0", "streams", "jobs", ">"]);
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "3"));
+    assert_eq!(client.xadd("jobs", "10-0", &[("job", "10")]), Ok(name("10-0")));
+    let got = |id: &str, job: &str| Ok(Reply::Arr(vec![Reply::Arr(vec![name("jobs"), Reply::Arr(vec![entry(id, &["job", job])])])]));
+    assert_eq!(plain.join().unwrap(), got("10-0", "10"));
+    assert_eq!(first.join().unwrap(), got("10-0", "10"));
+    assert!(eventually(|| info_field(&mut client, "blocked_clients") == "1"));
+    assert_eq!(client.xadd("jobs", "11-0", &[("job", "11")]), Ok(name("11-0")));
+    assert_eq!(second.join().unwrap(), got("11-0", "11"));
+
+    // and give up after their timeout
+    let start = std::time::Instant::now();
+    assert_eq!(client.command(&["xread", "block", "100", "streams", "jobs", "$"]), Ok(Reply::Nil));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    assert!(matches!(client.xreadgroup("nope", "c", &["jobs"], &[">"]), Ok(Reply::Err { code: 15, .. })));
+    assert!(matches!(client.xreadgroup("workers", "c", &["missing"], &[">"]), Ok(Reply::Err { code: 15, .. })));
+    assert!(matches!(client.command(&["xgroup", "setid", "jobs", "nope", "0"]), Ok(Reply::Err { code: 15, .. })));
+    assert_eq!(client.command(&["xgroup", "destroy", "jobs", "workers"]), Ok(Reply::Int(1)));
+    assert!(matches!(client.xack("jobs", "workers", &["bad"]), Ok(Reply::Err { code: 4, .. })));
+    assert!(matches!(client.command(&["xgroup", "frob", "jobs", "workers"]), Ok(Reply::Err { code: 1, .. })));
 }